@@ -0,0 +1,271 @@
+//! Letting the model use tools: calling it, running the tools it asks for, and sending back the results until it
+//! is done.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use futures::future::join_all;
+
+use crate::{
+    anthropic::{Client, Content, Conversation, MessagesResponse, Spent, models::Model},
+    tools::ToolRegistry,
+};
+
+/// Returned, inside the `anyhow::Error`, when an [`Agent`] stops before the model finished. The conversation is
+/// left in a consistent state, so that the run can be resumed.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum LimitReached {
+    #[error("model still calling tools after {0} iterations")]
+    Iterations(u32),
+    #[error("used {used} tokens of the {max} allowed")]
+    Tokens { used: u64, max: u64 },
+    #[error("deadline of {0:?} passed")]
+    Deadline(Duration),
+}
+
+/// Each step of a run, as passed to the agent's hooks.
+#[derive(Debug)]
+pub enum Step<'a> {
+    /// about to call the model, for the `n`th time this run
+    Request(u32),
+    Response(&'a MessagesResponse),
+    /// a `tool_use` block about to be run
+    ToolUse(&'a Content),
+    /// the `tool_result` it produced
+    ToolResult(&'a Content),
+}
+
+type Hook = Arc<dyn Fn(&Step<'_>) + Send + Sync>;
+
+/// Drives a conversation through as many model calls and tool runs as it takes for the model to stop asking for
+/// tools, within limits.
+#[derive(Clone)]
+pub struct Agent {
+    client: Client,
+    tools: ToolRegistry,
+    max_iterations: u32,
+    max_tokens: Option<u64>,
+    deadline: Option<Duration>,
+    hooks: Vec<Hook>,
+}
+
+impl Agent {
+    pub fn new(client: Client, tools: ToolRegistry) -> Self {
+        Self { client, tools, max_iterations: 10, max_tokens: None, deadline: None, hooks: vec![] }
+    }
+
+    /// The most times to call the model in one run. Defaults to 10.
+    pub fn with_max_iterations(mut self, n: u32) -> Self {
+        self.max_iterations = n.max(1);
+        self
+    }
+
+    /// Stops calling the model once a run has used this many tokens, input and output combined.
+    pub fn with_token_budget(mut self, tokens: u64) -> Self {
+        self.max_tokens = Some(tokens);
+        self
+    }
+
+    /// Stops a run which has taken longer than `timeout`, including any model call or tool still in progress.
+    pub fn with_deadline(mut self, timeout: Duration) -> Self {
+        self.deadline = Some(timeout);
+        self
+    }
+
+    /// Calls `hook` with every [`Step`] of every run.
+    pub fn with_hook(mut self, hook: impl Fn(&Step<'_>) + Send + Sync + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn tools(&self) -> &ToolRegistry {
+        &self.tools
+    }
+
+    /// Adds `prompt` to the conversation and runs it; see [`Agent::run`].
+    pub async fn ask(&self, conv: &mut Conversation, prompt: &str) -> Result<MessagesResponse> {
+        conv.push_user(prompt);
+        self.run(conv).await
+    }
+
+    /// Has the model reply to the conversation, which should end with a user message, running the tools it calls
+    /// and sending back their results until it stops for any reason other than `tool_use`. Tools called in the same
+    /// reply run concurrently. Returns the model's final reply.
+    pub async fn run(&self, conv: &mut Conversation) -> Result<MessagesResponse> {
+        let started = Instant::now();
+        let deadline = self.deadline.map(|timeout| (started + timeout, timeout));
+        let definitions = self.tools.definitions();
+        let mut used = 0;
+        for iteration in 1..=self.max_iterations {
+            if let Some(max) = self.max_tokens.filter(|max| used >= *max) {
+                return Err(LimitReached::Tokens { used, max }.into());
+            }
+            self.emit(Step::Request(iteration));
+            let reply = self.client.reply(conv, &definitions);
+            let resp = match deadline {
+                Some((at, timeout)) => {
+                    tokio::time::timeout_at(at.into(), reply).await.map_err(|_| LimitReached::Deadline(timeout))??
+                }
+                None => reply.await?,
+            };
+            if let Some(usage) = &resp.usage {
+                used += Spent::of(usage, &Model::from(resp.model.as_str())).tokens;
+            }
+            self.emit(Step::Response(&resp));
+            if resp.stop_reason.as_deref() != Some("tool_use") {
+                return Ok(resp);
+            }
+
+            let calls = resp.content.iter().filter(|c| matches!(c, Content::ToolUse { .. })).collect::<Vec<_>>();
+            tracing::debug!("iteration {iteration}: running {} tool calls", calls.len());
+            calls.iter().for_each(|call| self.emit(Step::ToolUse(call)));
+            let results = join_all(calls.iter().map(|call| self.call_tool(call, deadline))).await;
+            results.iter().for_each(|result| self.emit(Step::ToolResult(result)));
+            // every tool_use has its result, even past the deadline, so the conversation can carry on later
+            conv.push_tool_results(results);
+            if let Some((at, timeout)) = deadline {
+                if Instant::now() >= at {
+                    return Err(LimitReached::Deadline(timeout).into());
+                }
+            }
+        }
+        Err(LimitReached::Iterations(self.max_iterations).into())
+    }
+
+    async fn call_tool(&self, call: &Content, deadline: Option<(Instant, Duration)>) -> Content {
+        let Content::ToolUse { id, .. } = call else { unreachable!("only tool_use blocks are called") };
+        let dispatch = async { self.tools.dispatch(call).await.expect("tool_use blocks always have a result") };
+        let Some((at, _)) = deadline else {
+            return dispatch.await;
+        };
+        tokio::time::timeout_at(at.into(), dispatch).await.unwrap_or_else(|_| Content::ToolResult {
+            tool_use_id: id.clone(),
+            content: String::from("the tool did not finish before the deadline"),
+            is_error: true,
+        })
+    }
+
+    fn emit(&self, step: Step<'_>) {
+        self.hooks.iter().for_each(|hook| hook(&step));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use schemars::JsonSchema;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        anthropic::mock::{MockResponse, MockServer},
+        tools::Tool,
+    };
+
+    #[derive(Deserialize, JsonSchema)]
+    struct SleepInput {
+        millis: u64,
+    }
+
+    struct Sleep;
+
+    impl Tool for Sleep {
+        type Input = SleepInput;
+
+        fn name(&self) -> &str {
+            "sleep"
+        }
+
+        fn description(&self) -> &str {
+            "Sleeps for a while."
+        }
+
+        async fn call(&self, input: SleepInput) -> Result<String> {
+            tokio::time::sleep(Duration::from_millis(input.millis)).await;
+            Ok(format!("slept {}ms", input.millis))
+        }
+    }
+
+    fn agent(server: &MockServer) -> Agent {
+        let client = Client::new(String::from("key")).unwrap().with_endpoint(server.url());
+        let mut tools = ToolRegistry::new();
+        tools.register(Sleep);
+        Agent::new(client, tools)
+    }
+
+    #[tokio::test]
+    async fn runs_tools_until_done() {
+        let server = MockServer::start().await.unwrap();
+        server.push(MockResponse::tool_uses(&[("sleep", json!({"millis": 200})), ("sleep", json!({"millis": 200}))]));
+        server.push(MockResponse::tool_use("nap", json!({})));
+        server.push(MockResponse::message("well rested"));
+        let steps = Arc::new(Mutex::new(vec![]));
+        let agent = {
+            let steps = steps.clone();
+            agent(&server).with_hook(move |step| {
+                let name = match step {
+                    Step::Request(n) => format!("request {n}"),
+                    Step::Response(resp) => format!("response {}", resp.stop_reason.as_deref().unwrap_or_default()),
+                    Step::ToolUse(_) => String::from("tool_use"),
+                    Step::ToolResult(_) => String::from("tool_result"),
+                };
+                steps.lock().unwrap().push(name);
+            })
+        };
+
+        let mut conv = Conversation::new(agent.client().model(), None);
+        let started = Instant::now();
+        let resp = agent.ask(&mut conv, "take two naps").await.unwrap();
+        assert_eq!(resp.text(), "well rested");
+        // the two sleeps ran side by side
+        assert!(started.elapsed() < Duration::from_millis(400), "{:?}", started.elapsed());
+
+        let reqs = server.requests();
+        assert_eq!(reqs.len(), 3);
+        assert_eq!(reqs[0].body["tools"][0]["name"], "sleep");
+        let results = &reqs[1].body["messages"][2]["content"];
+        assert_eq!(results[0], json!({"type": "tool_result", "tool_use_id": "toolu_mock_0", "content": "slept 200ms"}));
+        assert_eq!(results[1]["tool_use_id"], "toolu_mock_1");
+        assert_eq!(reqs[2].body["messages"][4]["content"][0]["is_error"], true);
+        assert_eq!(conv.messages.len(), 6);
+        assert_eq!(conv.turn_starts(), vec![0]);
+        assert_eq!(
+            steps.lock().unwrap()[..6],
+            ["request 1", "response tool_use", "tool_use", "tool_use", "tool_result", "tool_result"]
+        );
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let server = MockServer::start().await.unwrap();
+        for _ in 0..3 {
+            server.push(MockResponse::tool_use("sleep", json!({"millis": 0})));
+        }
+        let agent = agent(&server).with_max_iterations(2);
+        let mut conv = Conversation::new(agent.client().model(), None);
+        let err = agent.ask(&mut conv, "sleep forever").await.unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&LimitReached::Iterations(2)));
+        assert_eq!(conv.messages.last().unwrap().role, "user");
+
+        let agent = agent.with_token_budget(10);
+        let err = agent.run(&mut conv).await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(LimitReached::Tokens { max: 10, .. })), "{err:#}");
+
+        server.push(MockResponse::tool_use("sleep", json!({"millis": 5000})));
+        let agent = agent.with_token_budget(1000).with_deadline(Duration::from_millis(100));
+        let mut conv = Conversation::new(agent.client().model(), None);
+        let err = agent.ask(&mut conv, "sleep a long time").await.unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&LimitReached::Deadline(Duration::from_millis(100))));
+        let Content::ToolResult { is_error, .. } = &conv.messages.last().unwrap().content[0] else { panic!() };
+        assert!(is_error);
+    }
+}
@@ -0,0 +1,240 @@
+//! The message batches api: many requests processed asynchronously, at a discount, with results within a day.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Client, Conversation, MessagesResponse, Response, Spent,
+    client::{MessagesRequest, ToolDefinition},
+    models::Model,
+};
+
+/// Batched requests cost half as much as the same requests sent one at a time.
+const BATCH_DISCOUNT: f64 = 0.5;
+
+/// A submitted batch and how far along it is.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Batch {
+    pub id: String,
+    /// `in_progress`, `canceling` or `ended`
+    pub processing_status: String,
+    pub request_counts: RequestCounts,
+    /// where the results can be fetched from once the batch has ended
+    pub results_url: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+}
+
+impl Batch {
+    pub fn is_ended(&self) -> bool {
+        self.processing_status == "ended"
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct RequestCounts {
+    pub processing: u64,
+    pub succeeded: u64,
+    pub errored: u64,
+    pub canceled: u64,
+    pub expired: u64,
+}
+
+/// What became of one request of a batch.
+#[derive(Debug, Deserialize)]
+pub struct BatchResult {
+    pub custom_id: String,
+    pub result: BatchOutcome,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BatchOutcome {
+    Succeeded {
+        message: MessagesResponse,
+    },
+    /// the error is a [`Response::Error`]
+    Errored {
+        error: Response,
+    },
+    Canceled,
+    Expired,
+}
+
+#[derive(Serialize)]
+struct BatchRequest {
+    custom_id: String,
+    params: MessagesRequest,
+}
+
+impl Client {
+    /// Submits the next turn of each conversation, which should end with a user message, as a batch. The results
+    /// are identified by the id each conversation is paired with. The batch is refused if the requests together
+    /// could go over the client's budgets.
+    pub async fn create_batch(&self, convs: &[(String, Conversation)], tools: &[ToolDefinition]) -> Result<Batch> {
+        let mut requests = vec![];
+        let mut estimate = Spent::default();
+        for (custom_id, conv) in convs {
+            let params = self.with_defaults(MessagesRequest {
+                model: conv.model.clone(),
+                max_tokens: self.max_tokens(),
+                system: conv.system.clone(),
+                messages: conv.messages.clone(),
+                tools: tools.to_vec(),
+                ..Default::default()
+            });
+            estimate = estimate + discounted(params.estimate());
+            requests.push(BatchRequest { custom_id: custom_id.clone(), params });
+        }
+        self.check_estimate(estimate).with_context(|| format!("batch of {} requests", requests.len()))?;
+        let body = serde_json::json!({"requests": requests});
+        self.request_json(Method::POST, "/v1/messages/batches", Some(&body)).await
+    }
+
+    pub async fn batch(&self, id: &str) -> Result<Batch> {
+        self.request_json(Method::GET, &format!("/v1/messages/batches/{id}"), None::<&()>).await
+    }
+
+    /// Checks on the batch every `poll` until it has ended.
+    pub async fn wait_for_batch(&self, id: &str, poll: Duration) -> Result<Batch> {
+        loop {
+            let batch = self.batch(id).await?;
+            if batch.is_ended() {
+                return Ok(batch);
+            }
+            tracing::debug!("batch {id} is {}: {:?}", batch.processing_status, batch.request_counts);
+            tokio::time::sleep(poll).await;
+        }
+    }
+
+    /// The results of an ended batch, in no particular order. The usage of those which succeeded is counted against
+    /// the client's budgets, so they should be fetched only once.
+    pub async fn batch_results(&self, batch: &Batch) -> Result<Vec<BatchResult>> {
+        let url = batch.results_url.as_deref().with_context(|| format!("batch {} has no results yet", batch.id))?;
+        let text = self.request_text(Method::GET, url, None::<&()>).await?;
+        let results = text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).context("parse batch result"))
+            .collect::<Result<Vec<BatchResult>>>()?;
+        for result in &results {
+            if let BatchOutcome::Succeeded { message: MessagesResponse { model, usage: Some(usage), .. } } = &result.result {
+                self.record_spent(discounted(Spent::of(usage, &Model::from(model.as_str()))));
+            }
+        }
+        Ok(results)
+    }
+}
+
+fn discounted(spent: Spent) -> Spent {
+    Spent { dollars: spent.dollars * BATCH_DISCOUNT, ..spent }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::anthropic::{
+        Budget, BudgetExceeded, BudgetGuard,
+        mock::{MockResponse, MockServer},
+    };
+
+    #[tokio::test]
+    async fn batch() {
+        let server = MockServer::start().await.unwrap();
+        let guard = BudgetGuard::new(Budget::dollars(1.0));
+        let client = Client::new(String::from("key"))
+            .unwrap()
+            .with_endpoint(server.url())
+            .with_temperature(0.5)
+            .with_budget(guard.clone());
+        let batch = |status: &str, results_url: Option<String>| {
+            json!({
+                "id": "msgbatch_1",
+                "type": "message_batch",
+                "processing_status": status,
+                "request_counts": {"processing": 0, "succeeded": 1, "errored": 1, "canceled": 0, "expired": 0},
+                "results_url": results_url,
+                "created_at": "2024-11-01T00:00:00Z",
+                "ended_at": null,
+            })
+        };
+        let results_url = format!("{}v1/messages/batches/msgbatch_1/results", server.url());
+        server
+            .push(MockResponse::json(200, batch("in_progress", None)))
+            .push(MockResponse::json(200, batch("in_progress", None)))
+            .push(MockResponse::json(200, batch("ended", Some(results_url))));
+        let message = json!({
+            "custom_id": "a",
+            "result": {
+                "type": "succeeded",
+                "message": {
+                    "type": "message",
+                    "model": "claude-3-5-haiku-20241022",
+                    "content": [{"type": "text", "text": "hi"}],
+                    "usage": {"input_tokens": 1000, "output_tokens": 100},
+                },
+            },
+        });
+        let error = json!({
+            "custom_id": "b",
+            "result": {
+                "type": "errored",
+                "error": {"type": "error", "error": {"type": "invalid_request_error", "message": "bad"}},
+            },
+        });
+        server.push(MockResponse::text(&format!("{message}\n{error}\n")));
+
+        let convs = ["a", "b"].map(|id| {
+            let mut conv = Conversation::new(client.model(), None);
+            conv.push_user("hello");
+            (id.to_string(), conv)
+        });
+        let created = client.create_batch(&convs, &[]).await.unwrap();
+        assert!(!created.is_ended());
+        let ended = client.wait_for_batch(&created.id, Duration::ZERO).await.unwrap();
+        assert_eq!(ended.request_counts.errored, 1);
+        let results = client.batch_results(&ended).await.unwrap();
+        assert!(matches!(&results[0].result, BatchOutcome::Succeeded { message } if message.text() == "hi"));
+        assert!(
+            matches!(&results[1].result, BatchOutcome::Errored { error: Response::Error { error } } if error.message == "bad")
+        );
+
+        let reqs = server.requests();
+        assert_eq!((reqs[0].method.as_str(), reqs[0].path.as_str()), ("POST", "/v1/messages/batches"));
+        assert_eq!(reqs[0].body["requests"][1]["custom_id"], "b");
+        assert_eq!(reqs[0].body["requests"][1]["params"]["temperature"], 0.5);
+        assert_eq!((reqs[2].method.as_str(), reqs[2].path.as_str()), ("GET", "/v1/messages/batches/msgbatch_1"));
+        assert_eq!(reqs[3].path, "/v1/messages/batches/msgbatch_1/results");
+        // $0.0008 of input and $0.0004 of output, at half price
+        assert!((guard.spent().dollars - 0.0006).abs() < 1e-9, "{:?}", guard.spent());
+        assert_eq!(guard.spent().tokens, 1100);
+    }
+
+    #[tokio::test]
+    async fn batch_budget() {
+        let server = MockServer::start().await.unwrap();
+        // each request could cost about $0.002 at the batch discount, so one fits but not two
+        let guard = BudgetGuard::new(Budget::dollars(0.003));
+        let client = Client::new(String::from("key")).unwrap().with_endpoint(server.url()).with_budget(guard);
+        let convs = ["a", "b"].map(|id| {
+            let mut conv = Conversation::new(client.model(), None);
+            conv.push_user("hello");
+            (id.to_string(), conv)
+        });
+        let err = client.create_batch(&convs, &[]).await.unwrap_err();
+        assert_eq!(err.to_string(), "batch of 2 requests");
+        assert!(err.downcast_ref::<BudgetExceeded>().unwrap().estimate.unwrap().dollars > 0.003);
+        assert!(server.requests().is_empty());
+
+        server.push(MockResponse::error(400, "invalid_request_error", "bad"));
+        assert!(client.create_batch(&convs[..1], &[]).await.unwrap_err().downcast_ref::<BudgetExceeded>().is_none());
+        assert_eq!(server.requests().len(), 1);
+    }
+}
@@ -0,0 +1,196 @@
+//! hard caps on spend
+
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Usage, models::Model};
+
+/// Limits on total spend. Unset limits are not enforced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Budget {
+    pub max_dollars: Option<f64>,
+    /// input, output and cache tokens combined
+    pub max_tokens: Option<u64>,
+}
+
+/// What has been spent against a [`Budget`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Spent {
+    pub dollars: f64,
+    pub tokens: u64,
+    /// whether any of it was on a model missing from the pricing table, whose dollars are unknown
+    pub unpriced: bool,
+}
+
+/// Returned, inside the `anyhow::Error`, for any request refused because of a [`Budget`].
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error(
+    "budget exceeded: spent ${:.4} and {} tokens{}{}, budget is {}",
+    spent.dollars,
+    spent.tokens,
+    estimate_note(estimate),
+    unpriced_note(spent, estimate),
+    budget_note(budget)
+)]
+pub struct BudgetExceeded {
+    pub budget: Budget,
+    pub spent: Spent,
+    /// the estimated cost of the refused request, if it was refused before being sent
+    pub estimate: Option<Spent>,
+}
+
+fn estimate_note(estimate: &Option<Spent>) -> String {
+    estimate.map(|e| format!(" with ~${:.4} and {} tokens more requested", e.dollars, e.tokens)).unwrap_or_default()
+}
+
+fn unpriced_note(spent: &Spent, estimate: &Option<Spent>) -> &'static str {
+    if spent.unpriced || estimate.is_some_and(|e| e.unpriced) { " on a model of unknown pricing" } else { "" }
+}
+
+fn budget_note(budget: &Budget) -> String {
+    match (budget.max_dollars, budget.max_tokens) {
+        (Some(d), Some(t)) => format!("${d:.4} and {t} tokens"),
+        (Some(d), None) => format!("${d:.4}"),
+        (None, Some(t)) => format!("{t} tokens"),
+        (None, None) => String::from("unlimited"),
+    }
+}
+
+impl Budget {
+    pub fn dollars(max: f64) -> Self {
+        Self { max_dollars: Some(max), max_tokens: None }
+    }
+
+    pub fn tokens(max: u64) -> Self {
+        Self { max_dollars: None, max_tokens: Some(max) }
+    }
+
+    /// Fails once `spent` has reached either limit. A dollar limit can't be enforced on spend of unknown price, so
+    /// any such spend fails it.
+    pub fn check(&self, spent: Spent) -> Result<(), BudgetExceeded> {
+        let over = self.max_dollars.is_some_and(|max| spent.unpriced || spent.dollars >= max)
+            || self.max_tokens.is_some_and(|max| spent.tokens >= max);
+        if over { Err(BudgetExceeded { budget: *self, spent, estimate: None }) } else { Ok(()) }
+    }
+
+    /// Fails if spending `estimate` on top of `spent` would go over either limit.
+    pub fn check_estimate(&self, spent: Spent, estimate: Spent) -> Result<(), BudgetExceeded> {
+        self.check(spent)?;
+        let over = self.max_dollars.is_some_and(|max| estimate.unpriced || spent.dollars + estimate.dollars > max)
+            || self.max_tokens.is_some_and(|max| spent.tokens + estimate.tokens > max);
+        if over { Err(BudgetExceeded { budget: *self, spent, estimate: Some(estimate) }) } else { Ok(()) }
+    }
+}
+
+impl Spent {
+    /// The spend for `usage` on `model`. Models of unknown pricing are counted in tokens only, and marked
+    /// [`Spent::unpriced`].
+    pub fn of(usage: &Usage, model: &Model) -> Self {
+        // models warned about already, so that a conversation re-counting its turns warns only once
+        static UNPRICED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+        let cost = usage.cost(model);
+        if cost.is_none() && UNPRICED.lock().unwrap().insert(model.to_string()) {
+            tracing::warn!("no pricing for {model}; only its tokens are counted, and dollar budgets refuse it");
+        }
+        let tokens = usage.input_tokens + usage.output_tokens + usage.cache_creation_input_tokens + usage.cache_read_input_tokens;
+        Self { dollars: cost.map(|cost| cost.total()).unwrap_or_default(), tokens, unpriced: cost.is_none() }
+    }
+}
+
+impl std::ops::Add for Spent {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self { dollars: self.dollars + rhs.dollars, tokens: self.tokens + rhs.tokens, unpriced: self.unpriced || rhs.unpriced }
+    }
+}
+
+/// Tracks spend against a [`Budget`]. Clones share the same tally, so a guard can be attached to a client and then
+/// narrowed with another guard for a single batch of work, or handed to several clients at once.
+#[derive(Debug, Clone)]
+pub struct BudgetGuard {
+    budget: Budget,
+    spent: Arc<Mutex<Spent>>,
+}
+
+impl BudgetGuard {
+    pub fn new(budget: Budget) -> Self {
+        Self { budget, spent: Arc::default() }
+    }
+
+    pub fn budget(&self) -> Budget {
+        self.budget
+    }
+
+    pub fn spent(&self) -> Spent {
+        *self.spent.lock().unwrap()
+    }
+
+    pub fn check(&self) -> Result<(), BudgetExceeded> {
+        self.budget.check(self.spent())
+    }
+
+    pub fn check_estimate(&self, estimate: Spent) -> Result<(), BudgetExceeded> {
+        self.budget.check_estimate(self.spent(), estimate)
+    }
+
+    pub fn record(&self, usage: &Usage, model: &Model) {
+        self.record_spent(Spent::of(usage, model));
+    }
+
+    /// Counts spend which isn't priced as a plain request would be, e.g. discounted batch results.
+    pub fn record_spent(&self, spent: Spent) {
+        let mut total = self.spent.lock().unwrap();
+        *total = *total + spent;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anthropic::models::SONNET;
+
+    #[test]
+    fn guard() {
+        let guard = BudgetGuard::new(Budget { max_dollars: Some(1.0), max_tokens: Some(500_000) });
+        let shared = guard.clone();
+        let usage = Usage { input_tokens: 100_000, output_tokens: 10_000, ..Default::default() };
+        guard.record(&usage, &SONNET);
+        assert!((shared.spent().dollars - 0.45).abs() < 1e-9);
+        assert_eq!(shared.spent().tokens, 110_000);
+        assert!(shared.check().is_ok());
+        let err = shared.check_estimate(Spent { dollars: 0.6, tokens: 1, unpriced: false }).unwrap_err();
+        assert_eq!(err.estimate, Some(Spent { dollars: 0.6, tokens: 1, unpriced: false }));
+        assert!(shared.check_estimate(Spent { dollars: 0.5, tokens: 1, unpriced: false }).is_ok());
+
+        guard.record(&usage, &SONNET);
+        guard.record(&usage, &SONNET);
+        let err = shared.check().unwrap_err();
+        assert_eq!(err.to_string(), "budget exceeded: spent $1.3500 and 330000 tokens, budget is $1.0000 and 500000 tokens");
+    }
+
+    #[test]
+    fn unknown_model() {
+        let usage = Usage { input_tokens: 10, ..Default::default() };
+        let guard = BudgetGuard::new(Budget::tokens(10));
+        guard.record(&usage, &Model::from("mystery"));
+        assert_eq!(guard.spent(), Spent { dollars: 0.0, tokens: 10, unpriced: true });
+        assert!(guard.check().is_err());
+
+        // a dollar cap can't tell how much an unpriced model has spent, so refuses it rather than letting it through
+        let guard = BudgetGuard::new(Budget::dollars(1.0));
+        let estimate = Spent::of(&usage, &Model::from("mystery"));
+        let err = guard.check_estimate(estimate).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "budget exceeded: spent $0.0000 and 0 tokens with ~$0.0000 and 10 tokens more requested on a model of unknown \
+             pricing, budget is $1.0000"
+        );
+        guard.record(&usage, &Model::from("mystery"));
+        assert!(guard.check().is_err());
+        assert!(BudgetGuard::new(Budget::tokens(100)).check_estimate(estimate).is_ok());
+    }
+}
@@ -0,0 +1,217 @@
+//! Recording api traffic to a jsonl cassette, and replaying it later for offline, deterministic tests.
+//!
+//! Each line of a cassette is one [`Interaction`]: the request that was sent and the response that came back,
+//! including every server sent event of a streaming response. Requests are stored without their headers, so the
+//! api key never ends up in a cassette.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    pub body: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    pub body: RecordedBody,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordedBody {
+    Json(Value),
+    /// the data of each event, in order
+    Events(Vec<Value>),
+    /// a body which isn't json, like the jsonl of batch results
+    Text(String),
+}
+
+impl RecordedRequest {
+    /// The request with anything that varies between otherwise identical runs stripped out, used to match a replayed
+    /// request against the cassette.
+    pub fn normalized(&self) -> Value {
+        let mut body = self.body.clone();
+        if let Some(obj) = body.as_object_mut() {
+            obj.remove("metadata");
+        }
+        serde_json::json!({"method": self.method, "path": self.path, "body": body})
+    }
+}
+
+impl RecordedResponse {
+    /// Keeps the headers a replay should reproduce, like `retry-after` and the rate limit headers, and drops the
+    /// ones describing the original connection.
+    pub fn new(status: u16, headers: &HeaderMap, body: RecordedBody) -> Self {
+        const SKIP: &[&str] =
+            &["content-length", "content-type", "transfer-encoding", "connection", "date", "set-cookie", "cf-ray"];
+        let headers = headers
+            .iter()
+            .filter(|(k, _)| !SKIP.contains(&k.as_str()))
+            .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+            .collect();
+        Self { status, headers, body }
+    }
+}
+
+/// Appends interactions to a cassette as the client makes requests. Clones write to the same file.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+}
+
+impl Recorder {
+    /// Starts a new cassette at `path`, replacing any existing one.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path).with_context(|| format!("create cassette {}", path.display()))?;
+        Ok(Self { path: path.to_path_buf(), file: Arc::new(Mutex::new(file)) })
+    }
+
+    /// Adds to the cassette at `path`, creating it if it doesn't exist.
+    pub fn append(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("open cassette {}", path.display()))?;
+        Ok(Self { path: path.to_path_buf(), file: Arc::new(Mutex::new(file)) })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, interaction: &Interaction) -> Result<()> {
+        let mut line = serde_json::to_vec(interaction).context("serialize interaction")?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line).context("write cassette")?;
+        file.flush().context("flush cassette")
+    }
+}
+
+/// Reads every interaction from a cassette.
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<Interaction>> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("open cassette {}", path.display()))?;
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |l| !l.trim().is_empty()))
+        .map(|(i, line)| {
+            let line = line.context("read cassette")?;
+            serde_json::from_str(&line).with_context(|| format!("{}:{}: parse interaction", path.display(), i + 1))
+        })
+        .collect()
+}
+
+/// Serves the interactions in a cassette from a [`super::mock::MockServer`]. Each request is answered with the
+/// first not yet replayed interaction whose normalized request matches it, so a cassette which sent the same request
+/// several times replays those responses in their original order. Once they have all been replayed, the last is
+/// repeated. Requests missing from the cassette get a 404.
+#[cfg(any(test, feature = "mock"))]
+pub async fn replay(path: impl AsRef<Path>) -> Result<super::mock::MockServer> {
+    use super::mock::{MockBody, MockResponse, MockServer};
+
+    let mut interactions = load(path)?.into_iter().map(|i| (i, false)).collect::<Vec<_>>();
+    MockServer::start_with(move |req| {
+        let normalized =
+            RecordedRequest { method: req.method.clone(), path: req.path.clone(), body: req.body.clone() }.normalized();
+        let matching = |(i, _): &&mut (Interaction, bool)| i.request.normalized() == normalized;
+        let found = match interactions.iter_mut().filter(matching).find(|(_, replayed)| !*replayed) {
+            Some(found) => Some(found),
+            None => interactions.iter_mut().filter(matching).last(),
+        };
+        let Some((interaction, replayed)) = found else {
+            tracing::warn!("cassette has no interaction for {} {}", req.method, req.path);
+            return Some(MockResponse::error(404, "not_found_error", "no recorded interaction matches this request"));
+        };
+        *replayed = true;
+        let resp = &interaction.response;
+        let body = match &resp.body {
+            RecordedBody::Json(v) => MockBody::Json(v.clone()),
+            RecordedBody::Events(events) => MockBody::Sse { events: events.clone(), delay: std::time::Duration::ZERO },
+            RecordedBody::Text(text) => MockBody::Text(text.clone()),
+        };
+        Some(MockResponse { status: resp.status, headers: resp.headers.clone(), body, delay: Default::default() })
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::anthropic::{
+        Client, Conversation, RetryPolicy,
+        mock::{MockResponse, MockServer},
+    };
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cassette.jsonl");
+
+        // record against a scripted server, standing in for the real api
+        let server = MockServer::start().await.unwrap();
+        server.push(MockResponse::message("recorded reply"));
+        server.push(MockResponse::rate_limited(Duration::ZERO));
+        server.push(MockResponse::stream("recorded stream"));
+        let retry = RetryPolicy { initial_backoff: Duration::from_millis(1), ..Default::default() };
+        let client = Client::new(String::from("secret-key"))
+            .unwrap()
+            .with_endpoint(server.url())
+            .with_retry(retry)
+            .with_recorder(Recorder::create(&path).unwrap());
+        client.speak("hello").await.unwrap();
+        let mut conv = Conversation::new(client.model(), None);
+        client.stream_reply(&mut conv, "stream please").await.unwrap();
+        drop(server);
+
+        let cassette = std::fs::read_to_string(&path).unwrap();
+        assert!(!cassette.contains("secret-key"));
+        let interactions = load(&path).unwrap();
+        assert_eq!(interactions.len(), 3);
+        assert_eq!(interactions[1].response.status, 429);
+        assert!(interactions[1].response.headers.contains(&(String::from("retry-after"), String::from("0"))));
+        let RecordedBody::Events(events) = &interactions[2].response.body else { panic!("not events") };
+        assert_eq!(events.last().unwrap()["type"], "message_stop");
+
+        // and replay with nothing scripted
+        let server = replay(&path).await.unwrap();
+        let client = Client::new(String::from("other-key")).unwrap().with_endpoint(server.url()).with_retry(retry);
+        let resp = client.speak("hello").await.unwrap().into_messages().unwrap();
+        assert_eq!(resp.text(), "recorded reply");
+        let mut conv = Conversation::new(client.model(), None);
+        let resp = client.stream_reply(&mut conv, "stream please").await.unwrap();
+        assert_eq!(resp.text(), "recorded stream");
+        assert_eq!(server.requests().len(), 3);
+
+        let resp = client.speak("never recorded").await.unwrap();
+        assert!(matches!(resp, crate::anthropic::Response::Error { .. }));
+    }
+}
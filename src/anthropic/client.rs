@@ -1,5 +1,6 @@
 use std::{
     borrow::BorrowMut,
+    collections::HashMap,
     io::{self, Read, Write},
     ops::Deref,
     path::Path,
@@ -12,30 +13,115 @@ use anyhow::{Context, Result};
 use base64::{Engine, prelude::BASE64_STANDARD};
 use eventsource_stream::Eventsource;
 use futures::{Stream, StreamExt, TryStreamExt, channel::mpsc::Receiver};
-use reqwest::{Method, RequestBuilder};
+use reqwest::{
+    Method, RequestBuilder, StatusCode,
+    header::{HeaderMap, HeaderValue},
+};
 use serde::{Deserialize, Serialize};
 use tokio::{io::AsyncWriteExt, sync::mpsc};
 
-use super::models;
+use super::{
+    BudgetGuard, Compaction, Conversation, RetryPolicy, Spent,
+    cassette::{Interaction, RecordedBody, RecordedRequest, RecordedResponse, Recorder},
+    context::estimate_tokens,
+    models,
+    partial_json::parse_partial_json,
+};
+use crate::secret::SecretString;
 
+#[derive(Clone)]
 pub struct Client {
-    key: String,
+    key: SecretString,
     endpoint: url::Url,
     model: String,
     version: String,
     max_tokens: u32,
+    temperature: Option<f32>,
+    system: Option<String>,
     client: reqwest::Client,
+    budgets: Vec<BudgetGuard>,
+    retry: RetryPolicy,
+    recorder: Option<Recorder>,
+    pub(super) extract_attempts: u32,
 }
 
 impl Client {
-    pub fn new(key: String) -> Result<Self> {
+    pub fn new(key: impl Into<SecretString>) -> Result<Self> {
+        let key = key.into();
+        anyhow::ensure!(HeaderValue::from_str(key.expose()).is_ok(), "api key contains invalid characters");
         let endpoint = url::Url::parse("https://api.anthropic.com/").context("parse endpoint")?;
         let model = models::HAIKU.to_string();
         let version = String::from("2023-06-01");
         let max_tokens = 1024;
-        let client =
-            reqwest::ClientBuilder::default().timeout(Duration::from_secs(10)).build().context("build http client")?;
-        Ok(Self { key, endpoint, model, version, max_tokens, client })
+        let client = reqwest::ClientBuilder::default().timeout(Duration::from_secs(10)).build().context("build http client")?;
+        Ok(Self {
+            key,
+            endpoint,
+            model,
+            version,
+            max_tokens,
+            temperature: None,
+            system: None,
+            client,
+            budgets: vec![],
+            retry: RetryPolicy::default(),
+            recorder: None,
+            extract_attempts: 3,
+        })
+    }
+
+    /// Points the client at a different api host, e.g. a proxy or a [`super::mock::MockServer`].
+    pub fn with_endpoint(mut self, endpoint: url::Url) -> Self {
+        self.endpoint = endpoint;
+        self
+    }
+
+    /// The model new conversations and one-off requests use.
+    pub fn with_model(mut self, model: impl ToString) -> Self {
+        self.model = model.to_string();
+        self
+    }
+
+    /// The most tokens a reply may have.
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// Sent with every request; the api's default is 1.0.
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    /// The system prompt of one-off requests, such as [`Client::speak`]; conversations have their own.
+    pub fn with_system(mut self, system: impl ToString) -> Self {
+        self.system = Some(system.to_string());
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// How many times [`Client::extract`] asks the model before giving up.
+    pub fn with_extract_attempts(mut self, attempts: u32) -> Self {
+        self.extract_attempts = attempts;
+        self
+    }
+
+    /// Records every request and its response to a cassette, which [`super::cassette::replay`] can serve later.
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Returns a client which also enforces `guard`, in addition to any budgets this client already has. Every
+    /// response's usage is recorded against all of them.
+    pub fn with_budget(mut self, guard: BudgetGuard) -> Self {
+        self.budgets.push(guard);
+        self
     }
 
     pub async fn speak(&self, msg: &str) -> Result<Response> {
@@ -44,6 +130,7 @@ impl Client {
             max_tokens: self.max_tokens,
             stream: false,
             messages: vec![Message { role: String::from("user"), content: vec![Content::text(msg)] }],
+            system: self.system.clone(),
             ..Default::default()
         })
         .await
@@ -56,48 +143,324 @@ impl Client {
             stream: false,
             messages: vec![Message {
                 role: String::from("user"),
-                content: vec![
-                    Content::image_path(&image).await.context("image_path")?,
-                    Content::text("what is in this image?"),
-                ],
+                content: vec![Content::image_path(&image).await.context("image_path")?, Content::text("what is in this image?")],
             }],
             ..Default::default()
         })
         .await
     }
 
-    pub async fn stream_speak(&self, msg: &str) -> Result<()> {
+    pub async fn stream_speak(&self, msg: &str) -> Result<MessagesResponse> {
         let req = MessagesRequest {
             model: self.model.clone(),
             max_tokens: self.max_tokens,
             stream: true,
             messages: vec![Message { role: String::from("user"), content: vec![Content::text(msg)] }],
-            system: Some(String::from("you are a helpful, wise modern day carl sagan.")),
+            system: self.system.clone(),
+            ..Default::default()
         };
-        self.post_streaming_to_stream(req)
-            .await?
-            .try_for_each(|ev| async move {
-                match ev {
-                    TextStreamEvent::Fragment(s) => {
-                        print!("{s}");
-                        io::stdout().flush().context("flush stdout")?;
-                    }
-                    TextStreamEvent::Eof(_) => {}
-                };
+        self.print_stream(req).await
+    }
+
+    /// Sends `msg` as the next user turn of `conv`, printing the reply as it streams in. The reply and its usage
+    /// are appended to the conversation.
+    pub async fn stream_reply(&self, conv: &mut Conversation, msg: &str) -> Result<MessagesResponse> {
+        conv.push_user(msg);
+        if let Err(err) = self.fit_context(conv).await {
+            conv.pop_user();
+            return Err(err.context("fit context"));
+        }
+        let req = MessagesRequest {
+            model: conv.model.clone(),
+            max_tokens: self.max_tokens,
+            stream: true,
+            messages: conv.messages.clone(),
+            system: conv.system.clone(),
+            ..Default::default()
+        };
+        if let Some(budget) = &conv.budget {
+            if let Err(err) = budget.check_estimate(conv.spent(), req.estimate()) {
+                conv.pop_user();
+                return Err(err.into());
+            }
+        }
+        let resp = match self.print_stream(req).await {
+            Ok(resp) => resp,
+            Err(err) => {
+                // leave the conversation as it was so that the turn can be retried
+                conv.pop_user();
+                return Err(err);
+            }
+        };
+        conv.push_response(&resp);
+        Ok(resp)
+    }
+
+    /// Has the model take the next turn of `conv`, which should end with a user message, offering it `tools`. The
+    /// reply is appended to the conversation; on error the conversation is left as it was, give or take compaction.
+    pub async fn reply(&self, conv: &mut Conversation, tools: &[ToolDefinition]) -> Result<MessagesResponse> {
+        self.fit_context(conv).await.context("fit context")?;
+        let req = MessagesRequest {
+            model: conv.model.clone(),
+            max_tokens: self.max_tokens,
+            system: conv.system.clone(),
+            messages: conv.messages.clone(),
+            tools: tools.to_vec(),
+            ..Default::default()
+        };
+        if let Some(budget) = &conv.budget {
+            budget.check_estimate(conv.spent(), req.estimate())?;
+        }
+        let resp = self.post_messages_req(req).await?.into_messages()?;
+        conv.push_response(&resp);
+        Ok(resp)
+    }
+
+    /// Like [`Client::reply`], but streams the reply, passing `on_event` everything it produces as it arrives:
+    /// text, and each `tool_use` block's input both while it streams and once it is complete.
+    pub async fn stream_reply_with(
+        &self,
+        conv: &mut Conversation,
+        tools: &[ToolDefinition],
+        mut on_event: impl FnMut(&TextStreamEvent),
+    ) -> Result<MessagesResponse> {
+        self.fit_context(conv).await.context("fit context")?;
+        let req = MessagesRequest {
+            model: conv.model.clone(),
+            max_tokens: self.max_tokens,
+            stream: true,
+            system: conv.system.clone(),
+            messages: conv.messages.clone(),
+            tools: tools.to_vec(),
+            ..Default::default()
+        };
+        if let Some(budget) = &conv.budget {
+            budget.check_estimate(conv.spent(), req.estimate())?;
+        }
+        let resp = self
+            .collect_stream(req, |ev| {
+                on_event(ev);
                 Ok(())
             })
-            .await
-            .context("text stream failure")
+            .await?;
+        conv.push_response(&resp);
+        Ok(resp)
     }
 
-    async fn post_messages_req(&self, req: impl Into<MessagesRequest>) -> Result<Response> {
+    /// Prints each text fragment as it arrives and returns the accumulated response.
+    async fn print_stream(&self, req: MessagesRequest) -> Result<MessagesResponse> {
+        self.collect_stream(req, |ev| {
+            if let TextStreamEvent::Fragment(s) = ev {
+                print!("{s}");
+                io::stdout().flush().context("flush stdout")?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Streams the response to `req`, passing each event to `on_event`, and returns the accumulated response.
+    async fn collect_stream(
+        &self,
+        req: MessagesRequest,
+        mut on_event: impl FnMut(&TextStreamEvent) -> Result<()>,
+    ) -> Result<MessagesResponse> {
+        let stream = self.post_streaming_to_stream(req).await?;
+        tokio::pin!(stream);
+        while let Some(ev) = stream.next().await {
+            let ev = ev.context("text stream failure")?;
+            on_event(&ev)?;
+            if let TextStreamEvent::Eof(resp) = ev {
+                return Ok(resp);
+            }
+        }
+        anyhow::bail!("text stream ended without message_stop")
+    }
+
+    /// Asks the api how many input tokens the conversation would use as a request.
+    pub async fn count_tokens(&self, conv: &Conversation) -> Result<u64> {
+        #[derive(Serialize)]
+        struct CountTokensRequest<'a> {
+            model: &'a str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            system: Option<&'a str>,
+            messages: &'a [Message],
+        }
+        #[derive(Deserialize)]
+        struct CountTokensResponse {
+            input_tokens: u64,
+        }
+        let url = self.endpoint.join("/v1/messages/count_tokens").context("build url")?;
+        let body = CountTokensRequest { model: &conv.model, system: conv.system.as_deref(), messages: &conv.messages };
+        let req = self.new_http_req(Method::POST, url).json(&body).build().context("build request")?;
+        let resp = self.send(req).await?;
+        let (status, headers) = (resp.status(), resp.headers().clone());
+        let text = resp.text().await.context("resp text")?;
+        self.record_json(&Method::POST, "/v1/messages/count_tokens", &body, status, &headers, &text);
+        match serde_json::from_str::<CountTokensResponse>(&text) {
+            Ok(count) => Ok(count.input_tokens),
+            Err(err) => match serde_json::from_str::<Response>(&text) {
+                Ok(Response::Error { error }) => Err(error.into()),
+                _ => Err(err).context("parse count_tokens json"),
+            },
+        }
+    }
+
+    /// Compacts the conversation according to its [`ContextPolicy`] if it has grown close to the model's context
+    /// window. Returns whether anything was compacted.
+    pub async fn fit_context(&self, conv: &mut Conversation) -> Result<bool> {
+        let Some(compaction) = conv.context.compaction.clone() else {
+            return Ok(false);
+        };
+        let limit = conv.context.limit(models::Model::from(conv.model.as_str()).context_window(), self.max_tokens);
+        let mut compacted = false;
+        while self.conversation_tokens(conv).await? > limit {
+            let turns = conv.turn_starts().len();
+            match compaction {
+                Compaction::DropOldest if turns > 1 => conv.drop_turns(0..1),
+                Compaction::KeepEnds { first, last } if turns > first + last.max(1) => {
+                    conv.drop_turns(first..turns - last.max(1))
+                }
+                // summarizing a second time would only summarize the summary
+                Compaction::Summarize { keep_last } if turns > keep_last.max(1) && !compacted => {
+                    let keep_from = turns - keep_last.max(1);
+                    let summary = self.summarize(conv, conv.turn_starts()[keep_from]).await?;
+                    conv.replace_with_summary(keep_from, &summary);
+                }
+                _ => anyhow::bail!("conversation exceeds {limit} tokens and cannot be compacted further"),
+            }
+            tracing::debug!("compacted conversation {} with {compaction:?}", conv.id);
+            compacted = true;
+        }
+        Ok(compacted)
+    }
+
+    async fn conversation_tokens(&self, conv: &Conversation) -> Result<u64> {
+        if conv.context.count_tokens { self.count_tokens(conv).await } else { Ok(conv.estimated_tokens()) }
+    }
+
+    /// Has the conversation's model condense its first `to` messages, which must end with an assistant turn, into a
+    /// summary. The summary's usage is recorded on the conversation, and so counts against its budget.
+    async fn summarize(&self, conv: &mut Conversation, to: usize) -> Result<String> {
+        let mut messages = conv.messages[..to].to_vec();
+        messages.push(Message {
+            role: String::from("user"),
+            content: vec![Content::text(
+                "Summarize our conversation so far. Keep every fact, decision and open question needed to continue \
+                 it. Reply with only the summary.",
+            )],
+        });
+        let req = MessagesRequest {
+            model: conv.model.clone(),
+            max_tokens: self.max_tokens,
+            stream: false,
+            system: conv.system.clone(),
+            messages,
+            ..Default::default()
+        };
+        if let Some(budget) = &conv.budget {
+            budget.check_estimate(conv.spent(), req.estimate())?;
+        }
+        let resp = self.post_messages_req(req).await.context("summarize")?.into_messages()?;
+        conv.push_summary_usage(&resp);
+        Ok(resp.text())
+    }
+
+    /// The model new conversations will use.
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn max_tokens(&self) -> u32 {
+        self.max_tokens
+    }
+
+    pub fn temperature(&self) -> Option<f32> {
+        self.temperature
+    }
+
+    /// Sends a request to the api, returning the body of a successful response. Unsuccessful ones become an
+    /// [`ApiError`]. `path` may also be an absolute url, as the api hands out for batch results.
+    pub(super) async fn request_text(&self, method: Method, path: &str, body: Option<&impl Serialize>) -> Result<String> {
+        let url = self.endpoint.join(path).context("build url")?;
+        let mut req = self.new_http_req(method.clone(), url);
+        if let Some(body) = body {
+            req = req.json(body);
+        }
+        let req = req.build().context("build request")?;
+        let path = match req.url().query() {
+            Some(query) => format!("{}?{query}", req.url().path()),
+            None => req.url().path().to_string(),
+        };
+        let resp = self.send(req).await?;
+        let (status, headers) = (resp.status(), resp.headers().clone());
+        if !status.is_success() {
+            let err = ApiError::from_response(resp).await;
+            let text = serde_json::json!({"type": "error", "error": {"type": err.error.typ, "message": err.error.message}});
+            self.record_json(&method, &path, &body, status, &headers, &text.to_string());
+            return Err(err.into());
+        }
+        let text = resp.text().await.context("resp text")?;
+        self.record_json(&method, &path, &body, status, &headers, &text);
+        Ok(text)
+    }
+
+    /// Like [`Client::request_text`], parsing the response as json.
+    pub(super) async fn request_json<T: serde::de::DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&impl Serialize>,
+    ) -> Result<T> {
+        let text = self.request_text(method, path, body).await?;
+        serde_json::from_str(&text).with_context(|| format!("parse {path} json"))
+    }
+
+    /// Fills in the request settings the client has and the request doesn't.
+    pub(super) fn with_defaults(&self, mut req: MessagesRequest) -> MessagesRequest {
+        req.temperature = req.temperature.or(self.temperature);
+        req
+    }
+
+    /// Refuses requests which any budget can't afford.
+    fn check_budgets(&self, req: &MessagesRequest) -> Result<()> {
+        if self.budgets.is_empty() {
+            return Ok(());
+        }
+        self.check_estimate(req.estimate())
+    }
+
+    /// Fails if spending `estimate` would go over any of the client's budgets.
+    pub(super) fn check_estimate(&self, estimate: Spent) -> Result<()> {
+        for guard in &self.budgets {
+            guard.check_estimate(estimate)?;
+        }
+        Ok(())
+    }
+
+    /// Counts `spent` against every one of the client's budgets.
+    pub(super) fn record_spent(&self, spent: Spent) {
+        self.budgets.iter().for_each(|guard| guard.record_spent(spent));
+    }
+
+    fn record_usage(budgets: &[BudgetGuard], resp: &MessagesResponse) {
+        if let Some(usage) = &resp.usage {
+            let model = models::Model::from(resp.model.as_str());
+            budgets.iter().for_each(|guard| guard.record(usage, &model));
+        }
+    }
+
+    pub(super) async fn post_messages_req(&self, req: impl Into<MessagesRequest>) -> Result<Response> {
         let method = reqwest::Method::POST;
         let url = self.endpoint.join("/v1/messages").context("build url")?;
-        let body = req.into();
+        let body = self.with_defaults(req.into());
+        self.check_budgets(&body)?;
         let req = self.new_http_req(method, url).json(&body).build().context("build request")?;
-        let resp = self.client.execute(req).await.context("exec req")?;
+        let resp = self.send(req).await?;
         let code = resp.status();
+        let headers = resp.headers().clone();
         let text = resp.text().await.context("resp text")?;
+        self.record_json(&Method::POST, "/v1/messages", &body, code, &headers, &text);
         let resp = match serde_json::from_str(&text).context("parse json") {
             Ok(v) => v,
             Err(err) => {
@@ -110,6 +473,9 @@ impl Client {
                 return Err(err);
             }
         };
+        if let Response::Messages(resp) = &resp {
+            Self::record_usage(&self.budgets, resp);
+        }
         Ok(resp)
     }
 
@@ -119,10 +485,28 @@ impl Client {
     ) -> Result<impl Stream<Item = Result<TextStreamEvent>>> {
         let method = reqwest::Method::POST;
         let url = self.endpoint.join("/v1/messages").context("build url")?;
-        let body = req.into();
+        let body = self.with_defaults(req.into());
+        self.check_budgets(&body)?;
         let req = self.new_http_req(method, url).json(&body).build().context("build request")?;
-        let stream = self.client.execute(req).await.context("exec req")?.bytes_stream().eventsource();
-        let stream = event_stream_to_text_events(stream);
+        let resp = self.send(req).await?;
+        if !resp.status().is_success() {
+            let (status, headers) = (resp.status(), resp.headers().clone());
+            let err = ApiError::from_response(resp).await;
+            let text = serde_json::json!({"type": "error", "error": {"type": err.error.typ, "message": err.error.message}});
+            self.record_json(&Method::POST, "/v1/messages", &body, status, &headers, &text.to_string());
+            return Err(err.into());
+        }
+        let recording = self.recorder.clone().map(|recorder| {
+            let request = recorded_request(&Method::POST, "/v1/messages", &body);
+            (recorder, request, resp.status().as_u16(), resp.headers().clone())
+        });
+        let stream = record_events(resp.bytes_stream().eventsource(), recording);
+        let budgets = self.budgets.clone();
+        let stream = event_stream_to_text_events(stream).inspect_ok(move |ev| {
+            if let TextStreamEvent::Eof(resp) = ev {
+                Self::record_usage(&budgets, resp);
+            }
+        });
         Ok(stream)
     }
 
@@ -137,70 +521,161 @@ impl Client {
         Ok(rx)
     }
 
+    /// Records a response with a body which, unless it isn't json (like batch results' jsonl), is recorded as json.
+    fn record_json(
+        &self,
+        method: &Method,
+        path: &str,
+        body: &impl Serialize,
+        status: StatusCode,
+        headers: &HeaderMap,
+        text: &str,
+    ) {
+        let Some(recorder) = &self.recorder else { return };
+        let resp = match serde_json::from_str(text) {
+            Ok(json) => RecordedBody::Json(json),
+            Err(_) => RecordedBody::Text(text.to_string()),
+        };
+        let interaction = Interaction {
+            request: recorded_request(method, path, body),
+            response: RecordedResponse::new(status.as_u16(), headers, resp),
+        };
+        record(recorder, &interaction);
+    }
+
+    /// Executes the request, retrying according to the client's [`RetryPolicy`]. The final response is returned
+    /// whatever its status.
+    async fn send(&self, mut req: reqwest::Request) -> Result<reqwest::Response> {
+        let mut attempt = 0;
+        loop {
+            let retry = req.try_clone().context("clone request")?;
+            let resp = self.client.execute(req).await.context("exec req")?;
+            let status = resp.status();
+            if !RetryPolicy::retryable(status) || attempt >= self.retry.max_retries {
+                return Ok(resp);
+            }
+            let delay = self.retry.delay(attempt, resp.headers());
+            tracing::warn!("{} returned {status}, retrying in {delay:?}", retry.url());
+            if self.recorder.is_some() {
+                // the discarded attempts are part of what happened, so they are recorded too
+                let headers = resp.headers().clone();
+                let text = resp.text().await.unwrap_or_default();
+                let body = retry.body().and_then(|b| b.as_bytes()).and_then(|b| serde_json::from_slice(b).ok());
+                let body: serde_json::Value = body.unwrap_or_default();
+                self.record_json(retry.method(), retry.url().path(), &body, status, &headers, &text);
+            }
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+            req = retry;
+        }
+    }
+
     fn new_http_req(&self, method: reqwest::Method, url: impl reqwest::IntoUrl) -> RequestBuilder {
+        // sensitive values are left out of reqwest's and hyper's debug output
+        let mut key = HeaderValue::from_str(self.key.expose()).expect("checked by Client::new");
+        key.set_sensitive(true);
         self.client
             .request(method, url)
-            .header("x-api-key", &self.key)
+            .header("x-api-key", key)
             .header("anthropic-version", &self.version)
             .header("content-type", "application/json")
     }
 }
 
-#[derive(Debug)]
-enum TextStreamEvent {
+/// What a streaming response has produced so far. Serializes as `{"type": "fragment", "data": "..."}` and so on.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum TextStreamEvent {
+    /// more text
     Fragment(String),
+    /// the input of the `tool_use` block at `index` so far, as much of it as could be parsed
+    PartialToolInput { index: usize, name: String, input: serde_json::Value },
+    /// the complete input of the `tool_use` block at `index`
+    ToolInput { index: usize, id: String, name: String, input: serde_json::Value },
+    /// the whole response
     Eof(MessagesResponse),
 }
 
+/// The response being put together from a stream's events.
+#[derive(Default)]
+struct StreamState {
+    resp: Option<MessagesResponse>,
+    /// the raw json received so far for each `tool_use` block
+    tool_inputs: HashMap<usize, String>,
+}
+
 fn event_stream_to_text_events<S>(stream: S) -> impl Stream<Item = Result<TextStreamEvent>>
 where
     S: Stream<Item = Result<eventsource_stream::Event, eventsource_stream::EventStreamError<reqwest::Error>>>,
 {
     type Mutex<T> = tokio::sync::Mutex<T>;
-    let res = MessagesResponse::default();
-    let res = Arc::new(Mutex::new(Some(res)));
+    let state = StreamState { resp: Some(MessagesResponse::default()), ..Default::default() };
+    let state = Arc::new(Mutex::new(state));
     stream
         .map(|e| e.context("event stream error"))
-        .and_then(|e| async move {
-            serde_json::from_str::<ServerStreamEvent>(&e.data).context("parse ServerStreamEvent json")
-        })
+        .and_then(|e| async move { serde_json::from_str::<ServerStreamEvent>(&e.data).context("parse ServerStreamEvent json") })
         .and_then(move |sse| {
-            let msg = res.clone();
+            let state = state.clone();
             async move {
+                let mut state = state.lock().await;
+                let StreamState { resp, tool_inputs } = &mut *state;
+                let msg = resp.as_mut().context("no acc")?;
                 match sse {
                     ServerStreamEvent::MessageStart { message } => {
-                        let mut res = msg.lock().await;
-                        match res.as_mut() {
-                            Some(res) => res.extend(message),
-                            None => anyhow::bail!("no acc"),
-                        }
+                        msg.extend(message);
                         Ok(None)
                     }
                     ServerStreamEvent::StartBlock { index, content } => {
-                        anyhow::ensure!(index == 0, "index not zero");
-                        Ok(Some(TextStreamEvent::Fragment(content.to_string())))
+                        anyhow::ensure!(index == msg.content.len(), "block {index} started out of order");
+                        let event = match &content {
+                            Content::Text { text } => Some(TextStreamEvent::Fragment(text.clone())),
+                            Content::ToolUse { .. } => {
+                                tool_inputs.insert(index, String::new());
+                                None
+                            }
+                            _ => None,
+                        };
+                        msg.content.push(content);
+                        Ok(event)
+                    }
+                    ServerStreamEvent::BlockDelta { index, delta: Content::InputJsonDelta { partial_json } } => {
+                        let json = tool_inputs.get_mut(&index).with_context(|| format!("no tool_use at {index}"))?;
+                        json.push_str(&partial_json);
+                        let Some(Content::ToolUse { name, .. }) = msg.content.get(index) else {
+                            anyhow::bail!("no tool_use at {index}");
+                        };
+                        let input = parse_partial_json(json);
+                        Ok(input.map(|input| TextStreamEvent::PartialToolInput { index, name: name.clone(), input }))
                     }
                     ServerStreamEvent::BlockDelta { index, delta } => {
-                        anyhow::ensure!(index == 0, "index not zero");
-                        Ok(Some(TextStreamEvent::Fragment(delta.to_string())))
+                        let fragment = delta.to_string();
+                        msg.apply_delta(index, delta)?;
+                        Ok(Some(TextStreamEvent::Fragment(fragment)))
                     }
                     ServerStreamEvent::BlockStop { index } => {
-                        anyhow::ensure!(index == 0, "index not zero");
-                        Ok(None)
-                    }
-                    ServerStreamEvent::MessageDelta { message } => {
-                        let mut res = msg.lock().await;
-                        match res.as_mut() {
-                            Some(res) => res.extend(message),
-                            None => anyhow::bail!("no acc"),
+                        let Some(json) = tool_inputs.remove(&index) else {
+                            return Ok(None);
+                        };
+                        let Some(Content::ToolUse { id, name, input }) = msg.content.get_mut(index) else {
+                            anyhow::bail!("no tool_use at {index}");
+                        };
+                        // a tool called without input may get no deltas at all, leaving the input it started with
+                        if !json.trim().is_empty() {
+                            *input = serde_json::from_str(&json).with_context(|| format!("parse {name} input"))?;
                         }
+                        let (id, name, input) = (id.clone(), name.clone(), input.clone());
+                        Ok(Some(TextStreamEvent::ToolInput { index, id, name, input }))
+                    }
+                    ServerStreamEvent::MessageDelta { message, usage } => {
+                        msg.extend_delta(MessagesResponse { usage, ..message });
                         Ok(None)
                     }
                     ServerStreamEvent::MessageStop => {
-                        let msg = msg.lock().await.take().context("no acc")?;
+                        let msg = resp.take().context("no acc")?;
                         Ok(Some(TextStreamEvent::Eof(msg)))
                     }
                     ServerStreamEvent::Ping => Ok(None),
+                    ServerStreamEvent::Error { error } => Err(error.into()),
                 }
             }
         })
@@ -213,6 +688,51 @@ where
         })
 }
 
+fn recorded_request(method: &Method, path: &str, body: &impl Serialize) -> RecordedRequest {
+    let body = serde_json::to_value(body).unwrap_or_default();
+    RecordedRequest { method: method.to_string(), path: path.to_string(), body }
+}
+
+fn record(recorder: &Recorder, interaction: &Interaction) {
+    if let Err(err) = recorder.record(interaction) {
+        tracing::warn!("failed to record to {}: {err:#}", recorder.path().display());
+    }
+}
+
+/// Passes the eventsource stream through, recording its events once the message stops, errors, or the stream ends.
+fn record_events<S, E>(
+    stream: S,
+    recording: Option<(Recorder, RecordedRequest, u16, HeaderMap)>,
+) -> impl Stream<Item = Result<eventsource_stream::Event, E>>
+where
+    S: Stream<Item = Result<eventsource_stream::Event, E>>,
+{
+    async_stream::stream! {
+        let mut recording = recording;
+        let mut events = vec![];
+        for await event in stream {
+            if let (Some(_), Ok(ev)) = (&recording, &event) {
+                let data = serde_json::from_str(&ev.data).unwrap_or_else(|_| serde_json::Value::String(ev.data.clone()));
+                let last = matches!(data["type"].as_str(), Some("message_stop" | "error"));
+                events.push(data);
+                if last {
+                    // the consumer may stop polling as soon as it sees this event
+                    if let Some((recorder, request, status, headers)) = recording.take() {
+                        let body = RecordedBody::Events(std::mem::take(&mut events));
+                        let response = RecordedResponse::new(status, &headers, body);
+                        record(&recorder, &Interaction { request, response });
+                    }
+                }
+            }
+            yield event;
+        }
+        if let Some((recorder, request, status, headers)) = recording.take() {
+            let response = RecordedResponse::new(status, &headers, RecordedBody::Events(events));
+            record(&recorder, &Interaction { request, response });
+        }
+    }
+}
+
 /// consumes the eventsource stream and produces TextStreamEvents onto the supplied sender
 async fn stream_text_events<S>(stream: S, tx: mpsc::Sender<Result<TextStreamEvent>>)
 where
@@ -222,9 +742,8 @@ where
         tokio::pin!(stream);
         let mut resp = MessagesResponse::default();
         while let Some(event) = stream.next().await {
-            let event: ServerStreamEvent = event
-                .context("eventsource stream error")
-                .and_then(|e| serde_json::from_str(&e.data).context("parse json"))?;
+            let event: ServerStreamEvent =
+                event.context("eventsource stream error").and_then(|e| serde_json::from_str(&e.data).context("parse json"))?;
             match event {
                 ServerStreamEvent::MessageStart { message } => {
                     resp.extend(message);
@@ -246,11 +765,12 @@ where
                 ServerStreamEvent::BlockStop { index } => {
                     anyhow::ensure!(index == 0, "bad index: {index}");
                 }
-                ServerStreamEvent::MessageDelta { message } => {
-                    resp.extend(message);
+                ServerStreamEvent::MessageDelta { message, usage } => {
+                    resp.extend_delta(MessagesResponse { usage, ..message });
                 }
                 ServerStreamEvent::MessageStop => {}
                 ServerStreamEvent::Ping => {}
+                ServerStreamEvent::Error { error } => return Err(error.into()),
             };
         }
         anyhow::Ok(())
@@ -281,11 +801,16 @@ pub enum ServerStreamEvent {
     MessageDelta {
         #[serde(rename = "delta")]
         message: MessagesResponse,
+        #[serde(default)]
+        usage: Option<Usage>,
     },
     #[serde(rename = "message_stop")]
     MessageStop,
     #[serde(rename = "ping")]
     Ping,
+    /// e.g. an overloaded_error part way through a response
+    #[serde(rename = "error")]
+    Error { error: ServerError },
 }
 
 // Anthropic response for all of its apis
@@ -298,35 +823,152 @@ pub enum Response {
     Error { error: ServerError },
 }
 
-#[derive(Debug, Deserialize)]
+impl Response {
+    /// Converts an error response into an `Err`.
+    pub fn into_messages(self) -> Result<MessagesResponse> {
+        match self {
+            Response::Messages(resp) => Ok(resp),
+            Response::Error { error } => Err(error.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, thiserror::Error)]
+#[error("{typ}: {message}")]
 pub struct ServerError {
     #[serde(rename = "type")]
-    typ: String,
-    message: String,
+    pub typ: String,
+    pub message: String,
 }
 
-#[derive(Debug, Serialize, Default)]
-struct MessagesRequest {
-    model: String,
-    max_tokens: u32,
-    stream: bool,
-    system: Option<String>,
-    messages: Vec<Message>,
+impl ServerError {
+    /// Whether the api key was invalid or lacks permission.
+    pub fn is_auth(&self) -> bool {
+        matches!(self.typ.as_str(), "authentication_error" | "permission_error")
+    }
+}
+
+/// A request the api refused or failed, returned inside the `anyhow::Error`.
+#[derive(Debug, thiserror::Error)]
+#[error("api returned {status}: {error}")]
+pub struct ApiError {
+    pub status: reqwest::StatusCode,
+    pub error: ServerError,
+}
+
+impl ApiError {
+    /// Consumes an unsuccessful response. Bodies which aren't api errors are kept as the message.
+    async fn from_response(resp: reqwest::Response) -> Self {
+        let status = resp.status();
+        let text = resp.text().await.unwrap_or_default();
+        let error = match serde_json::from_str::<Response>(&text) {
+            Ok(Response::Error { error }) => error,
+            _ => ServerError { typ: String::from("unknown"), message: text },
+        };
+        Self { status, error }
+    }
+
+    /// Whether the api key was missing, invalid or lacks permission.
+    pub fn is_auth(&self) -> bool {
+        self.status == reqwest::StatusCode::UNAUTHORIZED || self.status == reqwest::StatusCode::FORBIDDEN
+    }
+}
+
+/// The body of a request to the messages api.
+#[derive(Debug, Clone, Serialize, Default, PartialEq)]
+pub struct MessagesRequest {
+    pub model: String,
+    pub max_tokens: u32,
+    pub stream: bool,
+    pub system: Option<String>,
+    pub messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolDefinition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+}
+
+/// A tool the model may call, described by a json schema of its input.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    pub input_schema: serde_json::Value,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ToolChoice {
+    /// the model decides whether to call a tool
+    Auto,
+    /// the model must call one of the tools
+    Any,
+    /// the model must call the named tool
+    Tool { name: String },
+}
+
+impl MessagesRequest {
+    /// The most this request could cost: its estimated input plus a reply of `max_tokens`.
+    pub(super) fn estimate(&self) -> Spent {
+        let usage = Usage {
+            input_tokens: estimate_tokens(self.system.as_deref(), &self.messages),
+            output_tokens: self.max_tokens as u64,
+            ..Default::default()
+        };
+        Spent::of(&usage, &models::Model::from(self.model.as_str()))
+    }
 }
 
-#[derive(Clone, Debug, Deserialize, Default)]
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
 #[serde(default)]
 pub struct MessagesResponse {
-    content: Vec<Content>,
-    id: String,
-    model: String,
-    role: String,
-    stop_reason: Option<String>,
-    stop_sequence: Option<String>,
-    usage: Option<Usage>,
+    pub content: Vec<Content>,
+    pub id: String,
+    pub model: String,
+    pub role: String,
+    pub stop_reason: Option<String>,
+    pub stop_sequence: Option<String>,
+    pub usage: Option<Usage>,
 }
 
 impl MessagesResponse {
+    /// The concatenated text blocks of the response.
+    pub fn text(&self) -> String {
+        self.content
+            .iter()
+            .filter_map(|c| match c {
+                Content::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// What this response cost, or `None` if the model's pricing is unknown.
+    pub fn cost(&self) -> Option<models::Cost> {
+        self.usage.as_ref()?.cost(&models::Model::from(self.model.as_str()))
+    }
+
+    /// Like [`MessagesResponse::extend`], but for the fields of a message_delta event.
+    fn extend_delta(&mut self, mut delta: Self) {
+        let usage = delta.usage.take();
+        self.extend(delta);
+        if let Some(usage) = usage {
+            self.usage.get_or_insert_with(Usage::default).update(usage);
+        }
+    }
+
+    /// Folds a streamed content_block_delta into the block at `index`.
+    fn apply_delta(&mut self, index: usize, delta: Content) -> Result<()> {
+        let block = self.content.get_mut(index).with_context(|| format!("no content block at {index}"))?;
+        match (block, delta) {
+            (Content::Text { text }, Content::TextDelta { text: delta }) => text.push_str(&delta),
+            (block, delta) => anyhow::bail!("cannot apply {delta:?} to {block:?}"),
+        }
+        Ok(())
+    }
+
     fn extend(&mut self, other: Self) {
         self.content.extend(other.content);
         if !other.id.is_empty() {
@@ -357,10 +999,10 @@ impl MessagesResponse {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Message {
-    role: String,
-    content: Vec<Content>,
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Message {
+    pub role: String,
+    pub content: Vec<Content>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -370,8 +1012,23 @@ pub enum Content {
     Text { text: String },
     #[serde(rename = "text_delta")]
     TextDelta { text: String },
+    /// a fragment of a streaming `tool_use` block's input
+    #[serde(rename = "input_json_delta")]
+    InputJsonDelta { partial_json: String },
     #[serde(rename = "image")]
     Image { source: ImageSource },
+    /// a pdf
+    #[serde(rename = "document")]
+    Document { source: ImageSource },
+    #[serde(rename = "tool_use")]
+    ToolUse { id: String, name: String, input: serde_json::Value },
+    #[serde(rename = "tool_result")]
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        is_error: bool,
+    },
 }
 
 impl std::fmt::Display for Content {
@@ -379,29 +1036,59 @@ impl std::fmt::Display for Content {
         match self {
             Content::Text { text } => write!(f, "{text}"),
             Content::TextDelta { text } => write!(f, "{text}"),
-            Content::Image { source: ImageSource { media_type, data, .. } } => {
+            Content::InputJsonDelta { partial_json } => write!(f, "{partial_json}"),
+            Content::Image { source: ImageSource { media_type, data, .. } }
+            | Content::Document { source: ImageSource { media_type, data, .. } } => {
                 write!(f, "[{media_type} ({} bytes)]", data.len())
             }
+            Content::ToolUse { name, input, .. } => write!(f, "[tool_use {name} {input}]"),
+            Content::ToolResult { content, is_error: false, .. } => write!(f, "[tool_result {content}]"),
+            Content::ToolResult { content, is_error: true, .. } => write!(f, "[tool_error {content}]"),
         }
     }
 }
 
 impl Content {
-    fn text(s: impl ToString) -> Self {
+    pub fn text(s: impl ToString) -> Self {
         Content::Text { text: s.to_string() }
     }
 
-    async fn image_path(p: impl AsRef<Path>) -> Result<Self> {
+    /// An image block holding the image file at `p`, whose type is guessed from its extension.
+    pub async fn image_path(p: impl AsRef<Path>) -> Result<Self> {
         let mime = mime_guess::from_path(&p).first().context("no mime type from filename")?;
+        anyhow::ensure!(mime.type_() == mime_guess::mime::IMAGE, "{} is not an image ({mime})", p.as_ref().display());
         let bs = tokio::fs::read(&p).await.context("read file")?;
-        let mut data = String::new();
-        BASE64_STANDARD.encode_string(&bs, &mut data);
-        Ok(Self::Image {
-            source: ImageSource { typ: String::from("base64"), media_type: mime.to_string(), data: data.to_string() },
-        })
+        Ok(Self::Image { source: ImageSource::base64(mime.as_ref(), &bs) })
+    }
+
+    /// A document block holding the pdf at `p`.
+    pub async fn pdf_path(p: impl AsRef<Path>) -> Result<Self> {
+        let bs = tokio::fs::read(&p).await.with_context(|| format!("read {}", p.as_ref().display()))?;
+        anyhow::ensure!(bs.starts_with(b"%PDF-"), "{} is not a pdf", p.as_ref().display());
+        Ok(Self::Document { source: ImageSource::base64("application/pdf", &bs) })
+    }
+
+    /// The file at `p` wrapped in `<file path="...">` tags, as whichever block suits it: a pdf becomes a document,
+    /// a png, jpeg, gif or webp an image, and anything else, which must be utf-8, text.
+    pub async fn file(p: impl AsRef<Path>) -> Result<Vec<Self>> {
+        let p = p.as_ref();
+        let open = format!("<file path=\"{}\">", p.display());
+        let mime = mime_guess::from_path(p).first_or_octet_stream();
+        let block = match mime.essence_str() {
+            "application/pdf" => Self::pdf_path(p).await?,
+            "image/png" | "image/jpeg" | "image/gif" | "image/webp" => Self::image_path(p).await?,
+            _ => {
+                let bs = tokio::fs::read(p).await.with_context(|| format!("read {}", p.display()))?;
+                let text =
+                    String::from_utf8(bs).map_err(|_| anyhow::anyhow!("{} is not text, an image or a pdf", p.display()))?;
+                return Ok(vec![Self::text(format!("{open}\n{text}\n</file>"))]);
+            }
+        };
+        Ok(vec![Self::text(open), block, Self::text("</file>")])
     }
 }
 
+/// The data of an image or document block.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ImageSource {
     #[serde(rename = "type")]
@@ -410,22 +1097,61 @@ pub struct ImageSource {
     data: String,
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+impl ImageSource {
+    fn base64(media_type: &str, bs: &[u8]) -> Self {
+        let mut data = String::new();
+        BASE64_STANDARD.encode_string(bs, &mut data);
+        Self { typ: String::from("base64"), media_type: media_type.to_string(), data }
+    }
+
+    /// The size of the decoded data, in bytes.
+    pub fn size(&self) -> usize {
+        self.data.len() / 4 * 3
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
 pub struct Usage {
-    input_tokens: u64,
-    output_tokens: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_creation_input_tokens: u64,
+    pub cache_read_input_tokens: u64,
 }
 
 impl Usage {
-    fn extend(&mut self, other: Self) {
+    pub fn extend(&mut self, other: Self) {
         self.input_tokens += other.input_tokens;
         self.output_tokens += other.output_tokens;
+        self.cache_creation_input_tokens += other.cache_creation_input_tokens;
+        self.cache_read_input_tokens += other.cache_read_input_tokens;
+    }
+
+    /// Applies the usage from a message_delta event, whose counts are cumulative rather than incremental.
+    fn update(&mut self, delta: Self) {
+        let max = |a: &mut u64, b: u64| *a = (*a).max(b);
+        max(&mut self.input_tokens, delta.input_tokens);
+        max(&mut self.output_tokens, delta.output_tokens);
+        max(&mut self.cache_creation_input_tokens, delta.cache_creation_input_tokens);
+        max(&mut self.cache_read_input_tokens, delta.cache_read_input_tokens);
+    }
+
+    /// `None` if the model's pricing is unknown.
+    pub fn cost(&self, model: &models::Model) -> Option<models::Cost> {
+        model.pricing().map(|p| p.cost(self))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Content, MessagesResponse, Response, Usage};
+    use super::{Client, Content, MessagesResponse, Response, ServerStreamEvent, Usage};
+    use crate::anthropic::{Budget, BudgetExceeded, BudgetGuard};
+
+    #[test]
+    fn invalid_key() {
+        assert!(Client::new("sk-ant\n123").is_err());
+        assert!(Client::new(" sk-ant-123\n").is_ok());
+    }
 
     #[test]
     fn serde_content() {
@@ -476,13 +1202,149 @@ mod tests {
     #[test]
     fn response_merge() {
         let mut r1 = MessagesResponse { usage: None, ..Default::default() };
-        let r2 = MessagesResponse { usage: Some(Usage { input_tokens: 42, output_tokens: 420 }), ..Default::default() };
+        let r2 = MessagesResponse {
+            usage: Some(Usage { input_tokens: 42, output_tokens: 420, ..Default::default() }),
+            ..Default::default()
+        };
         r1.extend(r2);
-        assert_eq!(r1.usage, Some(Usage { input_tokens: 42, output_tokens: 420 }));
+        assert_eq!(r1.usage, Some(Usage { input_tokens: 42, output_tokens: 420, ..Default::default() }));
         r1.extend(MessagesResponse {
-            usage: Some(Usage { input_tokens: 42, output_tokens: 420 }),
+            usage: Some(Usage { input_tokens: 42, output_tokens: 420, ..Default::default() }),
             ..Default::default()
         });
-        assert_eq!(r1.usage, Some(Usage { input_tokens: 42 * 2, output_tokens: 420 * 2 }));
+        assert_eq!(r1.usage, Some(Usage { input_tokens: 42 * 2, output_tokens: 420 * 2, ..Default::default() }));
+    }
+
+    #[test]
+    fn response_message_delta() {
+        let json = concat!(
+            r#"{"type":"message_start","message":{"id":"msg_1","type":"message","role":"assistant","content":[],"#,
+            r#""model":"claude-3-5-haiku-20241022","stop_reason":null,"stop_sequence":null,"#,
+            r#""usage":{"input_tokens":25,"output_tokens":1}}}"#,
+        );
+        let ServerStreamEvent::MessageStart { message } = serde_json::from_str(json).unwrap() else { panic!() };
+        let mut resp = MessagesResponse::default();
+        resp.extend(message);
+        let json =
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn","stop_sequence":null},"usage":{"output_tokens":15}}"#;
+        let ServerStreamEvent::MessageDelta { message, usage } = serde_json::from_str(json).unwrap() else { panic!() };
+        resp.extend_delta(MessagesResponse { usage, ..message });
+        assert_eq!(resp.stop_reason.as_deref(), Some("end_turn"));
+        assert_eq!(resp.usage, Some(Usage { input_tokens: 25, output_tokens: 15, ..Default::default() }));
+        assert!(resp.cost().unwrap().total() > 0.0);
+    }
+
+    #[tokio::test]
+    async fn budget_refuses_requests() {
+        let guard = BudgetGuard::new(Budget::tokens(100));
+        let client = Client::new(String::from("key")).unwrap().with_budget(guard);
+        // a reply of max_tokens alone would exceed the budget, so this never reaches the network
+        let err = client.speak("hello").await.unwrap_err();
+        let err = err.downcast_ref::<BudgetExceeded>().unwrap();
+        assert_eq!(err.estimate.unwrap().tokens, 1024 + 2);
+    }
+
+    #[test]
+    fn response_apply_delta() {
+        let mut r = MessagesResponse { content: vec![Content::text("Hello")], ..Default::default() };
+        r.apply_delta(0, Content::TextDelta { text: String::from(", world") }).unwrap();
+        assert_eq!(r.text(), "Hello, world");
+        assert!(r.apply_delta(1, Content::TextDelta { text: String::from("!") }).is_err());
+    }
+
+    #[tokio::test]
+    async fn stream_tool_input() {
+        use crate::anthropic::{
+            Conversation, TextStreamEvent,
+            mock::{MockResponse, MockServer},
+        };
+
+        let server = MockServer::start().await.unwrap();
+        let input = serde_json::json!({"path": "notes.txt", "content": "a file long enough to take several deltas"});
+        server.push(MockResponse::stream_tool_use("write_file", input.clone()));
+        let client = Client::new(String::from("key")).unwrap().with_endpoint(server.url());
+        let mut conv = Conversation::new(client.model(), None);
+        conv.push_user("write some notes");
+        let mut events = vec![];
+        let resp = client.stream_reply_with(&mut conv, &[], |ev| events.push(ev.clone())).await.unwrap();
+
+        assert!(matches!(&events[0], TextStreamEvent::Fragment(text) if text == "On it."));
+        let partial = events
+            .iter()
+            .filter_map(|ev| match ev {
+                TextStreamEvent::PartialToolInput { index: 1, input, .. } => Some(input.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert!(partial.len() > 3);
+        // the content grows as it streams
+        let content = partial.iter().filter_map(|i| i["content"].as_str()).collect::<Vec<_>>();
+        assert!(content.windows(2).all(|w| w[1].starts_with(w[0])), "{content:?}");
+        assert!(content.len() > 1 && content[0].len() < content[content.len() - 1].len());
+        let Some(TextStreamEvent::ToolInput { index: 1, name, input: complete, .. }) = events.iter().rev().nth(1) else {
+            panic!("{events:?}")
+        };
+        assert_eq!((name.as_str(), complete), ("write_file", &input));
+        assert_eq!(
+            resp.content[1],
+            Content::ToolUse { id: String::from("toolu_mock_0"), name: String::from("write_file"), input }
+        );
+        assert_eq!(resp.stop_reason.as_deref(), Some("tool_use"));
+        assert_eq!(conv.messages.len(), 2);
+        let json = serde_json::to_value(&events[0]).unwrap();
+        assert_eq!(json, serde_json::json!({"type": "fragment", "data": "On it."}));
+        let json = serde_json::to_value(events.last().unwrap()).unwrap();
+        assert_eq!((&json["type"], &json["data"]["stop_reason"]), (&"eof".into(), &"tool_use".into()));
+    }
+
+    #[tokio::test]
+    async fn summarize_with_conversation() {
+        use crate::anthropic::{
+            Compaction, ContextPolicy, Conversation, SUMMARY,
+            mock::{MockResponse, MockServer},
+        };
+
+        let server = MockServer::start().await.unwrap();
+        server.push(MockResponse::message("we talked"));
+        let client = Client::new(String::from("key")).unwrap().with_endpoint(server.url());
+        let mut conv = Conversation::new("claude-3-5-sonnet-latest", Some(String::from("Be brief.")));
+        // about 300 tokens, so that the last turn alone fits
+        let threshold = 300.0 / (200_000 - client.max_tokens()) as f64;
+        conv.context = ContextPolicy { compaction: Some(Compaction::Summarize { keep_last: 1 }), threshold, count_tokens: false };
+        for i in 0..3 {
+            conv.push_user(&format!("question {i} {}", "x".repeat(400)));
+            conv.push_response(&MessagesResponse { content: vec![Content::text("answer")], ..Default::default() });
+        }
+        assert!(client.fit_context(&mut conv).await.unwrap());
+
+        let req = &server.requests()[0].body;
+        assert_eq!((&req["model"], &req["system"]), (&"claude-3-5-sonnet-latest".into(), &"Be brief.".into()));
+        assert_eq!(req["messages"].as_array().unwrap().len(), 5);
+        let summary = conv.turns.last().unwrap();
+        assert_eq!(summary.stop_reason.as_deref(), Some(SUMMARY));
+        assert_eq!(conv.usage().input_tokens, 10);
+        assert_eq!(conv.messages.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn file_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        std::fs::write(path("a.rs"), "fn main() {}").unwrap();
+        std::fs::write(path("b.pdf"), b"%PDF-1.7 ...").unwrap();
+        std::fs::write(path("c.png"), [0x89, b'P', b'N', b'G']).unwrap();
+        std::fs::write(path("d.bin"), [0xff, 0xfe]).unwrap();
+
+        let text = Content::file(path("a.rs")).await.unwrap();
+        assert_eq!(text, [Content::text(format!("<file path=\"{}\">\nfn main() {{}}\n</file>", path("a.rs").display()))]);
+        let pdf = Content::file(path("b.pdf")).await.unwrap();
+        assert!(matches!(&pdf[1], Content::Document { source } if source.media_type == "application/pdf"));
+        assert_eq!(pdf[2], Content::text("</file>"));
+        let png = Content::file(path("c.png")).await.unwrap();
+        assert!(matches!(&png[1], Content::Image { source } if source.media_type == "image/png"));
+        let err = Content::file(path("d.bin")).await.unwrap_err();
+        assert!(err.to_string().ends_with("is not text, an image or a pdf"));
+        std::fs::write(path("e.pdf"), "not really").unwrap();
+        assert!(Content::file(path("e.pdf")).await.is_err());
     }
 }
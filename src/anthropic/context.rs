@@ -0,0 +1,169 @@
+//! keeping conversations within the model's context window
+
+use serde::{Deserialize, Serialize};
+
+use super::{Content, Conversation, Message};
+
+/// The tokens a single image is assumed to cost when estimating locally. A ~1 megapixel image is about 1,600 tokens.
+const IMAGE_TOKENS: u64 = 1600;
+
+/// Each page of a pdf is sent both as text and as an image, so is assumed to cost this many tokens, with a page
+/// assumed for every 50KB of pdf.
+const PDF_PAGE_TOKENS: u64 = 2500;
+const PDF_PAGE_BYTES: u64 = 50_000;
+
+/// How a conversation sheds history once it gets too close to the context window.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum Compaction {
+    /// Forget the oldest turns, one at a time, until the conversation fits.
+    DropOldest,
+    /// Keep the first `first` and last `last` turns, dropping everything in between.
+    KeepEnds { first: usize, last: usize },
+    /// Replace all but the last `keep_last` turns with a summary written by the model.
+    Summarize { keep_last: usize },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ContextPolicy {
+    /// `None` disables compaction, letting the api reject requests which are too large.
+    pub compaction: Option<Compaction>,
+    /// The fraction of the context window, after reserving room for the reply, at which compaction kicks in.
+    pub threshold: f64,
+    /// Ask the count_tokens api for an exact count instead of estimating locally.
+    pub count_tokens: bool,
+}
+
+impl Default for ContextPolicy {
+    fn default() -> Self {
+        Self { compaction: Some(Compaction::DropOldest), threshold: 0.9, count_tokens: false }
+    }
+}
+
+impl ContextPolicy {
+    /// The most input tokens a request may use before compaction is needed.
+    pub fn limit(&self, context_window: u64, max_tokens: u32) -> u64 {
+        let available = context_window.saturating_sub(max_tokens as u64);
+        (available as f64 * self.threshold) as u64
+    }
+}
+
+impl Conversation {
+    /// A rough, offline estimate of the input tokens this conversation will use: about four characters per token
+    /// of text, plus a fixed cost per image.
+    pub fn estimated_tokens(&self) -> u64 {
+        estimate_tokens(self.system.as_deref(), &self.messages)
+    }
+
+    /// The index into `messages` at which each turn starts. A turn is a user message and everything up to the
+    /// next one, not counting user messages which only carry tool results: those continue the turn that asked for
+    /// them, and can't be separated from it.
+    pub fn turn_starts(&self) -> Vec<usize> {
+        let tool_results =
+            |m: &Message| !m.content.is_empty() && m.content.iter().all(|c| matches!(c, Content::ToolResult { .. }));
+        self.messages.iter().enumerate().filter(|(_, m)| m.role == "user" && !tool_results(m)).map(|(i, _)| i).collect()
+    }
+
+    /// Removes the turns in `turns` (indices into [`Conversation::turn_starts`]) from the history.
+    pub fn drop_turns(&mut self, turns: std::ops::Range<usize>) {
+        let starts = self.turn_starts();
+        if turns.is_empty() || turns.start >= starts.len() {
+            return;
+        }
+        let from = starts[turns.start];
+        let to = starts.get(turns.end).copied().unwrap_or(self.messages.len());
+        self.messages.drain(from..to);
+    }
+
+    /// Replaces the first `turns` turns with a single exchange carrying `summary`.
+    pub fn replace_with_summary(&mut self, turns: usize, summary: &str) {
+        let starts = self.turn_starts();
+        let to = starts.get(turns).copied().unwrap_or(self.messages.len());
+        let summary = [
+            Message {
+                role: String::from("user"),
+                content: vec![Content::text(format!(
+                    "Here is a summary of our conversation so far:\n<summary>\n{summary}\n</summary>"
+                ))],
+            },
+            Message { role: String::from("assistant"), content: vec![Content::text("Understood.")] },
+        ];
+        self.messages.splice(0..to, summary);
+    }
+}
+
+/// See [`Conversation::estimated_tokens`].
+pub(crate) fn estimate_tokens(system: Option<&str>, messages: &[Message]) -> u64 {
+    system.map(estimate_text).unwrap_or_default() + messages.iter().map(estimate_message).sum::<u64>()
+}
+
+fn estimate_message(msg: &Message) -> u64 {
+    msg.content
+        .iter()
+        .map(|c| match c {
+            Content::Image { .. } => IMAGE_TOKENS,
+            Content::Document { source } => PDF_PAGE_TOKENS * (source.size() as u64).div_ceil(PDF_PAGE_BYTES).max(1),
+            c => estimate_text(&c.to_string()),
+        })
+        .sum()
+}
+
+fn estimate_text(s: &str) -> u64 {
+    (s.chars().count() as u64).div_ceil(4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anthropic::MessagesResponse;
+
+    fn conversation(turns: usize) -> Conversation {
+        let mut conv = Conversation::new("claude", None);
+        for i in 0..turns {
+            conv.push_user(&format!("question {i}"));
+            conv.push_response(&MessagesResponse { content: vec![Content::text(format!("answer {i}"))], ..Default::default() });
+        }
+        conv
+    }
+
+    fn texts(conv: &Conversation) -> Vec<String> {
+        conv.messages.iter().map(|m| m.content[0].to_string()).collect()
+    }
+
+    #[test]
+    fn estimate() {
+        let mut conv = Conversation::new("claude", Some(String::from("12345678")));
+        conv.push_user("1234");
+        assert_eq!(conv.estimated_tokens(), 3);
+    }
+
+    #[test]
+    fn drop_turns() {
+        let mut conv = conversation(4);
+        assert_eq!(conv.turn_starts(), vec![0, 2, 4, 6]);
+        conv.drop_turns(1..3);
+        assert_eq!(texts(&conv), vec!["question 0", "answer 0", "question 3", "answer 3"]);
+        conv.drop_turns(0..1);
+        assert_eq!(texts(&conv), vec!["question 3", "answer 3"]);
+        conv.drop_turns(5..6);
+        assert_eq!(conv.messages.len(), 2);
+    }
+
+    #[test]
+    fn summary() {
+        let mut conv = conversation(3);
+        conv.push_user("pending");
+        conv.replace_with_summary(2, "we talked");
+        let texts = texts(&conv);
+        assert!(texts[0].contains("<summary>\nwe talked\n</summary>"), "{}", texts[0]);
+        assert_eq!(texts[1..], ["Understood.", "question 2", "answer 2", "pending"]);
+    }
+
+    #[test]
+    fn limit() {
+        let policy = ContextPolicy { threshold: 0.5, ..Default::default() };
+        assert_eq!(policy.limit(200_000, 1024), 99_488);
+        assert_eq!(policy.limit(100, 1024), 0);
+    }
+}
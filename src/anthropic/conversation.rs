@@ -0,0 +1,191 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Budget, ContextPolicy, Spent,
+    client::{Content, Message, MessagesResponse, Usage},
+    models::{Cost, Model},
+};
+
+/// The stop reason of a turn cut short by the user, see [`Conversation::push_interrupted`].
+pub const INTERRUPTED: &str = "interrupted";
+/// The stop reason of the turn recording a summary written for compaction, see [`Conversation::push_summary_usage`].
+pub const SUMMARY: &str = "summary";
+
+/// A multi-turn exchange with the model, along with everything needed to pick it back up later.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Conversation {
+    pub id: String,
+    pub model: String,
+    pub system: Option<String>,
+    pub messages: Vec<Message>,
+    /// one entry per completed assistant turn, and per summary written to compact the conversation
+    #[serde(default)]
+    pub turns: Vec<Turn>,
+    #[serde(default)]
+    pub context: ContextPolicy,
+    /// caps spend across all of the conversation's turns
+    #[serde(default)]
+    pub budget: Option<Budget>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Bookkeeping for a single assistant reply.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Turn {
+    pub model: String,
+    pub stop_reason: Option<String>,
+    pub usage: Usage,
+}
+
+impl Conversation {
+    pub fn new(model: impl ToString, system: Option<String>) -> Self {
+        let now = Utc::now();
+        let id = now.format("%Y%m%d-%H%M%S-%3f").to_string();
+        Self {
+            id,
+            model: model.to_string(),
+            system,
+            messages: vec![],
+            turns: vec![],
+            context: ContextPolicy::default(),
+            budget: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    pub fn push_user(&mut self, msg: &str) {
+        self.messages.push(Message { role: String::from("user"), content: vec![Content::text(msg)] });
+        self.updated_at = Utc::now();
+    }
+
+    /// Answers the tool calls of the last assistant turn.
+    pub fn push_tool_results(&mut self, results: Vec<Content>) {
+        self.messages.push(Message { role: String::from("user"), content: results });
+        self.updated_at = Utc::now();
+    }
+
+    /// Removes the trailing user message, if there is one.
+    pub fn pop_user(&mut self) -> Option<Message> {
+        if self.messages.last().is_some_and(|m| m.role == "user") { self.messages.pop() } else { None }
+    }
+
+    pub fn push_response(&mut self, resp: &MessagesResponse) {
+        self.messages.push(Message { role: String::from("assistant"), content: resp.content.clone() });
+        self.turns.push(Turn {
+            model: resp.model.clone(),
+            stop_reason: resp.stop_reason.clone(),
+            usage: resp.usage.clone().unwrap_or_default(),
+        });
+        self.updated_at = Utc::now();
+    }
+
+    /// Keeps whatever text a reply got to before it was cut short, as a turn with an `interrupted` stop reason. Its
+    /// usage is unknown, so counts as nothing.
+    pub fn push_interrupted(&mut self, partial: &str) {
+        self.messages.push(Message { role: String::from("assistant"), content: vec![Content::text(partial)] });
+        self.turns.push(Turn {
+            model: self.model.clone(),
+            stop_reason: Some(String::from(INTERRUPTED)),
+            usage: Usage::default(),
+        });
+        self.updated_at = Utc::now();
+    }
+
+    /// Records the usage of `resp`, a summary of earlier turns, as a turn with a `summary` stop reason. The summary
+    /// itself goes into the history with [`Conversation::replace_with_summary`].
+    pub fn push_summary_usage(&mut self, resp: &MessagesResponse) {
+        self.turns.push(Turn {
+            model: resp.model.clone(),
+            stop_reason: Some(String::from(SUMMARY)),
+            usage: resp.usage.clone().unwrap_or_default(),
+        });
+        self.updated_at = Utc::now();
+    }
+
+    /// Usage summed across every turn.
+    pub fn usage(&self) -> Usage {
+        self.turns.iter().fold(Usage::default(), |mut acc, turn| {
+            acc.extend(turn.usage.clone());
+            acc
+        })
+    }
+
+    /// The running cost of the conversation. Turns taken with a model of unknown pricing count as free.
+    pub fn cost(&self) -> Cost {
+        self.turns.iter().filter_map(|turn| turn.usage.cost(&Model::from(turn.model.as_str()))).sum()
+    }
+
+    /// Spend across every turn, as counted against [`Conversation::budget`].
+    pub fn spent(&self) -> Spent {
+        self.turns
+            .iter()
+            .map(|turn| Spent::of(&turn.usage, &Model::from(turn.model.as_str())))
+            .fold(Spent::default(), |acc, s| acc + s)
+    }
+
+    /// The first line of the first user message, used to identify the conversation in listings.
+    pub fn title(&self) -> String {
+        self.messages
+            .iter()
+            .find(|m| m.role == "user")
+            .and_then(|m| m.content.first())
+            .map(|c| c.to_string().lines().next().unwrap_or_default().to_string())
+            .unwrap_or_default()
+    }
+
+    /// The conversation as a markdown transcript, with a section for the system prompt and one per message.
+    pub fn markdown(&self) -> String {
+        let system = self.system.iter().map(|system| format!("## System\n\n{system}\n"));
+        system.chain(self.messages.iter().map(Message::markdown)).collect::<Vec<_>>().join("\n")
+    }
+}
+
+impl Message {
+    /// The message as a section of a markdown transcript, headed by its role. Blocks other than text are shown as
+    /// placeholders.
+    pub fn markdown(&self) -> String {
+        let role = match self.role.as_str() {
+            "user" => "User",
+            "assistant" => "Assistant",
+            role => role,
+        };
+        let content = self.content.iter().map(|c| c.to_string()).collect::<Vec<_>>().join("\n\n");
+        format!("## {role}\n\n{content}\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turns() {
+        let mut conv = Conversation::new("claude", None);
+        conv.push_user("hello\nthere");
+        assert_eq!(conv.title(), "hello");
+        conv.push_response(&MessagesResponse {
+            content: vec![Content::text("hi")],
+            model: String::from("claude-3-5-haiku-20241022"),
+            usage: Some(Usage { input_tokens: 10, output_tokens: 2, ..Default::default() }),
+            ..Default::default()
+        });
+        conv.push_user("again");
+        conv.push_response(&MessagesResponse {
+            content: vec![Content::text("hi")],
+            usage: Some(Usage { input_tokens: 14, output_tokens: 3, ..Default::default() }),
+            ..Default::default()
+        });
+        assert_eq!(conv.messages.len(), 4);
+        assert_eq!(conv.usage(), Usage { input_tokens: 24, output_tokens: 5, ..Default::default() });
+        // the second turn has no model and so no known price
+        assert_eq!(conv.cost().total(), (10.0 * 0.8 + 2.0 * 4.0) / 1_000_000.0);
+        assert!(conv.pop_user().is_none());
+        conv.push_user("one more");
+        assert!(conv.pop_user().is_some());
+        assert_eq!(conv.messages.len(), 4);
+        assert!(conv.markdown().starts_with("## User\n\nhello\nthere\n\n## Assistant\n\nhi\n\n## User\n\nagain\n"));
+    }
+}
@@ -0,0 +1,147 @@
+//! structured output: having the model fill in a rust type
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+
+use super::{
+    Client, Content, Message,
+    client::{MessagesRequest, ToolChoice, ToolDefinition},
+};
+
+impl Client {
+    /// Asks the model to answer `prompt` with an instance of `T`.
+    ///
+    /// `T`'s json schema becomes the input schema of a tool the model is forced to call, and the tool's input is
+    /// deserialized into `T`. If that fails, the error is sent back as the tool's result and the model is asked to
+    /// try again, up to [`Client::with_extract_attempts`] times in all.
+    pub async fn extract<T: DeserializeOwned + JsonSchema>(&self, prompt: &str) -> Result<T> {
+        let (tool, wrapped) = extraction_tool::<T>();
+        let mut messages = vec![Message { role: String::from("user"), content: vec![Content::text(prompt)] }];
+        let mut last_err = None;
+        let attempts = self.extract_attempts.max(1);
+        for attempt in 1..=attempts {
+            let req = MessagesRequest {
+                model: self.model().to_string(),
+                max_tokens: self.max_tokens(),
+                messages: messages.clone(),
+                tools: vec![tool.clone()],
+                tool_choice: Some(ToolChoice::Tool { name: tool.name.clone() }),
+                ..Default::default()
+            };
+            let resp = self.post_messages_req(req).await.context("extract")?.into_messages()?;
+            let tool_use = resp.content.iter().find_map(|c| match c {
+                Content::ToolUse { id, name, input } if *name == tool.name => Some((id.clone(), input.clone())),
+                _ => None,
+            });
+            let feedback = match tool_use {
+                Some((id, input)) => {
+                    let input = if wrapped { input.get("value").cloned().unwrap_or(Value::Null) } else { input };
+                    match serde_json::from_value::<T>(input) {
+                        Ok(value) => return Ok(value),
+                        Err(err) => {
+                            tracing::debug!("extract attempt {attempt} failed: {err}");
+                            let content = format!("Invalid input: {err}. Call {} again with corrected input.", tool.name);
+                            last_err = Some(anyhow::Error::new(err));
+                            Content::ToolResult { tool_use_id: id, content, is_error: true }
+                        }
+                    }
+                }
+                None => {
+                    last_err = Some(anyhow::anyhow!("model did not call {}", tool.name));
+                    Content::text(format!("Respond by calling the {} tool.", tool.name))
+                }
+            };
+            messages.push(Message { role: String::from("assistant"), content: resp.content });
+            messages.push(Message { role: String::from("user"), content: vec![feedback] });
+        }
+        Err(last_err.context("no attempts made")?.context(format!("extract failed after {attempts} attempts")))
+    }
+}
+
+/// The tool whose input is a `T`; see [`input_schema`] for what the bool means.
+fn extraction_tool<T: JsonSchema>() -> (ToolDefinition, bool) {
+    let (schema, wrapped) = input_schema::<T>();
+    let title = match wrapped {
+        true => schema["properties"]["value"].get("title"),
+        false => schema.get("title"),
+    };
+    let title = title.and_then(Value::as_str).unwrap_or("value").to_string();
+    let name = title.chars().filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-').take(56).collect::<String>();
+    let tool = ToolDefinition {
+        name: format!("extract_{name}"),
+        description: format!("Record the requested information as a {title}."),
+        input_schema: schema,
+    };
+    (tool, wrapped)
+}
+
+/// `T`'s json schema, usable as a tool's input schema. Tool inputs must be objects, so any other schema is wrapped
+/// in an object with a single `value` property; the returned bool says whether that happened.
+pub fn input_schema<T: JsonSchema>() -> (Value, bool) {
+    let mut schema = schemars::schema_for!(T).to_value();
+    if let Some(obj) = schema.as_object_mut() {
+        obj.remove("$schema");
+    }
+    let wrapped = schema.get("type").and_then(Value::as_str) != Some("object");
+    if wrapped {
+        // definitions have to stay at the root for the schema's `$ref`s to resolve
+        let defs = schema.as_object_mut().and_then(|obj| obj.remove("$defs"));
+        schema = json!({"type": "object", "properties": {"value": schema}, "required": ["value"]});
+        if let Some(defs) = defs {
+            schema["$defs"] = defs;
+        }
+    }
+    (schema, wrapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::anthropic::mock::{MockResponse, MockServer};
+
+    #[derive(Debug, Deserialize, JsonSchema, PartialEq)]
+    struct Person {
+        name: String,
+        age: u32,
+    }
+
+    #[test]
+    fn tools() {
+        let (tool, wrapped) = extraction_tool::<Person>();
+        assert!(!wrapped);
+        assert_eq!(tool.name, "extract_Person");
+        assert_eq!(tool.input_schema["properties"]["age"]["type"], "integer");
+        assert!(tool.input_schema.get("$schema").is_none());
+
+        let (tool, wrapped) = extraction_tool::<Vec<Person>>();
+        assert!(wrapped);
+        assert_eq!(tool.input_schema["properties"]["value"]["type"], "array");
+        assert!(tool.input_schema["$defs"]["Person"].is_object());
+    }
+
+    #[tokio::test]
+    async fn retries_invalid_input() {
+        let server = MockServer::start().await.unwrap();
+        server.push(MockResponse::tool_use("extract_Person", json!({"name": "Ada", "age": "thirty-six"})));
+        server.push(MockResponse::tool_use("extract_Person", json!({"name": "Ada", "age": 36})));
+        let client = Client::new(String::from("key")).unwrap().with_endpoint(server.url());
+        let person = client.extract::<Person>("Ada Lovelace died at 36").await.unwrap();
+        assert_eq!(person, Person { name: String::from("Ada"), age: 36 });
+
+        let reqs = server.requests();
+        assert_eq!(reqs[0].body["tool_choice"], json!({"type": "tool", "name": "extract_Person"}));
+        let retry = &reqs[1].body["messages"];
+        assert_eq!(retry[1]["content"][0]["type"], "tool_use");
+        assert_eq!(retry[2]["content"][0]["is_error"], true);
+        assert!(retry[2]["content"][0]["content"].as_str().unwrap().contains("invalid type"));
+
+        server.push(MockResponse::tool_use("extract_Person", json!({})));
+        let client = client.with_extract_attempts(0);
+        let err = client.extract::<Person>("nobody").await.unwrap_err();
+        assert!(format!("{err:#}").contains("after 1 attempts"), "{err:#}");
+    }
+}
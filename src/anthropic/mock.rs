@@ -0,0 +1,435 @@
+//! A local stand-in for the anthropic api, so that the client can be exercised without a key or a network.
+//!
+//! The server replies to each request with the next scripted [`MockResponse`] and records every request it
+//! receives. It speaks just enough HTTP/1.1 for reqwest: one request per connection, with the connection closed after
+//! each response.
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+/// A canned reply.
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: MockBody,
+    /// how long to wait before sending the response
+    pub delay: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub enum MockBody {
+    Json(Value),
+    /// server sent events, sent `delay` apart
+    Sse {
+        events: Vec<Value>,
+        delay: Duration,
+    },
+    /// anything else, e.g. jsonl
+    Text(String),
+}
+
+/// A request received by the server.
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Value,
+}
+
+impl MockRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+impl MockResponse {
+    pub fn json(status: u16, body: Value) -> Self {
+        Self { status, headers: vec![], body: MockBody::Json(body), delay: Duration::ZERO }
+    }
+
+    /// A 200 whose body isn't json, e.g. jsonl.
+    pub fn text(body: &str) -> Self {
+        Self { status: 200, headers: vec![], body: MockBody::Text(body.to_string()), delay: Duration::ZERO }
+    }
+
+    /// A complete, non-streaming message replying with `text`.
+    pub fn message(text: &str) -> Self {
+        Self::json(200, message_json(vec![json!({"type": "text", "text": text})], Some("end_turn"), text))
+    }
+
+    /// A message calling the tool `name` with `input`.
+    pub fn tool_use(name: &str, input: Value) -> Self {
+        Self::tool_uses(&[(name, input)])
+    }
+
+    /// A message making several tool calls at once, with ids `toolu_mock_0`, `toolu_mock_1`, ...
+    pub fn tool_uses(calls: &[(&str, Value)]) -> Self {
+        let content = calls
+            .iter()
+            .enumerate()
+            .map(|(i, (name, input))| json!({"type": "tool_use", "id": format!("toolu_mock_{i}"), "name": name, "input": input}))
+            .collect::<Vec<_>>();
+        let text = calls.iter().map(|(_, input)| input.to_string()).collect::<String>();
+        Self::json(200, message_json(content, Some("tool_use"), &text))
+    }
+
+    /// A streamed message replying with `text`, sent a few characters per delta.
+    pub fn stream(text: &str) -> Self {
+        let mut start = message_json(vec![], None, "");
+        start["usage"]["output_tokens"] = json!(1);
+        let mut events = vec![
+            json!({"type": "message_start", "message": start}),
+            json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}}),
+            json!({"type": "ping"}),
+        ];
+        let chars = text.chars().collect::<Vec<_>>();
+        for chunk in chars.chunks(8) {
+            let text = chunk.iter().collect::<String>();
+            events.push(json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": text}}));
+        }
+        events.extend([
+            json!({"type": "content_block_stop", "index": 0}),
+            json!({
+                "type": "message_delta",
+                "delta": {"stop_reason": "end_turn", "stop_sequence": null},
+                "usage": {"output_tokens": output_tokens(text)},
+            }),
+            json!({"type": "message_stop"}),
+        ]);
+        Self::sse(events)
+    }
+
+    /// A streamed message with a little text and then a call to the tool `name`, its input sent a few characters
+    /// per `input_json_delta`.
+    pub fn stream_tool_use(name: &str, input: Value) -> Self {
+        let mut start = message_json(vec![], None, "");
+        start["usage"]["output_tokens"] = json!(1);
+        let tool_use = json!({"type": "tool_use", "id": "toolu_mock_0", "name": name, "input": {}});
+        let mut events = vec![
+            json!({"type": "message_start", "message": start}),
+            json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": "On it."}}),
+            json!({"type": "content_block_stop", "index": 0}),
+            json!({"type": "content_block_start", "index": 1, "content_block": tool_use}),
+        ];
+        let json = input.to_string();
+        let chars = json.chars().collect::<Vec<_>>();
+        for chunk in chars.chunks(8) {
+            let partial_json = chunk.iter().collect::<String>();
+            events.push(json!({
+                "type": "content_block_delta",
+                "index": 1,
+                "delta": {"type": "input_json_delta", "partial_json": partial_json},
+            }));
+        }
+        events.extend([
+            json!({"type": "content_block_stop", "index": 1}),
+            json!({
+                "type": "message_delta",
+                "delta": {"stop_reason": "tool_use", "stop_sequence": null},
+                "usage": {"output_tokens": output_tokens(&json)},
+            }),
+            json!({"type": "message_stop"}),
+        ]);
+        Self::sse(events)
+    }
+
+    /// A 200 streaming response made of arbitrary events.
+    pub fn sse(events: Vec<Value>) -> Self {
+        Self { status: 200, headers: vec![], body: MockBody::Sse { events, delay: Duration::ZERO }, delay: Duration::ZERO }
+    }
+
+    /// An api error, e.g. `error(400, "invalid_request_error", "...")`.
+    pub fn error(status: u16, typ: &str, message: &str) -> Self {
+        Self::json(status, json!({"type": "error", "error": {"type": typ, "message": message}}))
+    }
+
+    /// A 429 carrying the rate limit headers the api sends.
+    pub fn rate_limited(retry_after: Duration) -> Self {
+        Self::error(429, "rate_limit_error", "Number of request tokens has exceeded your per-minute rate limit")
+            .with_header("retry-after", &retry_after.as_secs_f64().to_string())
+            .with_header("anthropic-ratelimit-requests-limit", "50")
+            .with_header("anthropic-ratelimit-requests-remaining", "0")
+    }
+
+    pub fn overloaded() -> Self {
+        Self::error(529, "overloaded_error", "Overloaded")
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Spaces out the events of a streaming response.
+    pub fn with_event_delay(mut self, delay: Duration) -> Self {
+        if let MockBody::Sse { delay: d, .. } = &mut self.body {
+            *d = delay;
+        }
+        self
+    }
+}
+
+fn message_json(content: Vec<Value>, stop_reason: Option<&str>, text: &str) -> Value {
+    json!({
+        "id": "msg_mock",
+        "type": "message",
+        "role": "assistant",
+        "model": "claude-3-5-haiku-20241022",
+        "content": content,
+        "stop_reason": stop_reason,
+        "stop_sequence": null,
+        "usage": {"input_tokens": 10, "output_tokens": output_tokens(text)},
+    })
+}
+
+fn output_tokens(text: &str) -> u64 {
+    (text.len() as u64).div_ceil(4)
+}
+
+type Responder = Box<dyn FnMut(&MockRequest) -> Option<MockResponse> + Send>;
+
+#[derive(Default)]
+struct State {
+    script: VecDeque<MockResponse>,
+    responder: Option<Responder>,
+    requests: Vec<MockRequest>,
+}
+
+/// Serves scripted responses on a random local port until dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    pub async fn start() -> Result<Self> {
+        Self::start_with_state(State::default()).await
+    }
+
+    /// Starts a server which, once any scripted responses have been used up, asks `responder` how to reply.
+    pub async fn start_with<F>(responder: F) -> Result<Self>
+    where
+        F: FnMut(&MockRequest) -> Option<MockResponse> + Send + 'static,
+    {
+        Self::start_with_state(State { responder: Some(Box::new(responder)), ..Default::default() }).await
+    }
+
+    async fn start_with_state(state: State) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.context("bind mock server")?;
+        let addr = listener.local_addr().context("local addr")?;
+        let state = Arc::new(Mutex::new(state));
+        let task = tokio::spawn({
+            let state = state.clone();
+            async move {
+                while let Ok((conn, _)) = listener.accept().await {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = serve(conn, state).await {
+                            tracing::warn!("mock server: {err:#}");
+                        }
+                    });
+                }
+            }
+        });
+        Ok(Self { addr, state, task })
+    }
+
+    /// The base url to hand to [`super::Client::with_endpoint`].
+    pub fn url(&self) -> url::Url {
+        url::Url::parse(&format!("http://{}/", self.addr)).expect("mock url")
+    }
+
+    /// Queues a response. Responses are served in the order they were pushed; once the script runs out, requests
+    /// go to the responder, if there is one, or get a 500.
+    pub fn push(&self, resp: MockResponse) -> &Self {
+        self.state.lock().unwrap().script.push_back(resp);
+        self
+    }
+
+    /// Every request received so far.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(conn: TcpStream, state: Arc<Mutex<State>>) -> Result<()> {
+    let (read, mut write) = conn.into_split();
+    let mut read = BufReader::new(read);
+    let req = read_request(&mut read).await?;
+    let resp = {
+        let mut state = state.lock().unwrap();
+        let resp = match state.script.pop_front() {
+            Some(resp) => Some(resp),
+            None => state.responder.as_mut().and_then(|f| f(&req)),
+        };
+        state.requests.push(req);
+        resp
+    };
+    let resp = resp.unwrap_or_else(|| MockResponse::error(500, "api_error", "mock server has no response for request"));
+    tokio::time::sleep(resp.delay).await;
+    let reason = reqwest::StatusCode::from_u16(resp.status).ok().and_then(|s| s.canonical_reason()).unwrap_or("Unknown");
+    let mut head = format!("HTTP/1.1 {} {reason}\r\nconnection: close\r\n", resp.status);
+    for (k, v) in &resp.headers {
+        head.push_str(&format!("{k}: {v}\r\n"));
+    }
+    match resp.body {
+        MockBody::Json(body) => {
+            let body = serde_json::to_vec(&body).context("serialize body")?;
+            head.push_str(&format!("content-type: application/json\r\ncontent-length: {}\r\n\r\n", body.len()));
+            write.write_all(head.as_bytes()).await?;
+            write.write_all(&body).await?;
+        }
+        MockBody::Text(body) => {
+            head.push_str(&format!("content-type: text/plain\r\ncontent-length: {}\r\n\r\n", body.len()));
+            write.write_all(head.as_bytes()).await?;
+            write.write_all(body.as_bytes()).await?;
+        }
+        MockBody::Sse { events, delay } => {
+            head.push_str("content-type: text/event-stream\r\ncache-control: no-cache\r\n\r\n");
+            write.write_all(head.as_bytes()).await?;
+            for event in events {
+                let typ = event["type"].as_str().unwrap_or("message").to_string();
+                write.write_all(format!("event: {typ}\ndata: {event}\n\n").as_bytes()).await?;
+                write.flush().await?;
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+    write.shutdown().await?;
+    Ok(())
+}
+
+async fn read_request<R: AsyncBufReadExt + Unpin>(read: &mut R) -> Result<MockRequest> {
+    let mut line = String::new();
+    read.read_line(&mut line).await.context("read request line")?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().context("no method")?.to_string();
+    let path = parts.next().context("no path")?.to_string();
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        read.read_line(&mut line).await.context("read header")?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (k, v) = line.split_once(':').context("malformed header")?;
+        headers.push((k.trim().to_lowercase(), v.trim().to_string()));
+    }
+    let len = headers.iter().find(|(k, _)| k == "content-length").map(|(_, v)| v.parse::<usize>()).transpose()?;
+    let mut body = vec![0; len.unwrap_or_default()];
+    read.read_exact(&mut body).await.context("read body")?;
+    let body = if body.is_empty() { Value::Null } else { serde_json::from_slice(&body).context("parse body")? };
+    Ok(MockRequest { method, path, headers, body })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anthropic::{ApiError, Client, Response, RetryPolicy};
+
+    async fn setup() -> (MockServer, Client) {
+        let server = MockServer::start().await.unwrap();
+        let retry = RetryPolicy { initial_backoff: Duration::from_millis(1), ..Default::default() };
+        let client = Client::new(String::from("test-key")).unwrap().with_endpoint(server.url()).with_retry(retry);
+        (server, client)
+    }
+
+    #[tokio::test]
+    async fn messages() {
+        let (server, client) = setup().await;
+        server.push(MockResponse::message("hello from the mock"));
+        let Response::Messages(resp) = client.speak("hi").await.unwrap() else { panic!("not a message") };
+        assert_eq!(resp.text(), "hello from the mock");
+        let reqs = server.requests();
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(reqs[0].path, "/v1/messages");
+        assert_eq!(reqs[0].header("x-api-key"), Some("test-key"));
+        assert_eq!(reqs[0].body["messages"][0]["content"][0]["text"], "hi");
+    }
+
+    #[tokio::test]
+    async fn streaming() {
+        let (server, client) = setup().await;
+        let text = "a streamed reply which spans several deltas";
+        server.push(MockResponse::stream(text).with_event_delay(Duration::from_millis(1)));
+        let resp = client.with_system("be brief").stream_speak("hi").await.unwrap();
+        assert_eq!(resp.text(), text);
+        assert_eq!(resp.stop_reason.as_deref(), Some("end_turn"));
+        assert_eq!(resp.usage.unwrap().output_tokens, output_tokens(text));
+        assert_eq!(server.requests()[0].body["stream"], true);
+        assert_eq!(server.requests()[0].body["system"], "be brief");
+    }
+
+    #[tokio::test]
+    async fn retries() {
+        let (server, client) = setup().await;
+        server.push(MockResponse::rate_limited(Duration::ZERO)).push(MockResponse::overloaded());
+        server.push(MockResponse::message("finally"));
+        let resp = client.speak("hi").await.unwrap().into_messages().unwrap();
+        assert_eq!(resp.text(), "finally");
+        assert_eq!(server.requests().len(), 3);
+
+        // out of retries, the last error is returned
+        let client = client.with_retry(RetryPolicy::none());
+        server.push(MockResponse::rate_limited(Duration::ZERO));
+        let Response::Error { error } = client.speak("hi").await.unwrap() else { panic!("not an error") };
+        assert_eq!(error.typ, "rate_limit_error");
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        let (server, client) = setup().await;
+        server.push(MockResponse::error(401, "authentication_error", "invalid x-api-key"));
+        let err = client.stream_speak("hi").await.unwrap_err();
+        let err = err.downcast_ref::<ApiError>().unwrap();
+        assert!(err.is_auth());
+        assert_eq!(err.error.message, "invalid x-api-key");
+
+        // errors can also arrive part way through a stream
+        let mut events = match MockResponse::stream("partial").body {
+            MockBody::Sse { events, .. } => events,
+            _ => unreachable!(),
+        };
+        events.truncate(3);
+        events.push(json!({"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}}));
+        server.push(MockResponse::sse(events));
+        let err = client.stream_speak("hi").await.unwrap_err();
+        assert!(format!("{err:#}").contains("overloaded_error"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn delay() {
+        let (server, client) = setup().await;
+        server.push(MockResponse::message("slow").with_delay(Duration::from_millis(50)));
+        let start = std::time::Instant::now();
+        client.speak("hi").await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -1,5 +1,27 @@
+mod batch;
+mod budget;
+pub mod cassette;
 mod client;
-mod models;
+mod context;
+mod conversation;
+mod extract;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod models;
+mod partial_json;
+mod retry;
+mod session;
 mod stream;
 
-pub use client::Client;
+pub use batch::{Batch, BatchOutcome, BatchResult, RequestCounts};
+pub use budget::{Budget, BudgetExceeded, BudgetGuard, Spent};
+pub use client::{
+    ApiError, Client, Content, Message, MessagesRequest, MessagesResponse, Response, ServerError, TextStreamEvent, ToolChoice,
+    ToolDefinition, Usage,
+};
+pub use context::{Compaction, ContextPolicy};
+pub use conversation::{Conversation, INTERRUPTED, SUMMARY, Turn};
+pub use extract::input_schema;
+pub use partial_json::parse_partial_json;
+pub use retry::RetryPolicy;
+pub use session::{SessionStore, SessionSummary};
@@ -1,11 +1,106 @@
-use std::sync::LazyLock;
+use std::{collections::HashMap, sync::LazyLock};
 
-#[derive(Debug, Clone)]
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+
+use super::{Client, Usage};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Model(String);
 
 pub static SONNET: LazyLock<Model> = LazyLock::new(|| Model::from("claude-3-5-sonnet-latest"));
 pub static HAIKU: LazyLock<Model> = LazyLock::new(|| Model::from("claude-3-5-haiku-latest"));
 
+/// Per-model prices, keyed by both the aliases and the dated model ids the api reports back.
+pub static PRICING: LazyLock<HashMap<Model, Pricing>> = LazyLock::new(|| {
+    let sonnet = Pricing { input: 3.0, output: 15.0, cache_write: 3.75, cache_read: 0.30 };
+    let haiku = Pricing { input: 0.80, output: 4.0, cache_write: 1.0, cache_read: 0.08 };
+    let opus = Pricing { input: 15.0, output: 75.0, cache_write: 18.75, cache_read: 1.50 };
+    let haiku_3 = Pricing { input: 0.25, output: 1.25, cache_write: 0.30, cache_read: 0.03 };
+    [
+        ("claude-3-5-sonnet-latest", sonnet),
+        ("claude-3-5-sonnet-20241022", sonnet),
+        ("claude-3-5-sonnet-20240620", sonnet),
+        ("claude-3-sonnet-20240229", sonnet),
+        ("claude-3-5-haiku-latest", haiku),
+        ("claude-3-5-haiku-20241022", haiku),
+        ("claude-3-opus-latest", opus),
+        ("claude-3-opus-20240229", opus),
+        ("claude-3-haiku-20240307", haiku_3),
+    ]
+    .into_iter()
+    .map(|(name, pricing)| (Model::from(name), pricing))
+    .collect()
+});
+
+/// Dollars per million tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pricing {
+    pub input: f64,
+    pub output: f64,
+    pub cache_write: f64,
+    pub cache_read: f64,
+}
+
+impl Pricing {
+    pub fn cost(&self, usage: &Usage) -> Cost {
+        let per_token = |rate: f64, tokens: u64| rate * tokens as f64 / 1_000_000.0;
+        Cost {
+            input: per_token(self.input, usage.input_tokens),
+            output: per_token(self.output, usage.output_tokens),
+            cache_write: per_token(self.cache_write, usage.cache_creation_input_tokens),
+            cache_read: per_token(self.cache_read, usage.cache_read_input_tokens),
+        }
+    }
+}
+
+/// The price of some usage, in dollars.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Cost {
+    pub input: f64,
+    pub output: f64,
+    pub cache_write: f64,
+    pub cache_read: f64,
+}
+
+impl Cost {
+    pub fn total(&self) -> f64 {
+        self.input + self.output + self.cache_write + self.cache_read
+    }
+}
+
+impl std::ops::Add for Cost {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            input: self.input + rhs.input,
+            output: self.output + rhs.output,
+            cache_write: self.cache_write + rhs.cache_write,
+            cache_read: self.cache_read + rhs.cache_read,
+        }
+    }
+}
+
+impl std::ops::AddAssign for Cost {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl std::iter::Sum for Cost {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), |acc, c| acc + c)
+    }
+}
+
+impl std::fmt::Display for Cost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "${:.4}", self.total())
+    }
+}
+
 impl std::fmt::Display for Model {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.0.fmt(f)
@@ -17,4 +112,86 @@ impl From<&str> for Model {
     }
 }
 
-impl Model {}
+impl Model {
+    /// The number of tokens the model can attend to, prompt and reply combined.
+    pub fn context_window(&self) -> u64 {
+        // every claude 3 and 3.5 model shares the same window
+        200_000
+    }
+
+    /// `None` for models missing from the [`PRICING`] table.
+    pub fn pricing(&self) -> Option<Pricing> {
+        PRICING.get(self).copied()
+    }
+}
+
+/// A model the api offers, as listed by [`Client::list_models`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub id: String,
+    pub display_name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Client {
+    /// Every model the api offers, most recently released first.
+    pub async fn list_models(&self) -> Result<Vec<ModelInfo>> {
+        #[derive(Deserialize)]
+        struct Page {
+            data: Vec<ModelInfo>,
+            has_more: bool,
+            last_id: Option<String>,
+        }
+        let mut models = vec![];
+        let mut path = String::from("/v1/models?limit=100");
+        loop {
+            let page: Page = self.request_json(Method::GET, &path, None::<&()>).await?;
+            models.extend(page.data);
+            match page.last_id {
+                Some(last) if page.has_more => path = format!("/v1/models?limit=100&after_id={last}"),
+                _ => return Ok(models),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::anthropic::mock::{MockResponse, MockServer};
+
+    #[test]
+    fn usage_cost() {
+        let usage = Usage {
+            input_tokens: 1_000_000,
+            output_tokens: 2_000,
+            cache_creation_input_tokens: 10_000,
+            cache_read_input_tokens: 100_000,
+        };
+        let cost = SONNET.pricing().unwrap().cost(&usage);
+        assert_eq!(cost.input, 3.0);
+        assert_eq!(cost.output, 0.03);
+        assert_eq!(cost.to_string(), "$3.0975");
+        assert!(Model::from("gpt-4").pricing().is_none());
+        assert_eq!([cost, cost].into_iter().sum::<Cost>().input, 6.0);
+    }
+
+    #[tokio::test]
+    async fn list_models() {
+        let server = MockServer::start().await.unwrap();
+        let client = Client::new(String::from("key")).unwrap().with_endpoint(server.url());
+        let model = |id: &str| json!({"type": "model", "id": id, "display_name": id, "created_at": "2024-10-22T00:00:00Z"});
+        server
+            .push(MockResponse::json(200, json!({"data": [model("a")], "has_more": true, "last_id": "a"})))
+            .push(MockResponse::json(200, json!({"data": [model("b")], "has_more": false, "last_id": "b"})));
+        let models = client.list_models().await.unwrap();
+        assert_eq!(models.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(server.requests()[1].path, "/v1/models?limit=100&after_id=a");
+
+        server.push(MockResponse::error(401, "authentication_error", "invalid x-api-key"));
+        let err = client.list_models().await.unwrap_err();
+        assert!(err.downcast_ref::<crate::anthropic::ApiError>().unwrap().is_auth());
+    }
+}
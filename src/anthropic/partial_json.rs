@@ -0,0 +1,88 @@
+//! making sense of json which has only partly arrived, like a tool's input part way through streaming
+
+use serde_json::Value;
+
+/// Parses as much of the truncated json document `s` as can be recovered. Open strings, arrays and objects are
+/// closed, so `{"path": "a.txt", "content": "hel` gives `{"path": "a.txt", "content": "hel"}`. Anything that can't
+/// be completed, like a key without its value or half of a `true`, is left out. Returns `None` when nothing can be
+/// recovered, e.g. for an empty string.
+pub fn parse_partial_json(s: &str) -> Option<Value> {
+    if let Ok(value) = serde_json::from_str(s) {
+        return Some(value);
+    }
+    let mut open = vec![];
+    let (mut in_string, mut escaped) = (false, false);
+    // places the document can be cut back to, with the containers open there
+    let mut cuts = vec![];
+    for (i, c) in s.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' | '[' => {
+                open.push(c);
+                cuts.push((i + 1, open.clone()));
+            }
+            '}' | ']' => {
+                open.pop();
+            }
+            ',' => cuts.push((i, open.clone())),
+            _ => {}
+        }
+    }
+
+    let mut head = s.to_string();
+    if in_string {
+        if escaped {
+            head.pop();
+        }
+        // a half received \u escape
+        if let Some(at) = head.rfind("\\u").filter(|at| head.len() - at < 6) {
+            head.truncate(at);
+        }
+        head.push('"');
+    }
+    close(&head, &open).or_else(|| cuts.iter().rev().find_map(|(at, open)| close(&s[..*at], open)))
+}
+
+fn close(head: &str, open: &[char]) -> Option<Value> {
+    let mut s = head.trim_end().to_string();
+    s.extend(open.iter().rev().map(|c| if *c == '{' { '}' } else { ']' }));
+    serde_json::from_str(&s).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn partial() {
+        for (s, expected) in [
+            ("", None),
+            ("{", Some(json!({}))),
+            (r#"{"pa"#, Some(json!({}))),
+            (r#"{"path""#, Some(json!({}))),
+            (r#"{"path": "#, Some(json!({}))),
+            (r#"{"path": "a.t"#, Some(json!({"path": "a.t"}))),
+            (r#"{"path": "a.txt", "#, Some(json!({"path": "a.txt"}))),
+            (r#"{"lines": ["one\n", "tw"#, Some(json!({"lines": ["one\n", "tw"]}))),
+            (r#"{"quote": "say \"hi\" \"#, Some(json!({"quote": "say \"hi\" "}))),
+            (r#"{"e": "caf\u00"#, Some(json!({"e": "caf"}))),
+            (r#"{"n": 12"#, Some(json!({"n": 12}))),
+            (r#"{"ok": 1, "done": tr"#, Some(json!({"ok": 1}))),
+            (r#"{"a": {"b": [1, {"c": null}"#, Some(json!({"a": {"b": [1, {"c": null}]}}))),
+            (r#"{"a": 1}"#, Some(json!({"a": 1}))),
+        ] {
+            assert_eq!(parse_partial_json(s), expected, "{s}");
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! retrying requests the api asks us to try again later
+
+use std::time::Duration;
+
+use reqwest::{StatusCode, header::HeaderMap};
+
+/// How many times, and how patiently, to retry rate limited, overloaded and failed requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { max_retries: 2, initial_backoff: Duration::from_millis(500), max_backoff: Duration::from_secs(30) }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self { max_retries: 0, ..Default::default() }
+    }
+
+    /// 429s, 529s (overloaded) and other server errors are worth retrying; anything else will fail again.
+    pub fn retryable(status: StatusCode) -> bool {
+        status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+    }
+
+    /// How long to wait before retry number `attempt` (starting at 0). A `retry-after` header wins over the
+    /// exponential backoff, capped at `max_backoff` either way.
+    pub fn delay(&self, attempt: u32, headers: &HeaderMap) -> Duration {
+        let delay = retry_after(headers).unwrap_or_else(|| self.initial_backoff.saturating_mul(2_u32.saturating_pow(attempt)));
+        delay.min(self.max_backoff)
+    }
+}
+
+/// The `retry-after` header, in seconds. One too long for a `Duration`, such as `inf`, is [`Duration::MAX`].
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let secs = headers.get("retry-after")?.to_str().ok()?.trim().parse::<f64>().ok()?;
+    (secs >= 0.0).then(|| Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay() {
+        let policy = RetryPolicy::default();
+        let mut headers = HeaderMap::new();
+        assert_eq!(policy.delay(0, &headers), Duration::from_millis(500));
+        assert_eq!(policy.delay(2, &headers), Duration::from_secs(2));
+        assert_eq!(policy.delay(20, &headers), Duration::from_secs(30));
+        headers.insert("retry-after", "3".parse().unwrap());
+        assert_eq!(policy.delay(0, &headers), Duration::from_secs(3));
+        // from a misbehaving proxy
+        for bad in ["inf", "1e30"] {
+            headers.insert("retry-after", bad.parse().unwrap());
+            assert_eq!(policy.delay(0, &headers), Duration::from_secs(30));
+        }
+        headers.insert("retry-after", "NaN".parse().unwrap());
+        assert_eq!(policy.delay(0, &headers), Duration::from_millis(500));
+        assert!(RetryPolicy::retryable(StatusCode::from_u16(529).unwrap()));
+        assert!(!RetryPolicy::retryable(StatusCode::BAD_REQUEST));
+    }
+}
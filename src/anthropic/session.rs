@@ -0,0 +1,140 @@
+//! on-disk persistence for conversations
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+
+use super::Conversation;
+
+/// Stores each conversation as a standalone json file named after its id, so that a session can be copied to and
+/// resumed by someone else.
+#[derive(Debug, Clone)]
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+/// A lightweight description of a stored session.
+#[derive(Debug, Clone)]
+pub struct SessionSummary {
+    pub id: String,
+    pub model: String,
+    pub title: String,
+    pub turns: usize,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl SessionStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Opens the store under `$XDG_DATA_HOME/ai/sessions`, falling back to `~/.local/share/ai/sessions`.
+    pub fn open_default() -> Result<Self> {
+        let data = match std::env::var_os("XDG_DATA_HOME").filter(|v| !v.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var_os("HOME").context("no HOME set")?).join(".local/share"),
+        };
+        Ok(Self::new(data.join("ai").join("sessions")))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Writes the conversation, replacing any earlier save of the same session.
+    pub async fn save(&self, conv: &Conversation) -> Result<PathBuf> {
+        tokio::fs::create_dir_all(&self.dir).await.context("create session dir")?;
+        let path = self.path(&conv.id);
+        let tmp = path.with_extension("json.tmp");
+        let json = serde_json::to_vec_pretty(conv).context("serialize conversation")?;
+        tokio::fs::write(&tmp, json).await.context("write session")?;
+        tokio::fs::rename(&tmp, &path).await.context("rename session")?;
+        Ok(path)
+    }
+
+    /// Loads a session by id. A path to a session file (e.g. one shared by a teammate) is accepted as well.
+    pub async fn load(&self, id: &str) -> Result<Conversation> {
+        let path = if id.ends_with(".json") && Path::new(id).exists() { PathBuf::from(id) } else { self.path(id) };
+        let bs = tokio::fs::read(&path).await.with_context(|| format!("read session {}", path.display()))?;
+        serde_json::from_slice(&bs).with_context(|| format!("parse session {}", path.display()))
+    }
+
+    /// The most recently updated session, if any.
+    pub async fn latest(&self) -> Result<Option<Conversation>> {
+        match self.list().await?.first() {
+            Some(summary) => self.load(&summary.id).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// All sessions, most recently updated first.
+    pub async fn list(&self) -> Result<Vec<SessionSummary>> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err).context("read session dir"),
+        };
+        let mut res = vec![];
+        while let Some(entry) = entries.next_entry().await.context("read session dir entry")? {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let conv = match tokio::fs::read(&path)
+                .await
+                .context("read session")
+                .and_then(|bs| serde_json::from_slice::<Conversation>(&bs).context("parse session"))
+            {
+                Ok(conv) => conv,
+                Err(err) => {
+                    tracing::warn!("skipping {}: {err:#}", path.display());
+                    continue;
+                }
+            };
+            res.push(SessionSummary {
+                title: conv.title(),
+                turns: conv.turns.len(),
+                id: conv.id,
+                model: conv.model,
+                updated_at: conv.updated_at,
+            });
+        }
+        res.sort_by_key(|s| std::cmp::Reverse(s.updated_at));
+        Ok(res)
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn save_load_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SessionStore::new(dir.path());
+        assert!(store.latest().await.unwrap().is_none());
+
+        let mut first = Conversation::new("claude", Some(String::from("be brief")));
+        first.push_user("first");
+        store.save(&first).await.unwrap();
+
+        let mut second = Conversation::new("claude", None);
+        second.id = String::from("second");
+        second.push_user("second");
+        let path = store.save(&second).await.unwrap();
+
+        let loaded = store.load(&first.id).await.unwrap();
+        assert_eq!(loaded.messages, first.messages);
+        assert_eq!(loaded.system.as_deref(), Some("be brief"));
+        assert_eq!(store.load(path.to_str().unwrap()).await.unwrap().id, "second");
+
+        let list = store.list().await.unwrap();
+        assert_eq!(list.iter().map(|s| s.title.as_str()).collect::<Vec<_>>(), vec!["second", "first"]);
+        assert_eq!(store.latest().await.unwrap().unwrap().id, "second");
+    }
+}
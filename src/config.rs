@@ -0,0 +1,421 @@
+//! Settings from `~/.config/ai/config.toml`: named profiles of client settings and tool permissions, and the MCP
+//! servers whose tools can be offered to the model.
+//!
+//! ```toml
+//! default_profile = "work"
+//!
+//! [profiles.work]
+//! endpoint = "https://llm-proxy.example.com/"
+//! api_key = { env = "WORK_ANTHROPIC_KEY" }
+//! model = "claude-3-5-sonnet-latest"
+//! system = "Answer tersely."
+//! max_tokens = 4096
+//! retry = { max_retries = 5, initial_backoff_secs = 1.0 }
+//! tools = { shell = ["git status", "cargo test"], write_files = true, mcp_servers = ["github"] }
+//!
+//! [mcp_servers.github]
+//! command = "github-mcp-server"
+//! args = ["stdio"]
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    anthropic::{Client, RetryPolicy},
+    mcp::{McpClient, McpConfig, ServerConfig},
+    secret::{self, SecretString},
+    tools::{
+        ToolRegistry,
+        fs::Sandbox,
+        shell::{ApprovalPolicy, Shell},
+    },
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// the profile used when none is named; otherwise the one called `default`, if there is one
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    #[serde(default)]
+    pub mcp_servers: BTreeMap<String, ServerConfig>,
+}
+
+/// Settings for the client and the tools it may use. Anything left out keeps the client's default.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub endpoint: Option<url::Url>,
+    /// where the api key comes from; `ANTHROPIC_API_KEY` if left out
+    pub api_key: Option<KeySource>,
+    pub model: Option<String>,
+    pub system: Option<String>,
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<f32>,
+    pub retry: Option<RetryConfig>,
+    pub tools: ToolPermissions,
+}
+
+/// Where to read the api key from, e.g. `{ env = "ANTHROPIC_API_KEY" }` or `{ command = "pass show anthropic" }`.
+/// Paths may start with `~/`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum KeySource {
+    /// an environment variable
+    Env(String),
+    /// a file holding just the key, which only its owner should be able to read
+    File(PathBuf),
+    /// a command printing the key, run with `sh -c`
+    Command(String),
+    /// a file written by [`crate::secret::encrypt`], whose passphrase is taken from `AI_KEY_PASSPHRASE` or asked
+    /// for on the terminal
+    EncryptedFile(PathBuf),
+}
+
+impl Default for KeySource {
+    fn default() -> Self {
+        Self::Env(String::from("ANTHROPIC_API_KEY"))
+    }
+}
+
+/// Returned, inside the `anyhow::Error`, when the api key can't be found.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("no api key: {0}")]
+pub struct MissingKey(pub String);
+
+impl KeySource {
+    pub fn read(&self) -> Result<SecretString> {
+        let key = match self {
+            KeySource::Env(var) => match std::env::var(var) {
+                Ok(key) => SecretString::new(key),
+                Err(_) => return Err(MissingKey(format!("{var} is not set")).into()),
+            },
+            KeySource::File(path) => {
+                let path = expand_home(path)?;
+                warn_if_readable_by_others(&path);
+                SecretString::new(read_key_file(&path)?)
+            }
+            KeySource::Command(command) => {
+                let output = std::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(std::process::Stdio::inherit())
+                    .output()
+                    .with_context(|| format!("run {command}"))?;
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                anyhow::ensure!(output.status.success(), "{command} failed ({}): {}", output.status, stderr.trim());
+                SecretString::new(String::from_utf8(output.stdout).context("key is not utf-8")?)
+            }
+            KeySource::EncryptedFile(path) => {
+                let path = expand_home(path)?;
+                let text = read_key_file(&path)?;
+                let passphrase = match std::env::var("AI_KEY_PASSPHRASE") {
+                    Ok(passphrase) => passphrase,
+                    Err(_) => {
+                        rpassword::prompt_password(format!("passphrase for {}: ", path.display())).context("read passphrase")?
+                    }
+                };
+                secret::decrypt(&text, &SecretString::new(passphrase)).with_context(|| format!("decrypt {}", path.display()))?
+            }
+        };
+        if key.is_empty() {
+            return Err(MissingKey(format!("the key from {self:?} is empty")).into());
+        }
+        Ok(key)
+    }
+}
+
+fn read_key_file(path: &Path) -> Result<String> {
+    match std::fs::read_to_string(path) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Err(MissingKey(format!("{} not found", path.display())).into()),
+        result => result.with_context(|| format!("read {}", path.display())),
+    }
+}
+
+/// Replaces a leading `~/` with the home directory.
+pub fn expand_home(path: &Path) -> Result<PathBuf> {
+    match path.strip_prefix("~") {
+        Ok(rest) => Ok(PathBuf::from(std::env::var_os("HOME").context("no HOME set")?).join(rest)),
+        Err(_) => Ok(path.to_path_buf()),
+    }
+}
+
+#[cfg(unix)]
+fn warn_if_readable_by_others(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Ok(meta) = std::fs::metadata(path) {
+        if meta.permissions().mode() & 0o077 != 0 {
+            tracing::warn!("{} can be read by others; chmod 600 it", path.display());
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_readable_by_others(_path: &Path) {}
+
+/// [`RetryPolicy`] in seconds.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub max_retries: Option<u32>,
+    pub initial_backoff_secs: Option<f64>,
+    pub max_backoff_secs: Option<f64>,
+}
+
+impl RetryConfig {
+    pub fn policy(&self) -> Result<RetryPolicy> {
+        let default = RetryPolicy::default();
+        let secs = |secs: Option<f64>, default| match secs {
+            Some(secs) => Duration::try_from_secs_f64(secs).with_context(|| format!("invalid backoff {secs}")),
+            None => Ok(default),
+        };
+        Ok(RetryPolicy {
+            max_retries: self.max_retries.unwrap_or(default.max_retries),
+            initial_backoff: secs(self.initial_backoff_secs, default.initial_backoff)?,
+            max_backoff: secs(self.max_backoff_secs, default.max_backoff)?,
+        })
+    }
+}
+
+/// Which tools the model is offered. Files can always be read, within the directory the tools are given.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolPermissions {
+    pub shell: ShellPermission,
+    pub write_files: bool,
+    /// the servers, of the config's `mcp_servers`, whose tools to offer; all of them if left out
+    pub mcp_servers: Option<Vec<String>>,
+}
+
+/// `"ask"`, `"deny"`, or a list of the command prefixes to allow.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ShellPermission {
+    Mode(ShellMode),
+    Allow(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShellMode {
+    Ask,
+    Deny,
+}
+
+impl Default for ShellPermission {
+    fn default() -> Self {
+        Self::Mode(ShellMode::Ask)
+    }
+}
+
+impl ToolPermissions {
+    /// The filesystem and shell tools, working in `root`.
+    pub fn registry(&self, root: impl AsRef<Path>) -> Result<ToolRegistry> {
+        self.registry_with(root, ApprovalPolicy::Ask)
+    }
+
+    /// Like [`ToolPermissions::registry`], but commands the profile would have asked about on the terminal are
+    /// decided by `ask` instead, e.g. when the caller has its own way of asking.
+    pub fn registry_with(&self, root: impl AsRef<Path>, ask: ApprovalPolicy) -> Result<ToolRegistry> {
+        let root = root.as_ref();
+        let sandbox = Sandbox::new(root)?;
+        let mut registry = ToolRegistry::new();
+        if self.write_files {
+            sandbox.register(&mut registry);
+        } else {
+            sandbox.register_read_only(&mut registry);
+        }
+        let policy = match &self.shell {
+            ShellPermission::Mode(ShellMode::Deny) => None,
+            ShellPermission::Mode(ShellMode::Ask) => Some(ask),
+            ShellPermission::Allow(prefixes) => Some(ApprovalPolicy::Allowlist(prefixes.clone())),
+        };
+        if let Some(policy) = policy {
+            registry.register(Shell::new(policy, root));
+        }
+        Ok(registry)
+    }
+}
+
+impl Config {
+    /// `$AI_CONFIG`, else `$XDG_CONFIG_HOME/ai/config.toml`, falling back to `~/.config/ai/config.toml`.
+    pub fn default_path() -> Result<PathBuf> {
+        let var = |name| std::env::var_os(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+        if let Some(path) = var("AI_CONFIG") {
+            return Ok(path);
+        }
+        let config = match var("XDG_CONFIG_HOME") {
+            Some(dir) => dir,
+            None => var("HOME").context("no HOME set")?.join(".config"),
+        };
+        Ok(config.join("ai").join("config.toml"))
+    }
+
+    /// Loads the config at `path`, which is an empty config if there is no such file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err).with_context(|| format!("read {}", path.display())),
+        };
+        toml::from_str(&text).with_context(|| format!("parse {}", path.display()))
+    }
+
+    pub fn load_default() -> Result<Self> {
+        Self::load(Self::default_path()?)
+    }
+
+    /// The profile called `name`, which must exist, or if `None` the default profile. Without a default, that is
+    /// the profile called `default`, or no settings at all.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        match name.or(self.default_profile.as_deref()) {
+            Some(name) => self.profiles.get(name).cloned().with_context(|| format!("no profile named {name}")),
+            None => Ok(self.profiles.get("default").cloned().unwrap_or_default()),
+        }
+    }
+
+    /// The MCP servers `profile` may use.
+    pub fn mcp(&self, profile: &Profile) -> Result<McpConfig> {
+        let mut servers = self.mcp_servers.clone();
+        if let Some(names) = &profile.tools.mcp_servers {
+            if let Some(missing) = names.iter().find(|name| !servers.contains_key(*name)) {
+                anyhow::bail!("no mcp server named {missing}");
+            }
+            servers.retain(|name, _| names.contains(name));
+        }
+        Ok(McpConfig { servers })
+    }
+}
+
+impl Profile {
+    /// Applies `ANTHROPIC_BASE_URL` and `AI_MODEL`, as looked up by `var`, over the profile's settings.
+    pub fn with_env(mut self, var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        if let Some(endpoint) = var("ANTHROPIC_BASE_URL").filter(|v| !v.is_empty()) {
+            self.endpoint = Some(endpoint.parse().with_context(|| format!("invalid ANTHROPIC_BASE_URL {endpoint}"))?);
+        }
+        if let Some(model) = var("AI_MODEL").filter(|v| !v.is_empty()) {
+            self.model = Some(model);
+        }
+        Ok(self)
+    }
+
+    /// A client with the profile's key and settings.
+    pub fn client(&self) -> Result<Client> {
+        let key = self.api_key.clone().unwrap_or_default().read()?;
+        let mut client = Client::new(key)?;
+        if let Some(endpoint) = &self.endpoint {
+            client = client.with_endpoint(endpoint.clone());
+        }
+        if let Some(model) = &self.model {
+            client = client.with_model(model);
+        }
+        if let Some(system) = &self.system {
+            client = client.with_system(system);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            client = client.with_max_tokens(max_tokens);
+        }
+        if let Some(temperature) = self.temperature {
+            client = client.with_temperature(temperature);
+        }
+        if let Some(retry) = &self.retry {
+            client = client.with_retry(retry.policy()?);
+        }
+        Ok(client)
+    }
+
+    /// The profile's tools, working in `root`, together with those of the MCP servers it may use, which are
+    /// started. The servers run for as long as the returned clients are kept.
+    pub async fn tools(&self, config: &Config, root: impl AsRef<Path>) -> Result<(ToolRegistry, Vec<McpClient>)> {
+        self.tools_with(config, root, ApprovalPolicy::Ask).await
+    }
+
+    /// Like [`Profile::tools`], with shell commands needing approval decided by `ask`; see
+    /// [`ToolPermissions::registry_with`].
+    pub async fn tools_with(
+        &self,
+        config: &Config,
+        root: impl AsRef<Path>,
+        ask: ApprovalPolicy,
+    ) -> Result<(ToolRegistry, Vec<McpClient>)> {
+        let mut registry = self.tools.registry_with(root, ask)?;
+        let servers = config.mcp(self)?.connect(&mut registry).await?;
+        Ok((registry, servers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+default_profile = "work"
+
+[profiles.work]
+endpoint = "https://llm-proxy.example.com/"
+api_key = { env = "WORK_ANTHROPIC_KEY" }
+model = "claude-3-5-sonnet-latest"
+max_tokens = 4096
+retry = { max_retries = 5, initial_backoff_secs = 1.5 }
+tools = { shell = ["git status"], mcp_servers = ["github"] }
+
+[profiles.locked-down]
+tools = { shell = "deny" }
+
+[mcp_servers.github]
+command = "github-mcp-server"
+args = ["stdio"]
+
+[mcp_servers.other]
+command = "other"
+"#;
+
+    #[test]
+    fn profiles() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let work = config.profile(None).unwrap();
+        assert_eq!(work.api_key, Some(KeySource::Env(String::from("WORK_ANTHROPIC_KEY"))));
+        assert_eq!(work.tools.shell, ShellPermission::Allow(vec![String::from("git status")]));
+        let retry = work.retry.as_ref().unwrap().policy().unwrap();
+        assert_eq!((retry.max_retries, retry.initial_backoff), (5, Duration::from_millis(1500)));
+        assert_eq!(retry.max_backoff, RetryPolicy::default().max_backoff);
+        assert_eq!(config.mcp(&work).unwrap().servers.keys().collect::<Vec<_>>(), ["github"]);
+
+        let locked = config.profile(Some("locked-down")).unwrap();
+        assert_eq!(locked.tools.shell, ShellPermission::Mode(ShellMode::Deny));
+        assert_eq!(config.mcp(&locked).unwrap().servers.len(), 2);
+        let dir = tempfile::tempdir().unwrap();
+        let tools = locked.tools.registry(dir.path()).unwrap();
+        assert!(tools.get("read_file").is_some() && tools.get("write_file").is_none() && tools.get("shell").is_none());
+        assert!(config.profile(Some("nope")).is_err());
+
+        let env = |var: &str| (var == "AI_MODEL").then(|| String::from("claude-3-5-haiku-latest"));
+        let work = work.with_env(env).unwrap();
+        assert_eq!(work.model.as_deref(), Some("claude-3-5-haiku-latest"));
+        assert_eq!(work.endpoint.unwrap().as_str(), "https://llm-proxy.example.com/");
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("key"), "sk-ant-file\n").unwrap();
+        assert_eq!(KeySource::File(dir.path().join("key")).read().unwrap().expose(), "sk-ant-file");
+        let err = KeySource::File(dir.path().join("nope")).read().unwrap_err();
+        assert!(err.is::<MissingKey>());
+        assert_eq!(KeySource::Command(String::from("echo sk-ant-cmd")).read().unwrap().expose(), "sk-ant-cmd");
+        assert!(KeySource::Command(String::from("echo locked >&2; exit 1")).read().unwrap_err().to_string().contains("locked"));
+        let source: KeySource =
+            toml::from_str::<Profile>("api_key = { encrypted_file = \"~/key.enc\" }").unwrap().api_key.unwrap();
+        assert_eq!(source, KeySource::EncryptedFile(PathBuf::from("~/key.enc")));
+
+        let typo = toml::from_str::<Config>("[profiles.x]\nmodle = \"claude\"");
+        assert!(typo.unwrap_err().to_string().contains("unknown field `modle`"));
+        assert_eq!(Config::load(dir.path().join("missing.toml")).unwrap(), Config::default());
+    }
+}
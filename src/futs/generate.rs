@@ -101,14 +101,8 @@ mod tests {
         assert_eq!(Generate::new(word_count).next().await.transpose().unwrap(), Some(235976));
 
         assert_eq!(
-            Generate::new(word_count)
-                .take(2)
-                .collect::<Vec<_>>()
-                .await
-                .into_iter()
-                .collect::<Result<Vec<usize>, _>>()
-                .unwrap(),
-            iter::repeat(235976).take(2).collect::<Vec<_>>()
+            Generate::new(word_count).take(2).collect::<Vec<_>>().await.into_iter().collect::<Result<Vec<usize>, _>>().unwrap(),
+            iter::repeat_n(235976, 2).collect::<Vec<_>>()
         );
     }
 }
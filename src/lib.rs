@@ -1,5 +1,15 @@
 #![allow(unused)]
 
+pub mod agent;
 pub mod anthropic;
+pub mod config;
 pub mod futs;
+pub mod markdown;
+pub mod mcp;
+pub mod persona;
+pub mod repl;
+pub mod secret;
+pub mod template;
+pub mod tools;
 pub mod tracing;
+pub mod tui;
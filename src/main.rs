@@ -1,3 +1,606 @@
-fn main() {
-    println!("Hello, world!");
+use std::{
+    env,
+    io::{IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
+};
+
+use ai::{
+    agent::{Agent, Step},
+    anthropic::{
+        ApiError, BatchOutcome, Budget, BudgetGuard, Client, Content, Conversation, Message, MessagesResponse, Response,
+        ServerError, SessionStore, TextStreamEvent, cassette::Recorder,
+    },
+    config::{Config, MissingKey},
+    persona::Personas,
+    repl::Repl,
+    secret::SecretString,
+    template::Template,
+    tools::{ToolRegistry, fs::Sandbox, shell::ApprovalPolicy},
+    tui::App,
+};
+use anyhow::{Context, Result};
+use clap::Parser;
+
+#[derive(clap::Parser, Debug)]
+#[command(
+    name = "ai",
+    version,
+    about = "Talk to Claude from the command line",
+    after_help = "Exit codes: 1 for most errors, 2 for usage errors, 3 for api errors and 4 for authentication errors."
+)]
+struct Args {
+    #[clap(subcommand)]
+    cmd: Command,
+    /// the profile of ~/.config/ai/config.toml to use, instead of the default one
+    #[arg(long, short, global = true)]
+    profile: Option<String>,
+    /// the model to use, e.g. claude-3-5-sonnet-latest
+    #[arg(long, short, global = true)]
+    model: Option<String>,
+    /// a system prompt setting the role, tone or rules for the replies
+    #[arg(long, short, global = true)]
+    system: Option<String>,
+    /// a persona of ~/.config/ai/personas: a system prompt, and the model and parameters to use it with
+    #[arg(long, global = true)]
+    persona: Option<String>,
+    /// the most tokens a reply may have
+    #[arg(long, global = true)]
+    max_tokens: Option<u32>,
+    /// from 0.0 to 1.0; lower is more predictable
+    #[arg(long, short, global = true)]
+    temperature: Option<f32>,
+    /// how ask, chat, image, models and count-tokens print what they get back
+    #[arg(long, short, global = true, value_enum, default_value_t)]
+    output: Output,
+    /// refuse to send requests once this many dollars have been spent, and to models of unknown pricing
+    #[arg(long, global = true)]
+    max_dollars: Option<f64>,
+    /// refuse to send requests once this many tokens have been used
+    #[arg(long, global = true)]
+    max_total_tokens: Option<u64>,
+    /// record every request and response to this jsonl cassette
+    #[arg(long, global = true)]
+    record: Option<PathBuf>,
+    /// serve responses from this cassette instead of the api
+    #[cfg(feature = "mock")]
+    #[arg(long, global = true, conflicts_with = "record")]
+    replay: Option<PathBuf>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// ask a single question, streaming the answer
+    Ask {
+        #[command(flatten)]
+        input: Input,
+        /// let the model read files in the current directory, run commands and use MCP servers, as the profile
+        /// permits
+        #[arg(long)]
+        tools: bool,
+    },
+    /// have a conversation, saved as a session as it goes
+    Chat {
+        /// resume a saved session by id or path
+        #[arg(long, conflicts_with = "cont")]
+        resume: Option<String>,
+        /// resume the most recently updated session
+        #[arg(long = "continue")]
+        cont: bool,
+    },
+    /// have a conversation in a full-screen terminal ui, with saved sessions to hand
+    Tui {
+        /// let the model read files in the current directory, run commands and use MCP servers, as the profile
+        /// permits, approving each call as it is made
+        #[arg(long)]
+        tools: bool,
+    },
+    /// render a prompt template and stream the reply
+    Run {
+        template: PathBuf,
+        /// a value for one of the template's variables; may be repeated
+        #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_var)]
+        vars: Vec<(String, String)>,
+    },
+    /// ask about an image, by default what is in it
+    Image { path: PathBuf, prompt: Vec<String> },
+    /// list the models the api offers
+    Models,
+    /// count the tokens a prompt would use, system prompt included
+    CountTokens {
+        #[command(flatten)]
+        input: Input,
+    },
+    /// submit prompts as a batch, processed asynchronously at a discount
+    Batch {
+        #[clap(subcommand)]
+        cmd: BatchCommand,
+    },
+    /// list saved sessions
+    Sessions,
+    /// encrypt an api key, read from stdin or the terminal, into a file for a profile's api_key to use
+    EncryptKey { path: PathBuf },
+    /// serve ask_claude, describe_image and summarize_file as an MCP server over stdio
+    Mcp {
+        /// the directory describe_image and summarize_file may read from
+        #[arg(long, default_value = ".")]
+        root: PathBuf,
+    },
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Output {
+    /// the reply's text as it streams in, with usage on stderr
+    #[default]
+    Text,
+    /// the whole response, usage and stop reason included, once it is complete
+    Json,
+    /// each streaming event as a line of json
+    Ndjson,
+    /// a transcript of the exchange
+    Markdown,
+}
+
+/// A message made of any attached files, then whatever is piped to stdin, then the prompt.
+#[derive(clap::Args, Debug)]
+struct Input {
+    /// attach a file: text, a png, jpeg, gif or webp image, or a pdf; may be repeated
+    #[arg(short = 'f', long = "file")]
+    files: Vec<PathBuf>,
+    /// don't read stdin even if it is piped, e.g. when running in a loop which reads stdin itself
+    #[arg(long)]
+    no_stdin: bool,
+    /// the prompt; taken from stdin if left out and stdin is piped
+    prompt: Vec<String>,
+}
+
+impl Input {
+    async fn content(&self) -> Result<Vec<Content>> {
+        let mut content = vec![];
+        for path in &self.files {
+            content.extend(Content::file(path).await.map_err(|err| CliError::Usage(format!("{err:#}")))?);
+        }
+        let stdin = if self.no_stdin { None } else { read_piped_stdin()? };
+        match (stdin, self.prompt.join(" ")) {
+            (Some(stdin), prompt) if prompt.is_empty() => content.push(Content::text(stdin)),
+            (Some(stdin), prompt) => {
+                content.push(Content::text(format!("<stdin>\n{stdin}\n</stdin>")));
+                content.push(Content::text(prompt));
+            }
+            (None, prompt) if !prompt.is_empty() => content.push(Content::text(prompt)),
+            (None, _) if content.is_empty() => {
+                let msg = "nothing to send: give a prompt, pipe one to stdin, or attach files with -f";
+                return Err(CliError::Usage(msg.to_string()).into());
+            }
+            (None, _) => {}
+        }
+        Ok(content)
+    }
+
+    /// A new conversation with this input as its first message.
+    async fn conversation(&self, client: &Client, system: Option<String>) -> Result<Conversation> {
+        let mut conv = Conversation::new(client.model(), system);
+        conv.messages.push(Message { role: String::from("user"), content: self.content().await? });
+        Ok(conv)
+    }
+}
+
+/// Parses a `--var name=value`.
+fn parse_var(arg: &str) -> Result<(String, String), String> {
+    let (name, value) = arg.split_once('=').ok_or_else(|| format!("expected NAME=VALUE, got {arg:?}"))?;
+    Ok((name.trim().to_string(), value.to_string()))
+}
+
+/// Whatever is piped to stdin, or `None` if stdin is a terminal or empty.
+fn read_piped_stdin() -> Result<Option<String>> {
+    let mut stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        return Ok(None);
+    }
+    let mut text = String::new();
+    stdin.read_to_string(&mut text).context("read stdin")?;
+    let text = text.trim_end();
+    Ok((!text.is_empty()).then(|| text.to_string()))
+}
+
+/// Errors which get an exit code of their own, beyond those of the api.
+#[derive(Debug, thiserror::Error)]
+enum CliError {
+    #[error("{0}")]
+    Usage(String),
+}
+
+const EXIT_USAGE: u8 = 2;
+const EXIT_API: u8 = 3;
+const EXIT_AUTH: u8 = 4;
+
+fn exit_code(err: &anyhow::Error) -> u8 {
+    for cause in err.chain() {
+        match cause.downcast_ref::<CliError>() {
+            Some(CliError::Usage(_)) => return EXIT_USAGE,
+            None => {}
+        }
+        if cause.is::<MissingKey>() {
+            return EXIT_AUTH;
+        }
+        if let Some(err) = cause.downcast_ref::<ApiError>() {
+            return if err.is_auth() { EXIT_AUTH } else { EXIT_API };
+        }
+        if let Some(err) = cause.downcast_ref::<ServerError>() {
+            return if err.is_auth() { EXIT_AUTH } else { EXIT_API };
+        }
+    }
+    1
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum BatchCommand {
+    /// submit each non-empty line of a file as a prompt, identified as line-N in the results
+    Submit {
+        file: PathBuf,
+        /// wait for the batch to end and print its results
+        #[arg(long)]
+        wait: bool,
+    },
+    /// show how far along a batch is
+    Status { id: String },
+    /// print the results of an ended batch
+    Results { id: String },
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    // stdout carries the replies, or in mcp mode the protocol
+    ai::tracing::init_stderr();
+    match run(Args::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err:#}");
+            ExitCode::from(exit_code(&err))
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<()> {
+    if let Command::EncryptKey { path } = &args.cmd {
+        return encrypt_key(path);
+    }
+    // flags win over the persona, then the environment, then the profile
+    let (config, mut profile) = Config::load_default()
+        .and_then(|config| {
+            let profile = config.profile(args.profile.as_deref())?.with_env(|var| env::var(var).ok())?;
+            Ok((config, profile))
+        })
+        .map_err(|err| CliError::Usage(format!("{err:#}")))?;
+    let personas = Personas::open_default()?;
+    let persona = match &args.persona {
+        Some(name) => {
+            let persona = personas.get(name).map_err(|err| CliError::Usage(format!("{err:#}")))?;
+            persona.apply(&mut profile)?;
+            Some(persona)
+        }
+        None => None,
+    };
+    profile.model = args.model.clone().or(profile.model);
+    profile.system = args.system.clone().or(profile.system);
+    profile.max_tokens = args.max_tokens.or(profile.max_tokens);
+    profile.temperature = args.temperature.or(profile.temperature);
+    let mut client = profile.client()?;
+    if args.max_dollars.is_some() || args.max_total_tokens.is_some() {
+        let budget = Budget { max_dollars: args.max_dollars, max_tokens: args.max_total_tokens };
+        client = client.with_budget(BudgetGuard::new(budget));
+    }
+    if let Some(path) = &args.record {
+        client = client.with_recorder(Recorder::create(path)?);
+    }
+    #[cfg(feature = "mock")]
+    let _replay = match &args.replay {
+        Some(path) => {
+            let server = ai::anthropic::cassette::replay(path).await?;
+            client = client.with_endpoint(server.url());
+            Some(server)
+        }
+        None => None,
+    };
+    let new_conversation = || Conversation::new(client.model(), profile.system.clone());
+    match &args.cmd {
+        Command::Ask { input, tools: false } => {
+            let mut conv = input.conversation(&client, profile.system.clone()).await?;
+            stream(&client, &mut conv, args.output).await?;
+        }
+        Command::Ask { input, tools: true } => {
+            let mut conv = input.conversation(&client, profile.system.clone()).await?;
+            let (tools, _servers) = profile.tools(&config, ".").await?;
+            let agent = Agent::new(client.clone(), tools).with_hook(|step| {
+                if let Step::ToolUse(Content::ToolUse { name, input, .. }) = step {
+                    eprintln!("[{name} {input}]");
+                }
+            });
+            let resp = agent.run(&mut conv).await?;
+            print_reply(&conv, 0, &resp, args.output)?;
+        }
+        Command::Chat { resume, cont } => {
+            let store = SessionStore::open_default()?;
+            let mut conv = match (resume, cont) {
+                (Some(id), _) => store.load(id).await?,
+                (None, true) => store.latest().await?.context("no sessions to continue")?,
+                (None, false) => new_conversation(),
+            };
+            if let Some(model) = args.model.as_ref().or(persona.as_ref().and_then(|p| p.model.as_ref())) {
+                conv.model = model.clone();
+            }
+            if args.system.is_some() || persona.is_some() {
+                conv.system = profile.system.clone();
+            }
+            eprintln!("session {}", conv.id);
+            let mut repl = Repl::new(client, conv)
+                .with_store(store)
+                .with_personas(personas, args.persona.clone())
+                .with_on_event(chat_printer(args.output));
+            if let Some(history) = Repl::default_history() {
+                repl = repl.with_history(history);
+            }
+            repl.run().await?;
+        }
+        Command::Tui { tools } => {
+            let store = SessionStore::open_default()?;
+            let models = match client.list_models().await {
+                Ok(models) => models.into_iter().map(|m| m.id).collect(),
+                Err(err) => {
+                    tracing::warn!("list models: {err:#}");
+                    vec![]
+                }
+            };
+            let mut app = App::new(client.clone(), new_conversation(), store).with_models(models);
+            let _servers = if *tools {
+                // every call is approved in the ui, so commands needn't be asked about again
+                let approved = ApprovalPolicy::Custom(Arc::new(|_| true));
+                let (tools, servers) = profile.tools_with(&config, ".", approved).await?;
+                app = app.with_tools(tools);
+                servers
+            } else {
+                vec![]
+            };
+            ai::tui::run(&mut app).await?;
+        }
+        Command::Run { template, vars } => {
+            let usage = |err: anyhow::Error| CliError::Usage(format!("{err:#}"));
+            let template = Template::load(template).map_err(usage)?;
+            let mut req = template.render(&client, &vars.iter().cloned().collect()).map_err(usage)?;
+            // flags win over the template, which wins over the persona and the profile
+            req.model = args.model.clone().unwrap_or(req.model);
+            req.system = args.system.clone().or(req.system).or(profile.system.clone());
+            let mut client = client.clone().with_max_tokens(args.max_tokens.unwrap_or(req.max_tokens));
+            if let Some(temperature) = args.temperature.or(req.temperature) {
+                client = client.with_temperature(temperature);
+            }
+            let mut conv = Conversation::new(&req.model, req.system);
+            conv.messages = req.messages;
+            stream(&client, &mut conv, args.output).await?;
+        }
+        Command::Image { path, prompt } => {
+            let mut conv = new_conversation();
+            let prompt = if prompt.is_empty() { String::from("What is in this image?") } else { prompt.join(" ") };
+            let image = Content::image_path(path).await?;
+            conv.messages.push(Message { role: String::from("user"), content: vec![image, Content::text(prompt)] });
+            stream(&client, &mut conv, args.output).await?;
+        }
+        Command::Models => {
+            let models = client.list_models().await?;
+            match args.output {
+                Output::Json => println!("{}", serde_json::to_string_pretty(&models)?),
+                Output::Ndjson => {
+                    for model in models {
+                        println!("{}", serde_json::to_string(&model)?);
+                    }
+                }
+                Output::Text | Output::Markdown => {
+                    for model in models {
+                        let default = if model.id == client.model() { " (default)" } else { "" };
+                        let created = model.created_at.format("%Y-%m-%d");
+                        println!("{}\t{}\t{created}{default}", model.id, model.display_name);
+                    }
+                }
+            }
+        }
+        Command::CountTokens { input } => {
+            let conv = input.conversation(&client, profile.system.clone()).await?;
+            let tokens = client.count_tokens(&conv).await?;
+            match args.output {
+                Output::Json | Output::Ndjson => println!("{}", serde_json::json!({"input_tokens": tokens})),
+                Output::Text | Output::Markdown => println!("{tokens}"),
+            }
+        }
+        Command::Batch { cmd } => batch(&client, cmd, new_conversation).await?,
+        Command::Sessions => {
+            let store = SessionStore::open_default()?;
+            for s in store.list().await? {
+                println!("{}\t{}\t{} turns\t{}\t{}", s.id, s.updated_at.format("%Y-%m-%d %H:%M"), s.turns, s.model, s.title);
+            }
+        }
+        Command::Mcp { root } => {
+            let mut tools = ToolRegistry::new();
+            ai::tools::claude::register(&client, &Sandbox::new(root)?, &mut tools);
+            ai::mcp::server::serve_stdio(tools).await?;
+        }
+        Command::EncryptKey { .. } => unreachable!("handled before the client is built"),
+    };
+    Ok(())
+}
+
+/// Has the model reply to `conv`, printing the reply as `output` says: text and events as they stream in, the
+/// response or a transcript once it is complete.
+async fn stream(client: &Client, conv: &mut Conversation, output: Output) -> Result<MessagesResponse> {
+    let question = conv.messages.len().saturating_sub(1);
+    let mut stdout = std::io::stdout();
+    let mut text = ai::repl::print_markdown();
+    let resp = client
+        .stream_reply_with(conv, &[], |ev| {
+            match (output, ev) {
+                (Output::Text, ev) => return text(ev),
+                (Output::Ndjson, ev) => match serde_json::to_string(ev) {
+                    Ok(line) => println!("{line}"),
+                    Err(err) => tracing::warn!("serialize event: {err}"),
+                },
+                _ => return,
+            }
+            let _ = stdout.flush();
+        })
+        .await?;
+    match output {
+        Output::Text => print_usage(&resp),
+        Output::Ndjson => {}
+        Output::Json | Output::Markdown => print_reply(conv, question, &resp, output)?,
+    }
+    Ok(resp)
+}
+
+/// Prints a complete reply. A markdown transcript starts with the message at `from`, and includes the system
+/// prompt if that is the first.
+fn print_reply(conv: &Conversation, from: usize, resp: &MessagesResponse, output: Output) -> Result<()> {
+    match output {
+        Output::Text => {
+            let mut text = ai::repl::print_markdown();
+            text(&TextStreamEvent::Fragment(resp.text()));
+            text(&TextStreamEvent::Eof(resp.clone()));
+            print_usage(resp);
+        }
+        Output::Json => println!("{}", serde_json::to_string_pretty(resp)?),
+        Output::Ndjson => println!("{}", serde_json::to_string(&TextStreamEvent::Eof(resp.clone()))?),
+        Output::Markdown if from == 0 => println!("{}", conv.markdown()),
+        Output::Markdown => {
+            // compaction may have dropped messages from the start
+            let from = from.min(conv.messages.len().saturating_sub(2));
+            println!("{}", conv.messages[from..].iter().map(Message::markdown).collect::<Vec<_>>().join("\n"));
+        }
+    }
+    Ok(())
+}
+
+async fn batch(client: &Client, cmd: &BatchCommand, new_conversation: impl Fn() -> Conversation) -> Result<()> {
+    let poll = Duration::from_secs(30);
+    let batch = match cmd {
+        BatchCommand::Submit { file, wait } => {
+            let text = tokio::fs::read_to_string(file).await.with_context(|| format!("read {}", file.display()))?;
+            let convs = text
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| !line.trim().is_empty())
+                .map(|(i, line)| {
+                    let mut conv = new_conversation();
+                    conv.push_user(line);
+                    (format!("line-{}", i + 1), conv)
+                })
+                .collect::<Vec<_>>();
+            if convs.is_empty() {
+                return Err(CliError::Usage(format!("{} has no prompts", file.display())).into());
+            }
+            let batch = client.create_batch(&convs, &[]).await?;
+            eprintln!("submitted batch {} of {} requests", batch.id, convs.len());
+            if !wait {
+                println!("{}", batch.id);
+                return Ok(());
+            }
+            client.wait_for_batch(&batch.id, poll).await?
+        }
+        BatchCommand::Status { id } => {
+            let batch = client.batch(id).await?;
+            let counts = &batch.request_counts;
+            println!(
+                "{}\t{}\t{} processing, {} succeeded, {} errored, {} canceled, {} expired",
+                batch.id,
+                batch.processing_status,
+                counts.processing,
+                counts.succeeded,
+                counts.errored,
+                counts.canceled,
+                counts.expired
+            );
+            return Ok(());
+        }
+        BatchCommand::Results { id } => client.batch(id).await?,
+    };
+    let mut results = client.batch_results(&batch).await?;
+    // line-2 before line-10
+    results.sort_by(|a, b| (a.custom_id.len(), &a.custom_id).cmp(&(b.custom_id.len(), &b.custom_id)));
+    for result in results {
+        match result.result {
+            BatchOutcome::Succeeded { message } => println!("### {}\n\n{}\n", result.custom_id, message.text()),
+            BatchOutcome::Errored { error: Response::Error { error } } => {
+                println!("### {} (errored)\n\n{error}\n", result.custom_id)
+            }
+            BatchOutcome::Errored { error: Response::Messages(_) } => {
+                println!("### {} (errored)\n", result.custom_id)
+            }
+            BatchOutcome::Canceled => println!("### {} (canceled)\n", result.custom_id),
+            BatchOutcome::Expired => println!("### {} (expired)\n", result.custom_id),
+        }
+    }
+    Ok(())
+}
+
+fn encrypt_key(path: &Path) -> Result<()> {
+    let key = match read_piped_stdin()? {
+        Some(key) => key,
+        None => rpassword::prompt_password("api key: ").context("read key")?,
+    };
+    let key = SecretString::new(key);
+    if key.is_empty() {
+        return Err(CliError::Usage(String::from("no key given")).into());
+    }
+    let passphrase = match env::var("AI_KEY_PASSPHRASE") {
+        Ok(passphrase) => SecretString::new(passphrase),
+        Err(_) => {
+            let passphrase = SecretString::new(rpassword::prompt_password("passphrase: ").context("read passphrase")?);
+            let again = SecretString::new(rpassword::prompt_password("again: ").context("read passphrase")?);
+            if passphrase.expose() != again.expose() {
+                return Err(CliError::Usage(String::from("the passphrases differ")).into());
+            }
+            passphrase
+        }
+    };
+    let text = ai::secret::encrypt(&key, &passphrase)?;
+    let mut file = std::fs::OpenOptions::new();
+    file.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o600);
+    let mut file = file.open(path).with_context(|| format!("create {}", path.display()))?;
+    file.write_all(text.as_bytes()).with_context(|| format!("write {}", path.display()))?;
+    eprintln!("wrote {}; use it with api_key = {{ encrypted_file = {:?} }}", path.display(), path.display().to_string());
+    Ok(())
+}
+
+/// Prints each reply of a chat as `output` says.
+fn chat_printer(output: Output) -> impl FnMut(&TextStreamEvent) + Send + 'static {
+    let mut text = ai::repl::print_markdown();
+    move |ev| {
+        match (output, ev) {
+            (Output::Text, TextStreamEvent::Eof(resp)) => {
+                text(ev);
+                print_usage(resp);
+            }
+            (Output::Text, ev) => text(ev),
+            (Output::Ndjson, ev) => match serde_json::to_string(ev) {
+                Ok(line) => println!("{line}"),
+                Err(err) => tracing::warn!("serialize event: {err}"),
+            },
+            (Output::Json, TextStreamEvent::Eof(resp)) => match serde_json::to_string_pretty(resp) {
+                Ok(json) => println!("{json}"),
+                Err(err) => tracing::warn!("serialize response: {err}"),
+            },
+            (Output::Markdown, TextStreamEvent::Eof(resp)) => {
+                println!("{}", Message { role: String::from("assistant"), content: resp.content.clone() }.markdown())
+            }
+            _ => return,
+        }
+        let _ = std::io::stdout().flush();
+    }
+}
+
+fn print_usage(resp: &MessagesResponse) {
+    let Some(usage) = &resp.usage else { return };
+    let cost = resp.cost().map(|c| c.to_string()).unwrap_or_else(|| String::from("unknown cost"));
+    eprintln!("[{} in, {} out: {cost}]", usage.input_tokens, usage.output_tokens);
 }
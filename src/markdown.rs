@@ -0,0 +1,331 @@
+//! Rendering markdown for the terminal as it streams in.
+
+use std::{
+    io::{self, IsTerminal, Write},
+    sync::LazyLock,
+};
+
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Theme, ThemeSet},
+    parsing::SyntaxSet,
+    util::as_24_bit_terminal_escaped,
+};
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+static THEME: LazyLock<Theme> =
+    LazyLock::new(|| ThemeSet::load_defaults().themes.remove("base16-ocean.dark").unwrap_or_default());
+
+const RESET: &str = "\x1b[0m";
+const HEADING: &str = "\x1b[1;35m";
+const QUOTE: &str = "\x1b[2m";
+const MARKER: &str = "\x1b[33m";
+const FENCE: &str = "\x1b[2m";
+
+/// What kind of line is being rendered, as worked out from how it starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Paragraph,
+    Heading,
+    Quote,
+    Item,
+    Fence,
+}
+
+/// Emphasis open at this point of the line.
+#[derive(Debug, Default, Clone, Copy)]
+struct Inline {
+    bold: bool,
+    italic: bool,
+    code: bool,
+}
+
+/// Formats markdown for the terminal as fragments of it arrive: headings, emphasis, inline code, lists, block quotes
+/// and fenced code blocks, which are syntax highlighted by language. A line's text is written as soon as what it
+/// means is known, so a marker split across fragments, like a `**` arriving as two `*`s, is held back until the
+/// rest of it arrives. Code is highlighted a line at a time.
+pub struct MarkdownRenderer<W> {
+    out: W,
+    /// write the markdown as it is, e.g. when the output isn't a terminal
+    plain: bool,
+    /// the line being rendered, as much of it as has arrived
+    line: String,
+    /// how much of `line` has been written
+    written: usize,
+    block: Option<Block>,
+    inline: Inline,
+    /// the highlighter for the fenced code block the line is in, if it is in one
+    code: Option<HighlightLines<'static>>,
+}
+
+impl MarkdownRenderer<io::Stdout> {
+    /// Renders to stdout, passing the markdown through as it is unless stdout is a terminal.
+    pub fn stdout() -> Self {
+        let plain = !io::stdout().is_terminal();
+        Self::new(io::stdout(), plain)
+    }
+}
+
+impl<W: Write> MarkdownRenderer<W> {
+    pub fn new(out: W, plain: bool) -> Self {
+        Self { out, plain, line: String::new(), written: 0, block: None, inline: Inline::default(), code: None }
+    }
+
+    /// Renders the next fragment of markdown, as far as it can be yet.
+    pub fn push(&mut self, fragment: &str) -> io::Result<()> {
+        if self.plain {
+            self.out.write_all(fragment.as_bytes())?;
+            return self.out.flush();
+        }
+        let mut lines = fragment.split('\n');
+        if let Some(first) = lines.next() {
+            self.line.push_str(first);
+        }
+        for line in lines {
+            self.end_line(true)?;
+            self.line.push_str(line);
+        }
+        self.render(false)?;
+        self.out.flush()
+    }
+
+    /// Renders whatever is left once the markdown is complete, and resets the renderer for the next document.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if !self.plain {
+            if !self.line.is_empty() {
+                self.end_line(false)?;
+            }
+            self.code = None;
+        }
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    /// Writes as much of the current line as can be, all of it if `complete`.
+    fn render(&mut self, complete: bool) -> io::Result<()> {
+        if self.code.is_some() {
+            // highlighting needs whole lines
+            return Ok(());
+        }
+        if self.block.is_none() {
+            let Some((block, skip)) = classify(&self.line, complete) else { return Ok(()) };
+            self.block = Some(block);
+            self.written = skip;
+            let (indent, marker) = (&self.line[..self.line.len() - self.line.trim_start().len()], self.line[..skip].trim());
+            match block {
+                Block::Paragraph | Block::Fence => {}
+                Block::Heading => write!(self.out, "{HEADING}")?,
+                Block::Quote => write!(self.out, "{QUOTE}│{RESET} {QUOTE}")?,
+                Block::Item if matches!(marker, "-" | "*" | "+") => write!(self.out, "{indent}{MARKER}•{RESET} ")?,
+                Block::Item => write!(self.out, "{indent}{MARKER}{marker}{RESET} ")?,
+            }
+        }
+        if self.block == Some(Block::Fence) {
+            return Ok(());
+        }
+        self.inline(complete)
+    }
+
+    /// Writes the current line's text from where it got to, styling emphasis and inline code. Unless `complete`, a
+    /// trailing character which might start a marker is held back.
+    fn inline(&mut self, complete: bool) -> io::Result<()> {
+        let line = std::mem::take(&mut self.line);
+        let res = self.write_inline(&line, complete);
+        self.line = line;
+        res
+    }
+
+    fn write_inline(&mut self, line: &str, complete: bool) -> io::Result<()> {
+        let rest = &line[self.written..];
+        let chars = rest.char_indices().collect::<Vec<_>>();
+        let before = line[..self.written].chars().next_back();
+        let mut i = 0;
+        while i < chars.len() {
+            let (at, c) = chars[i];
+            let next = chars.get(i + 1).map(|&(_, c)| c);
+            let prev = if i == 0 { before } else { Some(chars[i - 1].1) };
+            let held = next.is_none() && !complete;
+            let mut skip = 1;
+            match c {
+                '`' => {
+                    self.inline.code = !self.inline.code;
+                    self.write_style()?;
+                }
+                _ if self.inline.code => write!(self.out, "{c}")?,
+                '\\' if held => break,
+                // only punctuation can be escaped, so `\n` and `C:\Users` keep their backslash
+                '\\' if next.is_some_and(|c| c.is_ascii_punctuation()) => {
+                    write!(self.out, "{}", next.unwrap_or_default())?;
+                    skip = 2;
+                }
+                '*' | '_' if held => break,
+                '*' | '_' if next == Some(c) => {
+                    self.inline.bold = !self.inline.bold;
+                    self.write_style()?;
+                    skip = 2;
+                }
+                '*' | '_' => {
+                    let word = |c: Option<char>| c.is_some_and(|c| !c.is_whitespace());
+                    let inside = |c: Option<char>| c == Some('_') || c.is_some_and(char::is_alphanumeric);
+                    let toggles = if self.inline.italic { word(prev) } else { word(next) };
+                    // snake_case isn't emphasis
+                    if toggles && !(c == '_' && (inside(prev) && inside(next))) {
+                        self.inline.italic = !self.inline.italic;
+                        self.write_style()?;
+                    } else {
+                        write!(self.out, "{c}")?;
+                    }
+                }
+                c => write!(self.out, "{c}")?,
+            }
+            i += skip;
+            self.written += chars.get(i).map_or(rest.len(), |&(next, _)| next) - at;
+        }
+        Ok(())
+    }
+
+    /// Finishes the current line, which is followed by a newline if `newline`.
+    fn end_line(&mut self, newline: bool) -> io::Result<()> {
+        let newline = if newline { "\n" } else { "" };
+        if let Some(highlighter) = &mut self.code {
+            if self.line.trim_start().starts_with("```") {
+                write!(self.out, "{FENCE}{}{RESET}{newline}", self.line)?;
+                self.code = None;
+            } else {
+                let line = format!("{}\n", self.line);
+                let ranges = highlighter.highlight_line(&line, &SYNTAXES).map_err(io::Error::other)?;
+                let escaped = as_24_bit_terminal_escaped(&ranges, false);
+                write!(self.out, "{}{RESET}{newline}", escaped.trim_end_matches('\n'))?;
+            }
+        } else {
+            self.render(true)?;
+            if self.block == Some(Block::Fence) {
+                let lang = self.line.trim_start().trim_start_matches('`').trim();
+                let syntax = SYNTAXES.find_syntax_by_token(lang).unwrap_or_else(|| SYNTAXES.find_syntax_plain_text());
+                self.code = Some(HighlightLines::new(syntax, &THEME));
+                write!(self.out, "{FENCE}{}{RESET}{newline}", self.line)?;
+            } else {
+                let styled = self.inline.bold || self.inline.italic || self.inline.code;
+                let reset = if styled || self.block.is_some_and(|b| b != Block::Paragraph) { RESET } else { "" };
+                write!(self.out, "{reset}{newline}")?;
+            }
+        }
+        self.line.clear();
+        self.written = 0;
+        self.block = None;
+        self.inline = Inline::default();
+        Ok(())
+    }
+
+    /// Switches to the line's style plus whatever emphasis is open.
+    fn write_style(&mut self) -> io::Result<()> {
+        let base = match self.block {
+            Some(Block::Heading) => HEADING,
+            Some(Block::Quote) => QUOTE,
+            _ => "",
+        };
+        write!(self.out, "{RESET}{base}")?;
+        if self.inline.bold {
+            write!(self.out, "\x1b[1m")?;
+        }
+        if self.inline.italic {
+            write!(self.out, "\x1b[3m")?;
+        }
+        if self.inline.code {
+            write!(self.out, "\x1b[36m")?;
+        }
+        Ok(())
+    }
+}
+
+/// Works out what kind of line `line` starts, and how much of it is markup, or `None` if that can't be told until
+/// more of it arrives.
+fn classify(line: &str, complete: bool) -> Option<(Block, usize)> {
+    let trimmed = line.trim_start();
+    let indent = line.len() - trimmed.len();
+    // after the marker comes a space, or the line must be complete to tell
+    let marked = |block, len: usize| match trimmed[len..].chars().next() {
+        Some(' ') => Some((block, indent + len + 1)),
+        Some(_) => Some((Block::Paragraph, 0)),
+        None if complete => Some((Block::Paragraph, 0)),
+        None => None,
+    };
+    if trimmed.starts_with("```") {
+        return Some((Block::Fence, 0));
+    }
+    let hashes = trimmed.len() - trimmed.trim_start_matches('#').len();
+    let digits = trimmed.len() - trimmed.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    match trimmed.chars().next() {
+        None if complete => Some((Block::Paragraph, 0)),
+        None => None,
+        Some('`') if !complete && "```".starts_with(trimmed) => None,
+        Some('#') if hashes <= 6 => marked(Block::Heading, hashes),
+        Some('>') => match trimmed[1..].chars().next() {
+            Some(' ') => Some((Block::Quote, indent + 2)),
+            None if !complete => None,
+            _ => Some((Block::Quote, indent + 1)),
+        },
+        Some('-' | '*' | '+') => marked(Block::Item, 1),
+        Some(_) if digits > 0 => match trimmed[digits..].chars().next() {
+            Some('.' | ')') => marked(Block::Item, digits + 1),
+            None if !complete => None,
+            _ => Some((Block::Paragraph, 0)),
+        },
+        Some(_) => Some((Block::Paragraph, 0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(fragments: &[&str]) -> String {
+        let mut renderer = MarkdownRenderer::new(vec![], false);
+        for fragment in fragments {
+            renderer.push(fragment).unwrap();
+        }
+        renderer.finish().unwrap();
+        String::from_utf8(renderer.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn blocks() {
+        assert_eq!(render(&["# Title\n"]), "\x1b[1;35mTitle\x1b[0m\n");
+        assert_eq!(render(&["- one\n2. two"]), "\x1b[33m•\x1b[0m one\x1b[0m\n\x1b[33m2.\x1b[0m two\x1b[0m");
+        assert_eq!(render(&["> wise\n"]), "\x1b[2m│\x1b[0m \x1b[2mwise\x1b[0m\n");
+        assert_eq!(render(&["#hashtag -dash 3.14"]), "#hashtag -dash 3.14");
+        assert_eq!(render(&["a **b** `c` snake_case"]), "a \x1b[0m\x1b[1mb\x1b[0m \x1b[0m\x1b[36mc\x1b[0m snake_case");
+        assert_eq!(render(&["use \\n for newline, \\*not italic\\*"]), "use \\n for newline, *not italic*");
+        assert_eq!(render(&["C:\\Users\\ada\\"]), "C:\\Users\\ada\\");
+
+        let code = render(&["```rust\nfn main() {}\n```\nafter"]);
+        assert!(code.starts_with("\x1b[2m```rust\x1b[0m\n\x1b[38;2;"), "{code:?}");
+        assert!(code.ends_with("\x1b[0m\n\x1b[2m```\x1b[0m\nafter"), "{code:?}");
+        // unlike its text, code is highlighted
+        assert!(!code.contains("fn main"));
+    }
+
+    #[test]
+    fn split_markers() {
+        let text = "## A *heading*\n\n1. **bold** and _it_\n   - `co*de`\n> quote \\*\n```py\nx = 1\n```\ndone";
+        let whole = render(&[text]);
+        let chars = text.chars().map(String::from).collect::<Vec<_>>();
+        assert_eq!(render(&chars.iter().map(String::as_str).collect::<Vec<_>>()), whole);
+        for at in 1..text.len() {
+            if text.is_char_boundary(at) {
+                assert_eq!(render(&[&text[..at], &text[at..]]), whole, "split at {at}");
+            }
+        }
+    }
+
+    #[test]
+    fn plain() {
+        let mut renderer = MarkdownRenderer::new(vec![], true);
+        renderer.push("# **Title**").unwrap();
+        renderer.finish().unwrap();
+        assert_eq!(renderer.into_inner(), b"# **Title**");
+    }
+}
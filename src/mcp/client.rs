@@ -0,0 +1,422 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    process::Stdio,
+    sync::{
+        Arc, Mutex, OnceLock, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use futures::{FutureExt, future::BoxFuture};
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    process::Child,
+    sync::oneshot,
+    task::JoinHandle,
+};
+
+use super::{McpConfig, McpContent, McpPrompt, McpResource, McpTool, PROTOCOL_VERSION, RpcError, ServerConfig};
+use crate::{
+    anthropic::{Content, Message, ToolDefinition},
+    tools::{DynTool, ToolRegistry},
+};
+
+type Reply = std::result::Result<Value, RpcError>;
+
+/// A connection to an MCP server, initialized and ready for requests. Clones share the connection, which is closed,
+/// and the server killed, once the last is dropped.
+#[derive(Clone)]
+pub struct McpClient {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    name: String,
+    writer: tokio::sync::Mutex<Box<dyn AsyncWrite + Send + Unpin>>,
+    /// requests awaiting a reply, by id; `None` once the server has gone away
+    pending: Mutex<Option<HashMap<u64, oneshot::Sender<Reply>>>>,
+    next_id: AtomicU64,
+    /// the result of the initialize handshake
+    initialized: OnceLock<Value>,
+    timeout: Duration,
+    reader: Mutex<Option<JoinHandle<()>>>,
+    child: Mutex<Option<Child>>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Some(reader) = self.reader.get_mut().unwrap().take() {
+            reader.abort();
+        }
+    }
+}
+
+impl std::fmt::Debug for McpClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("McpClient").field("name", &self.inner.name).finish_non_exhaustive()
+    }
+}
+
+impl McpClient {
+    /// Launches the server described by `config` and initializes it. The server's stderr is logged at debug.
+    pub async fn spawn(name: &str, config: &ServerConfig) -> Result<Self> {
+        let mut cmd = tokio::process::Command::new(&config.command);
+        cmd.args(&config.args)
+            .envs(&config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(cwd) = &config.cwd {
+            cmd.current_dir(cwd);
+        }
+        let mut child = cmd.spawn().with_context(|| format!("launch mcp server {name}: {}", config.command))?;
+        let stdin = child.stdin.take().context("no stdin")?;
+        let stdout = child.stdout.take().context("no stdout")?;
+        if let Some(stderr) = child.stderr.take() {
+            let name = name.to_string();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    tracing::debug!(server = name, "{line}");
+                }
+            });
+        }
+        let client = Self::connect(name, stdout, stdin).await?;
+        *client.inner.child.lock().unwrap() = Some(child);
+        Ok(client)
+    }
+
+    /// Initializes a server already connected to `reader` and `writer`.
+    pub async fn connect(
+        name: &str,
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> Result<Self> {
+        let inner = Arc::new(Inner {
+            name: name.to_string(),
+            writer: tokio::sync::Mutex::new(Box::new(writer)),
+            pending: Mutex::new(Some(HashMap::new())),
+            next_id: AtomicU64::new(1),
+            initialized: OnceLock::new(),
+            timeout: Duration::from_secs(30),
+            reader: Mutex::new(None),
+            child: Mutex::new(None),
+        });
+        let reader = tokio::spawn(read_replies(reader, Arc::downgrade(&inner)));
+        *inner.reader.lock().unwrap() = Some(reader);
+        let client = Self { inner };
+
+        let params = json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": {},
+            "clientInfo": {"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")},
+        });
+        let init = client.request("initialize", params).await.with_context(|| format!("initialize {name}"))?;
+        tracing::debug!(server = name, "initialized: {init}");
+        let _ = client.inner.initialized.set(init);
+        client.notify("notifications/initialized", json!({})).await?;
+        Ok(client)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    /// The name and version the server reported.
+    pub fn server_info(&self) -> Option<&Value> {
+        self.inner.initialized.get()?.get("serverInfo")
+    }
+
+    /// Whether the server offers e.g. `tools`, `resources` or `prompts`.
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.inner.initialized.get().and_then(|init| init["capabilities"].get(capability)).is_some()
+    }
+
+    /// Sends a request and waits for its result.
+    pub async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let name = &self.inner.name;
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        match self.inner.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(id, tx),
+            None => anyhow::bail!("mcp server {name} has exited"),
+        };
+        self.inner.send(&json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params})).await?;
+        match tokio::time::timeout(self.inner.timeout, rx).await {
+            Ok(Ok(reply)) => reply.map_err(|err| anyhow::Error::new(err).context(format!("{name}: {method}"))),
+            Ok(Err(_)) => anyhow::bail!("mcp server {name} exited during {method}"),
+            Err(_) => {
+                if let Some(pending) = self.inner.pending.lock().unwrap().as_mut() {
+                    pending.remove(&id);
+                }
+                anyhow::bail!("{name}: {method} timed out after {:?}", self.inner.timeout)
+            }
+        }
+    }
+
+    pub async fn notify(&self, method: &str, params: Value) -> Result<()> {
+        self.inner.send(&json!({"jsonrpc": "2.0", "method": method, "params": params})).await
+    }
+
+    pub async fn list_tools(&self) -> Result<Vec<McpTool>> {
+        self.list("tools", "tools/list").await
+    }
+
+    pub async fn list_resources(&self) -> Result<Vec<McpResource>> {
+        self.list("resources", "resources/list").await
+    }
+
+    pub async fn list_prompts(&self) -> Result<Vec<McpPrompt>> {
+        self.list("prompts", "prompts/list").await
+    }
+
+    /// Every page of a listing, or nothing if the server lacks the capability.
+    async fn list<T: DeserializeOwned>(&self, capability: &str, method: &str) -> Result<Vec<T>> {
+        if !self.has_capability(capability) {
+            return Ok(vec![]);
+        }
+        let mut items = vec![];
+        let mut cursor = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({"cursor": cursor}),
+                None => json!({}),
+            };
+            let mut page = self.request(method, params).await?;
+            let page_items = serde_json::from_value::<Vec<T>>(page[capability].take())
+                .with_context(|| format!("{}: parse {method} result", self.inner.name))?;
+            items.extend(page_items);
+            cursor = page.get("nextCursor").and_then(Value::as_str).map(String::from);
+            if cursor.is_none() {
+                return Ok(items);
+            }
+        }
+    }
+
+    /// Calls a tool, returning the text of its result. Results the server marks as errors become `Err`s.
+    pub async fn call_tool(&self, name: &str, arguments: Value) -> Result<String> {
+        let mut result = self.request("tools/call", json!({"name": name, "arguments": arguments})).await?;
+        let content = serde_json::from_value::<Vec<McpContent>>(result["content"].take()).context("parse content")?;
+        let text = content.iter().map(|c| c.to_string()).collect::<Vec<_>>().join("\n");
+        match result["isError"].as_bool() {
+            Some(true) => Err(anyhow::anyhow!(text)),
+            _ => Ok(text),
+        }
+    }
+
+    /// The text of a resource. Binary contents are shown as a placeholder.
+    pub async fn read_resource(&self, uri: &str) -> Result<String> {
+        let result = self.request("resources/read", json!({"uri": uri})).await?;
+        let contents = result["contents"].as_array().context("no contents")?;
+        let texts = contents.iter().map(|c| match c["text"].as_str() {
+            Some(text) => text.to_string(),
+            None => format!("[{}]", c["mimeType"].as_str().unwrap_or("binary")),
+        });
+        Ok(texts.collect::<Vec<_>>().join("\n"))
+    }
+
+    /// A prompt, filled in with `arguments`, as messages ready to send to the model.
+    pub async fn get_prompt(&self, name: &str, arguments: &BTreeMap<String, String>) -> Result<Vec<Message>> {
+        let result = self.request("prompts/get", json!({"name": name, "arguments": arguments})).await?;
+        let messages = result["messages"].as_array().context("no messages")?;
+        messages
+            .iter()
+            .map(|m| {
+                let role = m["role"].as_str().context("no role")?.to_string();
+                let content = serde_json::from_value::<McpContent>(m["content"].clone()).context("parse content")?;
+                Ok(Message { role, content: vec![Content::text(content)] })
+            })
+            .collect()
+    }
+
+    /// Adds the server's tools to `registry`, each named `<server>__<tool>` to keep servers apart. Returns how many
+    /// there were.
+    pub async fn register_tools(&self, registry: &mut ToolRegistry) -> Result<usize> {
+        let tools = self.list_tools().await?;
+        let count = tools.len();
+        for tool in tools {
+            let name = format!("{}__{}", self.inner.name, tool.name)
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+                .take(64)
+                .collect();
+            registry.register_dyn(Arc::new(McpToolHandle { client: self.clone(), name, tool }));
+        }
+        Ok(count)
+    }
+}
+
+impl McpConfig {
+    /// Launches every configured server and registers its tools. The clients must be kept for as long as the tools
+    /// are in use, although the tools themselves keep their servers alive too.
+    pub async fn connect(&self, registry: &mut ToolRegistry) -> Result<Vec<McpClient>> {
+        let mut clients = vec![];
+        for (name, config) in &self.servers {
+            let client = McpClient::spawn(name, config).await?;
+            let count = client.register_tools(registry).await.with_context(|| format!("list tools of {name}"))?;
+            tracing::debug!("mcp server {name} has {count} tools");
+            clients.push(client);
+        }
+        Ok(clients)
+    }
+}
+
+impl Inner {
+    async fn send(&self, msg: &Value) -> Result<()> {
+        let mut line = serde_json::to_vec(msg).context("serialize message")?;
+        line.push(b'\n');
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&line).await.with_context(|| format!("write to mcp server {}", self.name))?;
+        writer.flush().await.with_context(|| format!("flush mcp server {}", self.name))
+    }
+}
+
+/// Routes replies to the requests awaiting them, and answers the server's own requests, until the server goes away.
+async fn read_replies(reader: impl AsyncRead + Unpin, inner: Weak<Inner>) {
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let Some(inner) = inner.upgrade() else { return };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let msg = match serde_json::from_str::<Value>(&line) {
+            Ok(msg) => msg,
+            Err(err) => {
+                tracing::warn!(server = inner.name, "unparseable message ({err}): {line}");
+                continue;
+            }
+        };
+        match (msg.get("id"), msg.get("method").and_then(Value::as_str)) {
+            (Some(id), Some(method)) => {
+                let reply = match method {
+                    "ping" => json!({"jsonrpc": "2.0", "id": id, "result": {}}),
+                    _ => {
+                        let err = RpcError::new(RpcError::METHOD_NOT_FOUND, format!("{method} is not supported"));
+                        json!({"jsonrpc": "2.0", "id": id, "error": err})
+                    }
+                };
+                if let Err(err) = inner.send(&reply).await {
+                    tracing::warn!(server = inner.name, "reply to {method}: {err:#}");
+                }
+            }
+            (Some(id), None) => {
+                let waiting = id.as_u64().and_then(|id| inner.pending.lock().unwrap().as_mut()?.remove(&id));
+                let Some(waiting) = waiting else {
+                    tracing::warn!(server = inner.name, "reply to unknown request {id}");
+                    continue;
+                };
+                let reply = match msg.get("error") {
+                    Some(err) => Err(serde_json::from_value(err.clone())
+                        .unwrap_or_else(|_| RpcError::new(0, format!("malformed error: {err}")))),
+                    None => Ok(msg.get("result").cloned().unwrap_or_default()),
+                };
+                let _ = waiting.send(reply);
+            }
+            (None, Some(method)) => tracing::debug!(server = inner.name, "notification {method}"),
+            (None, None) => tracing::warn!(server = inner.name, "unexpected message: {line}"),
+        }
+    }
+    // dropping the senders tells everyone still waiting that there will be no reply
+    if let Some(inner) = inner.upgrade() {
+        inner.pending.lock().unwrap().take();
+    }
+}
+
+/// One of an MCP server's tools, as registered with a [`ToolRegistry`].
+pub struct McpToolHandle {
+    client: McpClient,
+    name: String,
+    tool: McpTool,
+}
+
+impl DynTool for McpToolHandle {
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: self.name.clone(),
+            description: self.tool.description.clone().unwrap_or_default(),
+            input_schema: self.tool.input_schema.clone(),
+        }
+    }
+
+    fn call_json(&self, input: Value) -> BoxFuture<'_, Result<String>> {
+        self.client.call_tool(&self.tool.name, input).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcp::fake::FakeMcpServer;
+
+    #[tokio::test]
+    async fn client() {
+        let server = FakeMcpServer::new()
+            .with_tool("echo", "Echoes its input.", json!({"type": "object"}), |input| Ok(input.to_string()))
+            .with_tool("fail", "Always fails.", json!({"type": "object"}), |_| Err(String::from("it broke")))
+            .with_tool("third", "Makes the listing span pages.", json!({"type": "object"}), |_| Ok(String::new()))
+            .with_resource("file:///notes.txt", "notes", "remember the milk")
+            .with_prompt("review", "Reviews some code.", "Please review {{code}}.")
+            .with_page_size(2);
+        let requests = server.requests();
+        let client = server.connect("fake").await.unwrap();
+        assert_eq!(client.server_info().unwrap()["name"], "fake-mcp");
+        assert!(client.has_capability("tools") && client.has_capability("resources"));
+
+        let tools = client.list_tools().await.unwrap();
+        assert_eq!(tools.iter().map(|t| t.name.as_str()).collect::<Vec<_>>(), ["echo", "fail", "third"]);
+        let resources = client.list_resources().await.unwrap();
+        assert_eq!(resources[0].uri, "file:///notes.txt");
+        assert_eq!(client.read_resource("file:///notes.txt").await.unwrap(), "remember the milk");
+        assert_eq!(client.list_prompts().await.unwrap()[0].arguments[0].name, "code");
+        let args = BTreeMap::from([(String::from("code"), String::from("main.rs"))]);
+        let messages = client.get_prompt("review", &args).await.unwrap();
+        assert_eq!(messages[0].content[0], Content::text("Please review main.rs."));
+        let err = client.request("nope", json!({})).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<RpcError>().unwrap().code, RpcError::METHOD_NOT_FOUND);
+
+        let mut registry = ToolRegistry::new();
+        assert_eq!(client.register_tools(&mut registry).await.unwrap(), 3);
+        let defs = registry.definitions();
+        assert_eq!(defs[0].name, "fake__echo");
+        assert_eq!(defs[0].description, "Echoes its input.");
+        let call = |name: &str| Content::ToolUse { id: String::from("t"), name: name.to_string(), input: json!({"a": 1}) };
+        let result = registry.dispatch(&call("fake__echo")).await.unwrap();
+        assert_eq!(
+            result,
+            Content::ToolResult { tool_use_id: String::from("t"), content: String::from(r#"{"a":1}"#), is_error: false }
+        );
+        let Some(Content::ToolResult { content, is_error: true, .. }) = registry.dispatch(&call("fake__fail")).await else {
+            panic!()
+        };
+        assert_eq!(content, "it broke");
+
+        let methods = requests.lock().unwrap().iter().map(|r| r["method"].as_str().unwrap().to_string()).collect::<Vec<_>>();
+        assert_eq!(methods[..4], ["initialize", "notifications/initialized", "tools/list", "tools/list"]);
+    }
+
+    #[tokio::test]
+    async fn server_gone() {
+        let err = McpClient::spawn("missing", &ServerConfig { command: String::from("/nonexistent/mcp"), ..Default::default() })
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("launch mcp server missing"));
+
+        // a server which exits straight away
+        let config = ServerConfig {
+            command: String::from("sh"),
+            args: vec![String::from("-c"), String::from("exit 0")],
+            ..Default::default()
+        };
+        let err = McpClient::spawn("quitter", &config).await.unwrap_err();
+        assert!(format!("{err:#}").contains("initialize quitter"), "{err:#}");
+
+        let config: McpConfig =
+            serde_json::from_str(r#"{"mcpServers": {"files": {"command": "npx", "args": ["server-files"]}}}"#).unwrap();
+        assert_eq!(config.servers["files"].args, ["server-files"]);
+    }
+}
@@ -0,0 +1,169 @@
+//! An in-process MCP server with scripted tools, resources and prompts, for testing code which uses MCP servers.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use super::{McpClient, McpContent, McpPrompt, McpPromptArgument, McpResource, McpTool, PROTOCOL_VERSION, RpcError};
+
+type Handler = Arc<dyn Fn(Value) -> Result<String, String> + Send + Sync>;
+
+/// Serves whatever it has been given over an in-memory pipe. Prompt text may refer to arguments as `{{name}}`.
+#[derive(Clone, Default)]
+pub struct FakeMcpServer {
+    tools: Vec<(McpTool, Handler)>,
+    resources: Vec<(McpResource, String)>,
+    prompts: Vec<(McpPrompt, String)>,
+    /// how many items each page of a listing has; everything on one page if 0
+    page_size: usize,
+    requests: Arc<Mutex<Vec<Value>>>,
+}
+
+impl FakeMcpServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A tool whose result is `handler` applied to its arguments, with `Err`s sent as error results.
+    pub fn with_tool(
+        mut self,
+        name: &str,
+        description: &str,
+        input_schema: Value,
+        handler: impl Fn(Value) -> Result<String, String> + Send + Sync + 'static,
+    ) -> Self {
+        let tool = McpTool { name: name.to_string(), description: Some(description.to_string()), input_schema };
+        self.tools.push((tool, Arc::new(handler)));
+        self
+    }
+
+    pub fn with_resource(mut self, uri: &str, name: &str, text: &str) -> Self {
+        let resource = McpResource {
+            uri: uri.to_string(),
+            name: name.to_string(),
+            description: None,
+            mime_type: Some(String::from("text/plain")),
+        };
+        self.resources.push((resource, text.to_string()));
+        self
+    }
+
+    /// A prompt whose arguments are the `{{name}}`s in `text`, all of them required.
+    pub fn with_prompt(mut self, name: &str, description: &str, text: &str) -> Self {
+        let arguments = text
+            .split("{{")
+            .skip(1)
+            .filter_map(|s| s.split_once("}}"))
+            .map(|(arg, _)| McpPromptArgument { name: arg.trim().to_string(), description: None, required: true })
+            .collect();
+        let prompt = McpPrompt { name: name.to_string(), description: Some(description.to_string()), arguments };
+        self.prompts.push((prompt, text.to_string()));
+        self
+    }
+
+    pub fn with_page_size(mut self, n: usize) -> Self {
+        self.page_size = n;
+        self
+    }
+
+    /// Every message the server has received, in order, including notifications.
+    pub fn requests(&self) -> Arc<Mutex<Vec<Value>>> {
+        self.requests.clone()
+    }
+
+    /// Starts serving, returning a client connected to the server. The server stops when the client is dropped.
+    pub async fn connect(self, name: &str) -> Result<McpClient> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (client_read, client_write) = tokio::io::split(client);
+        tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(server);
+            let mut lines = BufReader::new(read).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let Ok(msg) = serde_json::from_str::<Value>(&line) else { continue };
+                self.requests.lock().unwrap().push(msg.clone());
+                let Some(id) = msg.get("id") else { continue };
+                let reply = match self.handle(msg["method"].as_str().unwrap_or_default(), &msg["params"]) {
+                    Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                    Err(err) => json!({"jsonrpc": "2.0", "id": id, "error": err}),
+                };
+                let mut reply = serde_json::to_vec(&reply).unwrap();
+                reply.push(b'\n');
+                if write.write_all(&reply).await.is_err() {
+                    return;
+                }
+            }
+        });
+        McpClient::connect(name, client_read, client_write).await
+    }
+
+    fn handle(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        let invalid = |msg: String| RpcError::new(RpcError::INVALID_PARAMS, msg);
+        match method {
+            "initialize" => {
+                let mut capabilities = json!({});
+                for (capability, offered) in [
+                    ("tools", !self.tools.is_empty()),
+                    ("resources", !self.resources.is_empty()),
+                    ("prompts", !self.prompts.is_empty()),
+                ] {
+                    if offered {
+                        capabilities[capability] = json!({});
+                    }
+                }
+                Ok(json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": capabilities,
+                    "serverInfo": {"name": "fake-mcp", "version": "0.0.0"},
+                }))
+            }
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(self.page("tools", self.tools.iter().map(|(t, _)| t), params)),
+            "resources/list" => Ok(self.page("resources", self.resources.iter().map(|(r, _)| r), params)),
+            "prompts/list" => Ok(self.page("prompts", self.prompts.iter().map(|(p, _)| p), params)),
+            "tools/call" => {
+                let name = params["name"].as_str().unwrap_or_default();
+                let (_, handler) =
+                    self.tools.iter().find(|(t, _)| t.name == name).ok_or_else(|| invalid(format!("no tool {name}")))?;
+                let (text, is_error) = match handler(params["arguments"].clone()) {
+                    Ok(text) => (text, false),
+                    Err(text) => (text, true),
+                };
+                Ok(json!({"content": [McpContent::text(text)], "isError": is_error}))
+            }
+            "resources/read" => {
+                let uri = params["uri"].as_str().unwrap_or_default();
+                let (resource, text) =
+                    self.resources.iter().find(|(r, _)| r.uri == uri).ok_or_else(|| invalid(format!("no resource {uri}")))?;
+                Ok(json!({"contents": [{"uri": uri, "mimeType": resource.mime_type, "text": text}]}))
+            }
+            "prompts/get" => {
+                let name = params["name"].as_str().unwrap_or_default();
+                let (prompt, text) =
+                    self.prompts.iter().find(|(p, _)| p.name == name).ok_or_else(|| invalid(format!("no prompt {name}")))?;
+                let mut text = text.clone();
+                for arg in &prompt.arguments {
+                    let value = params["arguments"][&arg.name]
+                        .as_str()
+                        .ok_or_else(|| invalid(format!("missing argument {}", arg.name)))?;
+                    text = text.replace(&format!("{{{{{}}}}}", arg.name), value);
+                }
+                Ok(json!({"messages": [{"role": "user", "content": McpContent::text(text)}]}))
+            }
+            _ => Err(RpcError::new(RpcError::METHOD_NOT_FOUND, format!("{method} is not supported"))),
+        }
+    }
+
+    /// The page of `items` after `params`' cursor, which is the index to start at.
+    fn page<'a, T: serde::Serialize + 'a>(&self, key: &str, items: impl Iterator<Item = &'a T>, params: &Value) -> Value {
+        let items = items.collect::<Vec<_>>();
+        let start = params["cursor"].as_str().and_then(|c| c.parse().ok()).unwrap_or(0_usize).min(items.len());
+        let end = if self.page_size == 0 { items.len() } else { (start + self.page_size).min(items.len()) };
+        let mut page = json!({ key: items[start..end] });
+        if end < items.len() {
+            page["nextCursor"] = json!(end.to_string());
+        }
+        page
+    }
+}
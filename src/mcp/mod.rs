@@ -0,0 +1,135 @@
+//! The Model Context Protocol: using the tools, resources and prompts of MCP servers.
+//!
+//! Servers are run as subprocesses and spoken to with JSON-RPC, one message per line, over their stdin and stdout.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+mod client;
+#[cfg(any(test, feature = "mock"))]
+pub mod fake;
+pub mod server;
+
+pub use client::{McpClient, McpToolHandle};
+
+/// The protocol revision this crate speaks.
+pub const PROTOCOL_VERSION: &str = "2025-06-18";
+
+/// How to launch an MCP server.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServerConfig {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// set in addition to the environment inherited from this process
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+}
+
+/// Servers by name, in the `{"mcpServers": {"name": {"command": ...}}}` format other MCP clients use, so that
+/// existing configuration can be reused as is.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct McpConfig {
+    #[serde(default, rename = "mcpServers")]
+    pub servers: BTreeMap<String, ServerConfig>,
+}
+
+impl McpConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("parse {}", path.display()))
+    }
+}
+
+/// An error a server replied with, returned inside the `anyhow::Error`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, thiserror::Error)]
+#[error("{message} ({code})")]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    pub const INVALID_PARAMS: i64 = -32602;
+
+    pub fn new(code: i64, message: impl ToString) -> Self {
+        Self { code, message: message.to_string(), data: None }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct McpTool {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub input_schema: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct McpResource {
+    pub uri: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct McpPrompt {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub arguments: Vec<McpPromptArgument>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct McpPromptArgument {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// A block of a tool result, resource or prompt message. Only text is interpreted; anything else, such as an image,
+/// is shown as a placeholder naming its type.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct McpContent {
+    #[serde(rename = "type")]
+    pub typ: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, Value>,
+}
+
+impl McpContent {
+    pub fn text(text: impl ToString) -> Self {
+        Self { typ: String::from("text"), text: Some(text.to_string()), other: Default::default() }
+    }
+}
+
+impl std::fmt::Display for McpContent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.text {
+            Some(text) => write!(f, "{text}"),
+            None => write!(f, "[{}]", self.typ),
+        }
+    }
+}
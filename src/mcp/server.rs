@@ -0,0 +1,161 @@
+//! Serving a [`ToolRegistry`] as an MCP server, so that other agents and editors can use its tools.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::Mutex,
+};
+
+use super::{McpContent, McpTool, PROTOCOL_VERSION, RpcError};
+use crate::{anthropic::Content, tools::ToolRegistry};
+
+/// Serves `tools` over stdin and stdout until stdin closes.
+pub async fn serve_stdio(tools: ToolRegistry) -> Result<()> {
+    serve(tools, tokio::io::stdin(), tokio::io::stdout()).await
+}
+
+/// Serves `tools` to the client at the other end of `reader` and `writer` until `reader` closes. Requests are
+/// handled concurrently, so a slow tool call doesn't hold up the rest.
+pub async fn serve(
+    tools: ToolRegistry,
+    reader: impl AsyncRead + Unpin,
+    writer: impl AsyncWrite + Send + Unpin + 'static,
+) -> Result<()> {
+    let tools = Arc::new(tools);
+    let writer = Arc::new(Mutex::new(writer));
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await.context("read request")? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let msg = match serde_json::from_str::<Value>(&line) {
+            Ok(msg) => msg,
+            Err(err) => {
+                tracing::warn!("unparseable message ({err}): {line}");
+                let err = RpcError::new(-32700, format!("parse error: {err}"));
+                send(&writer, &json!({"jsonrpc": "2.0", "id": null, "error": err})).await?;
+                continue;
+            }
+        };
+        let method = msg["method"].as_str().unwrap_or_default().to_string();
+        let Some(id) = msg.get("id").cloned() else {
+            tracing::debug!("notification {method}");
+            continue;
+        };
+        let (tools, writer) = (tools.clone(), writer.clone());
+        tokio::spawn(async move {
+            let reply = match handle(&tools, &method, msg.get("params").unwrap_or(&Value::Null)).await {
+                Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                Err(err) => json!({"jsonrpc": "2.0", "id": id, "error": err}),
+            };
+            if let Err(err) = send(&writer, &reply).await {
+                tracing::warn!("reply to {method}: {err:#}");
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn handle(tools: &ToolRegistry, method: &str, params: &Value) -> Result<Value, RpcError> {
+    match method {
+        "initialize" => Ok(json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": {"tools": {}},
+            "serverInfo": {"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")},
+        })),
+        "ping" => Ok(json!({})),
+        "tools/list" => {
+            let tools = tools
+                .definitions()
+                .into_iter()
+                .map(|d| McpTool { name: d.name, description: Some(d.description), input_schema: d.input_schema })
+                .collect::<Vec<_>>();
+            Ok(json!({"tools": tools}))
+        }
+        "tools/call" => {
+            let name = params["name"].as_str().unwrap_or_default();
+            if tools.get(name).is_none() {
+                return Err(RpcError::new(RpcError::INVALID_PARAMS, format!("no tool named {name}")));
+            }
+            let arguments = match params.get("arguments") {
+                Some(Value::Null) | None => json!({}),
+                Some(arguments) => arguments.clone(),
+            };
+            tracing::info!("calling {name}");
+            let call = Content::ToolUse { id: String::new(), name: name.to_string(), input: arguments };
+            let Some(Content::ToolResult { content, is_error, .. }) = tools.dispatch(&call).await else {
+                unreachable!("tool_use blocks always have a result")
+            };
+            Ok(json!({"content": [McpContent::text(content)], "isError": is_error}))
+        }
+        _ => Err(RpcError::new(RpcError::METHOD_NOT_FOUND, format!("{method} is not supported"))),
+    }
+}
+
+async fn send(writer: &Mutex<impl AsyncWrite + Unpin>, msg: &Value) -> Result<()> {
+    let mut line = serde_json::to_vec(msg).context("serialize reply")?;
+    line.push(b'\n');
+    let mut writer = writer.lock().await;
+    writer.write_all(&line).await.context("write reply")?;
+    writer.flush().await.context("flush reply")
+}
+
+#[cfg(test)]
+mod tests {
+    use schemars::JsonSchema;
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{mcp::McpClient, tools::Tool};
+
+    #[derive(Deserialize, JsonSchema)]
+    struct ShoutInput {
+        text: String,
+    }
+
+    struct Shout;
+
+    impl Tool for Shout {
+        type Input = ShoutInput;
+
+        fn name(&self) -> &str {
+            "shout"
+        }
+
+        fn description(&self) -> &str {
+            "Shouts."
+        }
+
+        async fn call(&self, input: ShoutInput) -> Result<String> {
+            anyhow::ensure!(!input.text.is_empty(), "nothing to shout");
+            Ok(input.text.to_uppercase())
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let mut tools = ToolRegistry::new();
+        tools.register(Shout);
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (server_read, server_write) = tokio::io::split(server);
+        let serving = tokio::spawn(serve(tools, server_read, server_write));
+        let (client_read, client_write) = tokio::io::split(client);
+        let client = McpClient::connect("self", client_read, client_write).await.unwrap();
+
+        assert_eq!(client.server_info().unwrap()["name"], env!("CARGO_PKG_NAME"));
+        let listed = client.list_tools().await.unwrap();
+        assert_eq!(listed[0].name, "shout");
+        assert_eq!(listed[0].input_schema["required"], json!(["text"]));
+        assert_eq!(client.call_tool("shout", json!({"text": "hi"})).await.unwrap(), "HI");
+        let err = client.call_tool("shout", json!({"text": ""})).await.unwrap_err();
+        assert_eq!(err.to_string(), "nothing to shout");
+        let err = client.call_tool("whisper", json!({})).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<RpcError>().unwrap().code, RpcError::INVALID_PARAMS);
+
+        drop(client);
+        serving.await.unwrap().unwrap();
+    }
+}
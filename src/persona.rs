@@ -0,0 +1,228 @@
+//! Personas: a system prompt, along with the model and parameters to use it with, kept as markdown files in
+//! `~/.config/ai/personas`. A persona is named after its file, and may start with settings between `+++` lines.
+//!
+//! ```markdown
+//! +++
+//! model = "claude-3-5-sonnet-latest"
+//! temperature = 0.2
+//! +++
+//! You review code for {{user}}, who is working in {{cwd}}. Today is {{date}}.
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::config::Profile;
+
+/// Personas which are there even without a directory of them. A file of the same name takes their place.
+const BUILT_IN: &[(&str, &str)] = &[("sagan", "you are a helpful, wise modern day carl sagan.")];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Persona {
+    pub name: String,
+    /// the system prompt, before its variables are filled in
+    pub system: String,
+    pub model: Option<String>,
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<f32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Settings {
+    model: Option<String>,
+    max_tokens: Option<u32>,
+    temperature: Option<f32>,
+}
+
+impl Persona {
+    /// Reads a persona from the text of its file: optional `+++` delimited toml settings, then the system prompt.
+    pub fn parse(name: impl ToString, text: &str) -> Result<Self> {
+        let name = name.to_string();
+        let (settings, system) = match split_front_matter(text, "+++") {
+            Some((settings, system)) => {
+                let settings = toml::from_str(settings).with_context(|| format!("parse settings of persona {name}"))?;
+                (settings, system)
+            }
+            None => (Settings::default(), text),
+        };
+        let Settings { model, max_tokens, temperature } = settings;
+        Ok(Self { name, system: system.trim().to_string(), model, max_tokens, temperature })
+    }
+
+    /// The system prompt with `vars` filled in; see [`fill`].
+    pub fn system_with(&self, vars: &BTreeMap<String, String>) -> Result<String> {
+        fill(&self.system, vars).with_context(|| format!("persona {}", self.name))
+    }
+
+    /// Sets `profile`'s system prompt to the persona's, with the [`vars`] of the moment, along with whichever of
+    /// the model and parameters the persona has.
+    pub fn apply(&self, profile: &mut Profile) -> Result<()> {
+        profile.system = Some(self.system_with(&vars())?);
+        profile.model = self.model.clone().or(profile.model.take());
+        profile.max_tokens = self.max_tokens.or(profile.max_tokens);
+        profile.temperature = self.temperature.or(profile.temperature);
+        Ok(())
+    }
+}
+
+/// Splits `text` into what is between a first line of `delim` and the next, and whatever follows.
+pub fn split_front_matter<'a>(text: &'a str, delim: &str) -> Option<(&'a str, &'a str)> {
+    let rest = text.strip_prefix(delim)?;
+    let rest = rest.strip_prefix('\n').or_else(|| rest.strip_prefix("\r\n"))?;
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == delim {
+            return Some((&rest[..offset], &rest[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+    None
+}
+
+/// The variables a persona's system prompt may use: today's `date`, the `cwd` and the `user`.
+pub fn vars() -> BTreeMap<String, String> {
+    let cwd = std::env::current_dir().map(|dir| dir.display().to_string()).unwrap_or_default();
+    let user = std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_default();
+    BTreeMap::from([
+        (String::from("date"), chrono::Local::now().format("%Y-%m-%d").to_string()),
+        (String::from("cwd"), cwd),
+        (String::from("user"), user),
+    ])
+}
+
+/// Replaces each `{{name}}` in `text` with the variable of that name. A variable which isn't in `vars` is an error.
+pub fn fill(text: &str, vars: &BTreeMap<String, String>) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find("}}").with_context(|| format!("unclosed {{{{ in {:?}", &rest[start..]))?;
+        let name = rest[start + 2..start + end].trim();
+        out.push_str(vars.get(name).with_context(|| format!("unknown variable {{{{{name}}}}}"))?);
+        rest = &rest[start + end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// A directory of personas, one `<name>.md` file each.
+#[derive(Debug, Clone)]
+pub struct Personas {
+    dir: PathBuf,
+}
+
+impl Personas {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// `$AI_PERSONAS`, else `$XDG_CONFIG_HOME/ai/personas`, falling back to `~/.config/ai/personas`.
+    pub fn default_dir() -> Result<PathBuf> {
+        let var = |name| std::env::var_os(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+        if let Some(dir) = var("AI_PERSONAS") {
+            return Ok(dir);
+        }
+        let config = match var("XDG_CONFIG_HOME") {
+            Some(dir) => dir,
+            None => var("HOME").context("no HOME set")?.join(".config"),
+        };
+        Ok(config.join("ai").join("personas"))
+    }
+
+    pub fn open_default() -> Result<Self> {
+        Ok(Self::new(Self::default_dir()?))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The persona called `name`, from its file or else built in.
+    pub fn get(&self, name: &str) -> Result<Persona> {
+        anyhow::ensure!(!name.is_empty() && !name.contains(['/', '\\']), "invalid persona name {name:?}");
+        let path = self.dir.join(format!("{name}.md"));
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Persona::parse(name, &text),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BUILT_IN
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(name, system)| Persona::parse(name, system))
+                .with_context(|| format!("no persona named {name} in {}", self.dir.display()))?,
+            Err(err) => Err(err).with_context(|| format!("read {}", path.display())),
+        }
+    }
+
+    /// The names of every persona, sorted.
+    pub fn list(&self) -> Result<Vec<String>> {
+        let mut names = BUILT_IN.iter().map(|(name, _)| name.to_string()).collect::<Vec<_>>();
+        match std::fs::read_dir(&self.dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    let path = entry.with_context(|| format!("list {}", self.dir.display()))?.path();
+                    if path.extension().is_some_and(|ext| ext == "md") {
+                        names.extend(path.file_stem().map(|stem| stem.to_string_lossy().into_owned()));
+                    }
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).with_context(|| format!("list {}", self.dir.display())),
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_vars() {
+        let vars = BTreeMap::from([(String::from("user"), String::from("ada"))]);
+        assert_eq!(fill("hi {{user}}, {{ user }}!", &vars).unwrap(), "hi ada, ada!");
+        assert_eq!(fill("no vars {", &vars).unwrap(), "no vars {");
+        assert_eq!(fill("{{nope}}", &vars).unwrap_err().to_string(), "unknown variable {{nope}}");
+        assert!(fill("{{user", &vars).is_err());
+        assert_eq!(super::vars().keys().collect::<Vec<_>>(), ["cwd", "date", "user"]);
+    }
+
+    #[test]
+    fn personas() {
+        let dir = tempfile::tempdir().unwrap();
+        let personas = Personas::new(dir.path());
+        std::fs::write(
+            dir.path().join("reviewer.md"),
+            "+++\nmodel = \"claude-3-5-sonnet-latest\"\ntemperature = 0.2\n+++\nReview {{user}}'s code.\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("plain.md"), "Be brief.").unwrap();
+        std::fs::write(dir.path().join("bad.md"), "+++\nmodle = \"x\"\n+++\n").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "").unwrap();
+
+        assert_eq!(personas.list().unwrap(), ["bad", "plain", "reviewer", "sagan"]);
+        let reviewer = personas.get("reviewer").unwrap();
+        assert_eq!(reviewer.system, "Review {{user}}'s code.");
+        assert_eq!((reviewer.model.as_deref(), reviewer.temperature), (Some("claude-3-5-sonnet-latest"), Some(0.2)));
+        let vars = BTreeMap::from([(String::from("user"), String::from("ada"))]);
+        assert_eq!(reviewer.system_with(&vars).unwrap(), "Review ada's code.");
+        assert_eq!(personas.get("plain").unwrap().model, None);
+        assert!(personas.get("sagan").unwrap().system.contains("carl sagan"));
+        assert!(format!("{:#}", personas.get("bad").unwrap_err()).contains("unknown field `modle`"));
+        assert!(personas.get("nope").is_err());
+        assert!(personas.get("../reviewer").is_err());
+
+        // the persona's settings win over the profile's, and what it leaves out is kept
+        let mut profile = Profile { model: Some(String::from("claude-a")), max_tokens: Some(99), ..Default::default() };
+        reviewer.apply(&mut profile).unwrap();
+        assert_eq!(profile.model.as_deref(), Some("claude-3-5-sonnet-latest"));
+        assert_eq!((profile.max_tokens, profile.temperature), (Some(99), Some(0.2)));
+        assert!(profile.system.unwrap().starts_with("Review "));
+    }
+}
@@ -0,0 +1,590 @@
+//! An interactive chat loop with line editing, history, multiline input and slash commands.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use rustyline::{DefaultEditor, error::ReadlineError};
+use tokio::sync::Notify;
+
+use crate::{
+    anthropic::{Client, Content, Conversation, INTERRUPTED, Message, MessagesResponse, SessionStore, TextStreamEvent},
+    markdown::MarkdownRenderer,
+    persona::{self, Personas},
+};
+
+const HELP: &str = "\
+/model [name]      show or change the model
+/system [prompt]   show or change the system prompt
+/persona [name]    list the personas, or take one on
+/clear             start a new conversation
+/save [path]       save the session, or write it to a .json or .md file
+/load <id|path>    load a saved session
+/cost              show what the session has cost so far
+/retry             ask again for the last reply
+/undo              forget the last exchange
+/attach <file>     attach a file to the next message
+/help              show this help
+
+Start and end a message with \"\"\" to write several lines, or end a line with \\ to continue it.";
+
+/// Returned, inside the `anyhow::Error`, when a reply is cancelled before any of its text arrived. The message
+/// it was replying to is taken back out of the conversation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("interrupted")]
+pub struct Interrupted;
+
+/// A slash command, as typed at the prompt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Model(Option<String>),
+    System(Option<String>),
+    Persona(Option<String>),
+    Clear,
+    Save(Option<PathBuf>),
+    Load(String),
+    Cost,
+    Retry,
+    Undo,
+    Attach(PathBuf),
+    Help,
+}
+
+impl Command {
+    /// Parses a line starting with `/`.
+    pub fn parse(line: &str) -> Result<Self> {
+        let line = line.trim().strip_prefix('/').context("not a command")?;
+        let (name, arg) = match line.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, Some(arg.trim().to_string()).filter(|a| !a.is_empty())),
+            None => (line, None),
+        };
+        let required = |what: &str| arg.clone().with_context(|| format!("/{name} needs {what}"));
+        Ok(match name {
+            "model" => Self::Model(arg),
+            "system" => Self::System(arg),
+            "persona" => Self::Persona(arg),
+            "clear" => Self::Clear,
+            "save" => Self::Save(arg.map(PathBuf::from)),
+            "load" => Self::Load(required("a session id or path")?),
+            "cost" => Self::Cost,
+            "retry" => Self::Retry,
+            "undo" => Self::Undo,
+            "attach" => Self::Attach(PathBuf::from(required("a file")?)),
+            "help" => Self::Help,
+            _ => anyhow::bail!("unknown command /{name}, try /help"),
+        })
+    }
+}
+
+/// Puts together input spanning several lines: lines ending in `\`, or everything between a pair of `"""`.
+#[derive(Debug, Default)]
+pub struct Multiline {
+    lines: Vec<String>,
+    quoted: bool,
+}
+
+impl Multiline {
+    /// Takes the next line, returning the whole input once it is complete.
+    pub fn feed(&mut self, line: &str) -> Option<String> {
+        if self.quoted {
+            match line.trim_end().strip_suffix(r#"""""#) {
+                Some(last) => {
+                    self.lines.push(last.to_string());
+                    return Some(self.take());
+                }
+                None => self.lines.push(line.to_string()),
+            }
+            return None;
+        }
+        if let Some(rest) = line.trim_start().strip_prefix(r#"""""#) {
+            if let Some(text) = rest.trim_end().strip_suffix(r#"""""#) {
+                self.lines.push(text.to_string());
+                return Some(self.take());
+            }
+            self.quoted = true;
+            if !rest.trim().is_empty() {
+                self.lines.push(rest.to_string());
+            }
+            return None;
+        }
+        match line.strip_suffix('\\') {
+            Some(start) => {
+                self.lines.push(start.to_string());
+                None
+            }
+            None => {
+                self.lines.push(line.to_string());
+                Some(self.take())
+            }
+        }
+    }
+
+    /// Whether a line has been started but not finished.
+    pub fn is_pending(&self) -> bool {
+        self.quoted || !self.lines.is_empty()
+    }
+
+    /// Throws away whatever has been fed so far.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    fn take(&mut self) -> String {
+        let text = self.lines.join("\n");
+        self.clear();
+        text
+    }
+}
+
+/// A chat with the model at the terminal. Each reply is streamed to the event handler, by default printing its
+/// text to stdout, and the session is saved after every turn if there is a store to save it in.
+///
+/// Ctrl-C while the model is replying drops the request, keeping the text received so far as an interrupted turn
+/// (see [`Conversation::push_interrupted`]). Pressing it again at an empty prompt exits.
+pub struct Repl {
+    client: Client,
+    /// the client as given, which each persona's parameters are applied to afresh
+    base: Client,
+    conv: Conversation,
+    store: Option<SessionStore>,
+    personas: Option<Personas>,
+    /// the persona last taken on, if any
+    persona: Option<String>,
+    history: Option<PathBuf>,
+    /// files attached to the next message
+    attachments: Vec<Content>,
+    on_event: Box<dyn FnMut(&TextStreamEvent) + Send>,
+    cancel: Arc<Notify>,
+    /// whether the last thing to happen was an interrupt, so that another one at the prompt exits
+    interrupted: bool,
+}
+
+impl Repl {
+    pub fn new(client: Client, conv: Conversation) -> Self {
+        Self {
+            base: client.clone(),
+            client,
+            conv,
+            store: None,
+            personas: None,
+            persona: None,
+            history: None,
+            attachments: vec![],
+            on_event: Box::new(print_markdown()),
+            cancel: Arc::new(Notify::new()),
+            interrupted: false,
+        }
+    }
+
+    /// Saves the session to `store` after each turn, and loads sessions by id from it.
+    pub fn with_store(mut self, store: SessionStore) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Offers the personas of `personas` to `/persona`. `current` is the one already in use, if any.
+    pub fn with_personas(mut self, personas: Personas, current: Option<String>) -> Self {
+        self.personas = Some(personas);
+        self.persona = current;
+        self
+    }
+
+    /// Keeps the prompt's history in `path` across runs.
+    pub fn with_history(mut self, path: impl Into<PathBuf>) -> Self {
+        self.history = Some(path.into());
+        self
+    }
+
+    /// Handles each event of a streaming reply instead of printing the reply's text.
+    pub fn with_on_event(mut self, on_event: impl FnMut(&TextStreamEvent) + Send + 'static) -> Self {
+        self.on_event = Box::new(on_event);
+        self
+    }
+
+    /// `$XDG_STATE_HOME/ai/history`, falling back to `~/.local/state/ai/history`.
+    pub fn default_history() -> Option<PathBuf> {
+        let state = match std::env::var_os("XDG_STATE_HOME").filter(|v| !v.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+        };
+        Some(state.join("ai").join("history"))
+    }
+
+    /// Cancels the reply in flight, if any, when notified with `notify_waiters`, just as Ctrl-C does.
+    pub fn cancel_handle(&self) -> Arc<Notify> {
+        self.cancel.clone()
+    }
+
+    pub fn conversation(&self) -> &Conversation {
+        &self.conv
+    }
+
+    /// Reads and handles input until end of file. Errors from a turn or a command are reported and the loop
+    /// carries on. Reading the prompt blocks, so this needs a multi-threaded runtime.
+    pub async fn run(&mut self) -> Result<()> {
+        let mut editor = DefaultEditor::new().context("set up line editor")?;
+        if let Some(path) = &self.history {
+            if let Err(err) = editor.load_history(path) {
+                if !matches!(&err, ReadlineError::Io(err) if err.kind() == std::io::ErrorKind::NotFound) {
+                    tracing::warn!("load history from {}: {err}", path.display());
+                }
+            }
+        }
+        let mut multiline = Multiline::default();
+        loop {
+            let prompt = if multiline.is_pending() { "... " } else { "> " };
+            let line = match tokio::task::block_in_place(|| editor.readline(prompt)) {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) if multiline.is_pending() => {
+                    multiline.clear();
+                    continue;
+                }
+                Err(ReadlineError::Interrupted) if self.interrupted => break,
+                Err(ReadlineError::Interrupted) => {
+                    self.interrupted = true;
+                    eprintln!("(press Ctrl-C again or Ctrl-D to exit)");
+                    continue;
+                }
+                Err(ReadlineError::Eof) => break,
+                Err(err) => return Err(err).context("read input"),
+            };
+            self.interrupted = false;
+            let Some(input) = multiline.feed(&line) else { continue };
+            if input.trim().is_empty() {
+                continue;
+            }
+            let _ = editor.add_history_entry(input.as_str());
+            if let Some(path) = &self.history {
+                if let Some(dir) = path.parent() {
+                    let _ = std::fs::create_dir_all(dir);
+                }
+                if let Err(err) = editor.append_history(path) {
+                    tracing::warn!("save history to {}: {err}", path.display());
+                }
+            }
+            match self.handle(&input).await {
+                Err(err) if err.is::<Interrupted>() => {}
+                Err(err) => eprintln!("error: {err:#}"),
+                Ok(()) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a complete input: a slash command, or a message for the model.
+    pub async fn handle(&mut self, input: &str) -> Result<()> {
+        if input.trim_start().starts_with('/') {
+            return self.command(Command::parse(input)?).await;
+        }
+        let mut content = self.attachments.clone();
+        content.push(Content::text(input.trim()));
+        self.conv.messages.push(Message { role: String::from("user"), content });
+        if let Err(err) = self.reply().await {
+            self.conv.pop_user();
+            return Err(err);
+        }
+        self.attachments.clear();
+        Ok(())
+    }
+
+    pub async fn command(&mut self, cmd: Command) -> Result<()> {
+        match cmd {
+            Command::Model(None) => eprintln!("{}", self.conv.model),
+            Command::Model(Some(model)) => self.conv.model = model,
+            Command::System(None) => eprintln!("{}", self.conv.system.as_deref().unwrap_or("(none)")),
+            Command::System(Some(system)) => self.conv.system = Some(system),
+            Command::Persona(None) => {
+                let personas = self.personas.as_ref().context("no personas to choose from")?;
+                for name in personas.list()? {
+                    let current = if self.persona.as_ref() == Some(&name) { " (current)" } else { "" };
+                    eprintln!("{name}{current}");
+                }
+            }
+            Command::Persona(Some(name)) => {
+                let personas = self.personas.as_ref().context("no personas to choose from")?;
+                let persona = personas.get(&name)?;
+                self.conv.system = Some(persona.system_with(&persona::vars())?);
+                self.conv.model = persona.model.clone().unwrap_or_else(|| self.base.model().to_string());
+                let mut client = self.base.clone();
+                if let Some(max_tokens) = persona.max_tokens {
+                    client = client.with_max_tokens(max_tokens);
+                }
+                if let Some(temperature) = persona.temperature {
+                    client = client.with_temperature(temperature);
+                }
+                self.client = client;
+                eprintln!("now {name}, using {}", self.conv.model);
+                self.persona = Some(name);
+            }
+            Command::Clear => {
+                self.conv = Conversation::new(&self.conv.model, self.conv.system.clone());
+                self.attachments.clear();
+                eprintln!("session {}", self.conv.id);
+            }
+            Command::Save(None) => {
+                let store = self.store.as_ref().context("nowhere to save sessions; give /save a path")?;
+                let path = store.save(&self.conv).await?;
+                eprintln!("saved to {}", path.display());
+            }
+            Command::Save(Some(path)) => {
+                let text = if path.extension().is_some_and(|ext| ext == "md") {
+                    self.conv.markdown()
+                } else {
+                    serde_json::to_string_pretty(&self.conv).context("serialize conversation")?
+                };
+                tokio::fs::write(&path, text).await.with_context(|| format!("write {}", path.display()))?;
+                eprintln!("saved to {}", path.display());
+            }
+            Command::Load(id) => {
+                self.conv = match &self.store {
+                    Some(store) => store.load(&id).await?,
+                    None => load(Path::new(&id)).await?,
+                };
+                self.attachments.clear();
+                eprintln!("session {}: {} messages", self.conv.id, self.conv.messages.len());
+            }
+            Command::Cost => {
+                let usage = self.conv.usage();
+                eprintln!(
+                    "{} over {} turns: {} input and {} output tokens",
+                    self.conv.cost(),
+                    self.conv.turns.len(),
+                    usage.input_tokens,
+                    usage.output_tokens
+                );
+            }
+            Command::Retry => {
+                // what was spent on the reply being replaced still counts towards the session's cost
+                anyhow::ensure!(self.conv.messages.last().is_some_and(|m| m.role == "assistant"), "no reply to retry");
+                let reply = self.conv.messages.pop();
+                if let Err(err) = self.reply().await {
+                    self.conv.messages.extend(reply);
+                    return Err(err);
+                }
+            }
+            Command::Undo => {
+                let turns = self.conv.turn_starts().len();
+                anyhow::ensure!(turns > 0, "nothing to undo");
+                self.conv.drop_turns(turns - 1..turns);
+                self.save().await?;
+            }
+            Command::Attach(path) => {
+                let content = Content::file(&path).await?;
+                self.attachments.extend(content);
+                eprintln!("attached {} to the next message", path.display());
+            }
+            Command::Help => eprintln!("{HELP}"),
+        }
+        Ok(())
+    }
+
+    /// Has the model reply to the conversation, which ends with a user message, and saves the session. Dropping
+    /// the reply on an interrupt closes the connection, stopping the generation.
+    async fn reply(&mut self) -> Result<()> {
+        let on_event = &mut self.on_event;
+        let mut partial = String::new();
+        let cancel = self.cancel.clone();
+        let reply = self.client.stream_reply_with(&mut self.conv, &[], |ev| {
+            if let TextStreamEvent::Fragment(s) = ev {
+                partial.push_str(s);
+            }
+            on_event(ev)
+        });
+        let done = tokio::select! {
+            res = reply => Some(res),
+            _ = tokio::signal::ctrl_c() => None,
+            _ = cancel.notified() => None,
+        };
+        match done {
+            Some(res) => _ = res?,
+            None => {
+                // let the handler finish off what it printed
+                let resp = MessagesResponse {
+                    content: vec![Content::text(&partial)],
+                    model: self.conv.model.clone(),
+                    role: String::from("assistant"),
+                    stop_reason: Some(String::from(INTERRUPTED)),
+                    ..Default::default()
+                };
+                (self.on_event)(&TextStreamEvent::Eof(resp));
+                self.interrupted = true;
+                eprintln!("[interrupted]");
+                if partial.trim().is_empty() {
+                    return Err(Interrupted.into());
+                }
+                self.conv.push_interrupted(partial.trim_end());
+            }
+        }
+        self.save().await
+    }
+
+    async fn save(&self) -> Result<()> {
+        if let Some(store) = &self.store {
+            store.save(&self.conv).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints the text of each reply as it streams in, rendering its markdown if stdout is a terminal.
+pub fn print_markdown() -> impl FnMut(&TextStreamEvent) + Send {
+    let mut renderer = MarkdownRenderer::stdout();
+    move |ev| {
+        let res = match ev {
+            TextStreamEvent::Fragment(s) => renderer.push(s),
+            TextStreamEvent::Eof(_) => renderer.finish().map(|()| println!()),
+            _ => Ok(()),
+        };
+        if let Err(err) = res {
+            tracing::warn!("print reply: {err}");
+        }
+    }
+}
+
+async fn load(path: &Path) -> Result<Conversation> {
+    let bs = tokio::fs::read(path).await.with_context(|| format!("read session {}", path.display()))?;
+    serde_json::from_slice(&bs).with_context(|| format!("parse session {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::anthropic::mock::{MockResponse, MockServer};
+
+    #[test]
+    fn parse() {
+        assert_eq!(Command::parse("/model").unwrap(), Command::Model(None));
+        assert_eq!(Command::parse(" /system  be brief ").unwrap(), Command::System(Some(String::from("be brief"))));
+        assert_eq!(Command::parse("/persona").unwrap(), Command::Persona(None));
+        assert_eq!(Command::parse("/attach a.rs").unwrap(), Command::Attach(PathBuf::from("a.rs")));
+        assert_eq!(Command::parse("/attach").unwrap_err().to_string(), "/attach needs a file");
+        assert_eq!(Command::parse("/nope").unwrap_err().to_string(), "unknown command /nope, try /help");
+    }
+
+    #[test]
+    fn multiline() {
+        let mut m = Multiline::default();
+        assert_eq!(m.feed("one"), Some(String::from("one")));
+        assert_eq!(m.feed("one \\"), None);
+        assert!(m.is_pending());
+        assert_eq!(m.feed("two"), Some(String::from("one \ntwo")));
+        assert_eq!(m.feed(r#""""first"#), None);
+        assert_eq!(m.feed("second \\"), None);
+        assert_eq!(m.feed(r#"third""""#), Some(String::from("first\nsecond \\\nthird")));
+        assert_eq!(m.feed(r#""""inline""""#), Some(String::from("inline")));
+        assert!(!m.is_pending());
+    }
+
+    #[tokio::test]
+    async fn commands() {
+        let server = MockServer::start().await.unwrap();
+        let client = Client::new("key").unwrap().with_endpoint(server.url());
+        let dir = tempfile::tempdir().unwrap();
+        let text = Arc::new(Mutex::new(String::new()));
+        let mut repl = Repl::new(client.clone(), Conversation::new(client.model(), None))
+            .with_store(SessionStore::new(dir.path()))
+            .with_personas(Personas::new(dir.path().join("personas")), None)
+            .with_on_event({
+                let text = text.clone();
+                move |ev| {
+                    if let TextStreamEvent::Fragment(s) = ev {
+                        text.lock().unwrap().push_str(s);
+                    }
+                }
+            });
+
+        std::fs::create_dir(dir.path().join("personas")).unwrap();
+        std::fs::write(
+            dir.path().join("personas/terse.md"),
+            "+++\nmodel = \"claude-3-5-sonnet-latest\"\nmax_tokens = 50\ntemperature = 0.2\n+++\nBe terse.\n",
+        )
+        .unwrap();
+        repl.handle("/persona terse").await.unwrap();
+        assert!(repl.handle("/persona nope").await.is_err());
+        repl.handle("/model claude-test").await.unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "some notes").unwrap();
+        repl.handle(&format!("/attach {}", dir.path().join("notes.txt").display())).await.unwrap();
+        server.push(MockResponse::stream("first"));
+        repl.handle("summarize").await.unwrap();
+        server.push(MockResponse::stream("second"));
+        repl.handle("/retry").await.unwrap();
+        assert_eq!(*text.lock().unwrap(), "firstsecond");
+
+        let requests = server.requests();
+        assert_eq!(requests[0].body["model"], "claude-test");
+        assert_eq!((&requests[0].body["system"], &requests[0].body["max_tokens"]), (&json!("Be terse."), &json!(50)));
+        let content = &requests[0].body["messages"][0]["content"];
+        assert!(content[0]["text"].as_str().unwrap().contains("some notes"), "{content}");
+        assert_eq!(content[1]["text"], "summarize");
+        assert_eq!(requests[1].body["messages"], requests[0].body["messages"]);
+        assert_eq!(repl.conversation().messages.len(), 2);
+        assert_eq!(repl.conversation().messages[1].content[0].to_string(), "second");
+
+        // the attachment went with the first message only, and the session was saved
+        server.push(MockResponse::stream("third"));
+        repl.handle("and again").await.unwrap();
+        assert_eq!(server.requests()[2].body["messages"][2]["content"].as_array().unwrap().len(), 1);
+        let id = repl.conversation().id.clone();
+        repl.handle("/undo").await.unwrap();
+        assert_eq!(repl.conversation().messages.len(), 2);
+        repl.handle("/clear").await.unwrap();
+        assert!(repl.conversation().messages.is_empty());
+        repl.handle(&format!("/load {id}")).await.unwrap();
+        assert_eq!(repl.conversation().messages.len(), 2);
+        assert!(repl.handle("/undo").await.is_ok());
+        assert!(repl.handle("/undo").await.is_err());
+
+        // another persona doesn't keep the last one's parameters
+        std::fs::write(dir.path().join("personas/plain.md"), "Be plain.").unwrap();
+        repl.handle("/persona plain").await.unwrap();
+        server.push(MockResponse::stream("fourth"));
+        repl.handle("hi").await.unwrap();
+        let last = server.requests().pop().unwrap();
+        assert_eq!((&last.body["system"], &last.body["max_tokens"]), (&json!("Be plain."), &json!(client.max_tokens())));
+        assert_eq!(last.body["model"], client.model());
+        assert!(last.body.get("temperature").is_none());
+    }
+
+    #[tokio::test]
+    async fn interrupt() {
+        let server = MockServer::start().await.unwrap();
+        let client = Client::new("key").unwrap().with_endpoint(server.url());
+        let mut repl = Repl::new(client.clone(), Conversation::new(client.model(), None));
+        let cancel = repl.cancel_handle();
+        repl = repl.with_on_event({
+            let cancel = cancel.clone();
+            move |ev| {
+                if matches!(ev, TextStreamEvent::Fragment(s) if s.ends_with("fo")) {
+                    cancel.notify_waiters();
+                }
+            }
+        });
+
+        let text = "one two three four five six seven eight nine ten eleven twelve";
+        server.push(MockResponse::stream(text).with_event_delay(Duration::from_millis(20)));
+        repl.handle("count").await.unwrap();
+        let conv = repl.conversation();
+        assert_eq!(conv.messages[1].content[0].to_string(), "one two three fo");
+        assert_eq!(conv.turns[0].stop_reason.as_deref(), Some(INTERRUPTED));
+
+        // with nothing to keep, the question is taken back
+        server.push(MockResponse::stream("late").with_delay(Duration::from_secs(5)));
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cancel.notify_waiters();
+        });
+        let err = repl.handle("again").await.unwrap_err();
+        assert!(err.is::<Interrupted>());
+        assert_eq!(repl.conversation().messages.len(), 2);
+    }
+}
@@ -0,0 +1,135 @@
+//! Keeping api keys out of logs and memory dumps, and encrypting them at rest with a passphrase.
+
+use std::{fmt, num::NonZeroU32};
+
+use anyhow::{Context, Result};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use zeroize::Zeroize;
+
+/// A string which is redacted when formatted and zeroed when dropped.
+#[derive(Clone)]
+pub struct SecretString(String);
+
+impl SecretString {
+    /// Takes `s` without its surrounding whitespace, zeroing whatever was trimmed off along with the original.
+    pub fn new(mut s: String) -> Self {
+        let trimmed = s.trim().to_string();
+        s.zeroize();
+        Self(trimmed)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(s: String) -> Self {
+        Self::new(s)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(s: &str) -> Self {
+        Self::new(s.to_string())
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString([redacted])")
+    }
+}
+
+impl fmt::Display for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+const HEADER: &str = "ai-secret v1 pbkdf2-sha256 chacha20-poly1305";
+const ITERATIONS: u32 = 600_000;
+
+/// Encrypts `secret` with a key derived from `passphrase`, as text that [`decrypt`] turns back into the secret.
+pub fn encrypt(secret: &SecretString, passphrase: &SecretString) -> Result<String> {
+    encrypt_with(secret, passphrase, ITERATIONS)
+}
+
+fn encrypt_with(secret: &SecretString, passphrase: &SecretString, iterations: u32) -> Result<String> {
+    let rng = SystemRandom::new();
+    let mut salt = [0; 16];
+    let mut nonce = [0; aead::NONCE_LEN];
+    rng.fill(&mut salt).ok().context("generate salt")?;
+    rng.fill(&mut nonce).ok().context("generate nonce")?;
+    let key = derive_key(passphrase, &salt, iterations)?;
+    let mut data = secret.expose().as_bytes().to_vec();
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(HEADER), &mut data).ok().context("encrypt")?;
+    let encoded = [&salt[..], &nonce[..], &data].map(|bs| BASE64_STANDARD.encode(bs));
+    Ok(format!("{HEADER}\n{iterations}\n{}\n", encoded.join("\n")))
+}
+
+/// Decrypts what [`encrypt`] produced.
+pub fn decrypt(text: &str, passphrase: &SecretString) -> Result<SecretString> {
+    let mut lines = text.lines().map(str::trim);
+    anyhow::ensure!(lines.next() == Some(HEADER), "not an encrypted secret");
+    let iterations = lines.next().and_then(|l| l.parse().ok()).context("no iteration count")?;
+    let mut next = |what| {
+        let line = lines.next().with_context(|| format!("no {what}"))?;
+        BASE64_STANDARD.decode(line).with_context(|| format!("decode {what}"))
+    };
+    let (salt, nonce, mut data) = (next("salt")?, next("nonce")?, next("ciphertext")?);
+    let nonce = Nonce::try_assume_unique_for_key(&nonce).ok().context("invalid nonce")?;
+    let key = derive_key(passphrase, &salt, iterations)?;
+    let plain = key.open_in_place(nonce, Aad::from(HEADER), &mut data).ok().context("wrong passphrase")?;
+    let secret = String::from_utf8(plain.to_vec()).context("secret is not utf-8");
+    data.zeroize();
+    Ok(SecretString::new(secret?))
+}
+
+fn derive_key(passphrase: &SecretString, salt: &[u8], iterations: u32) -> Result<LessSafeKey> {
+    let iterations = NonZeroU32::new(iterations).context("zero iterations")?;
+    let mut key = [0; 32];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.expose().as_bytes(), &mut key);
+    let unbound = UnboundKey::new(&aead::CHACHA20_POLY1305, &key).ok().context("derive key");
+    key.zeroize();
+    Ok(LessSafeKey::new(unbound?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacted() {
+        let key = SecretString::from(" sk-ant-123\n");
+        assert_eq!(key.expose(), "sk-ant-123");
+        assert_eq!(format!("{key} {key:?}"), "[redacted] SecretString([redacted])");
+    }
+
+    #[test]
+    fn encryption() {
+        let (key, passphrase) = (SecretString::from("sk-ant-123"), SecretString::from("hunter2"));
+        let text = encrypt_with(&key, &passphrase, 1000).unwrap();
+        assert!(!text.contains("sk-ant"));
+        assert_eq!(decrypt(&text, &passphrase).unwrap().expose(), "sk-ant-123");
+        let err = decrypt(&text, &SecretString::from("hunter3")).unwrap_err();
+        assert_eq!(err.to_string(), "wrong passphrase");
+        // the same secret encrypts differently every time
+        assert_ne!(text, encrypt_with(&key, &passphrase, 1000).unwrap());
+    }
+}
@@ -0,0 +1,668 @@
+//! Prompt templates: markdown files which, given their variables, render to a request for the messages api.
+//!
+//! A template may start with `+++` delimited toml settings: the model and parameters to use, and the variables it
+//! takes, each a `string`, `number`, `bool` or `list`. A variable without a default is required, as is any the body
+//! uses without declaring it. `{{date}}`, `{{cwd}}` and `{{user}}` are always there.
+//!
+//! The body fills in `{{name}}`, or `{{this}}` and `{{this.field}}` inside `{{#each list}}`, and may use
+//! `{{#if name}}`, `{{#unless name}}` and `{{else}}`, `{{> path}}` to include another file, and `{{! comments }}`.
+//! `{{#system}}`, `{{#user}}` and `{{#assistant}}` blocks make up the turns, e.g. for few-shot examples; without
+//! them the whole body is a single user message.
+//!
+//! ```markdown
+//! +++
+//! model = "claude-3-5-sonnet-latest"
+//! max_tokens = 512
+//!
+//! [vars.language]
+//! [vars.context]
+//! default = ""
+//! [vars.examples]
+//! type = "list"
+//! default = [{ question = "2 + 2?", answer = "4" }]
+//! +++
+//! {{#system}}Answer in {{language}}. Today is {{date}}.{{/system}}
+//! {{#each examples}}
+//! {{#user}}{{this.question}}{{/user}}
+//! {{#assistant}}{{this.answer}}{{/assistant}}
+//! {{/each}}
+//! {{#user}}
+//! {{> question.md}}
+//! {{#if context}}Some context: {{context}}{{/if}}
+//! {{/user}}
+//! ```
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    anthropic::{Client, Content, Message, MessagesRequest},
+    persona::{self, split_front_matter},
+};
+
+/// How deeply includes may nest, which stops a template including itself forever.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+static NULL: Value = Value::Null;
+
+/// Returned, inside the `anyhow::Error`, when a template is rendered without some of the variables it requires.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("missing variables: {}", .0.join(", "))]
+pub struct MissingVars(pub Vec<String>);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VarType {
+    #[default]
+    String,
+    Number,
+    Bool,
+    List,
+}
+
+/// A variable a template declares.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Var {
+    #[serde(rename = "type")]
+    pub typ: VarType,
+    /// the value when none is given; a variable without one is required
+    pub default: Option<Value>,
+    pub description: Option<String>,
+}
+
+/// A template's front-matter. Anything left out keeps the client's default.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub model: Option<String>,
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<f32>,
+    pub vars: BTreeMap<String, Var>,
+}
+
+impl VarType {
+    /// Parses a value as given on the command line. A list is comma separated, or else a json array.
+    pub fn parse(self, text: &str) -> Result<Value> {
+        match self {
+            Self::String => Ok(Value::from(text)),
+            Self::Number => {
+                let text = text.trim();
+                if let Ok(n) = text.parse::<i64>() {
+                    return Ok(Value::from(n));
+                }
+                let n = text.parse::<f64>().ok().filter(|n| n.is_finite());
+                Ok(Value::from(n.with_context(|| format!("{text:?} is not a number"))?))
+            }
+            Self::Bool => match text.trim() {
+                "true" | "yes" | "1" => Ok(Value::Bool(true)),
+                "false" | "no" | "0" => Ok(Value::Bool(false)),
+                text => anyhow::bail!("{text:?} is not true or false"),
+            },
+            Self::List if text.trim_start().starts_with('[') => {
+                Ok(Value::Array(serde_json::from_str(text).context("parse json list")?))
+            }
+            Self::List => Ok(text.split(',').map(str::trim).filter(|s| !s.is_empty()).collect()),
+        }
+    }
+
+    fn accepts(self, value: &Value) -> bool {
+        match self {
+            Self::String => value.is_string(),
+            Self::Number => value.is_number(),
+            Self::Bool => value.is_boolean(),
+            Self::List => value.is_array(),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::String => "string",
+            Self::Number => "number",
+            Self::Bool => "bool",
+            Self::List => "list",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Var(String),
+    If { path: String, negate: bool, then: Vec<Node>, otherwise: Vec<Node> },
+    Each { path: String, body: Vec<Node> },
+    Turn { role: Role, body: Vec<Node> },
+}
+
+#[derive(Debug, Clone)]
+pub struct Template {
+    pub settings: Settings,
+    body: Vec<Node>,
+    /// every variable the body refers to, declared or not
+    used: BTreeSet<String>,
+}
+
+impl Template {
+    /// Parses the text of a template, reading any files it includes from `dir`.
+    pub fn parse(text: &str, dir: impl AsRef<Path>) -> Result<Self> {
+        let (settings, body) = match split_front_matter(text, "+++") {
+            Some((settings, body)) => (toml::from_str::<Settings>(settings).context("parse settings")?, body),
+            None => (Settings::default(), text),
+        };
+        for (name, var) in &settings.vars {
+            if let Some(default) = &var.default {
+                anyhow::ensure!(var.typ.accepts(default), "the default of {name} is not a {}", var.typ.name());
+            }
+        }
+        let body = parse_body(body, dir.as_ref(), 0)?;
+        let mut used = BTreeSet::new();
+        collect_vars(&body, &mut used);
+        Ok(Self { settings, body, used })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        Self::parse(&text, path.parent().unwrap_or(Path::new("."))).with_context(|| format!("template {}", path.display()))
+    }
+
+    /// The value of every variable, from those `given`, the declared defaults and the ones always there. Given
+    /// values are parsed as their declared type; undeclared ones are strings.
+    pub fn vars(&self, given: &BTreeMap<String, String>) -> Result<BTreeMap<String, Value>> {
+        if let Some(name) = given.keys().find(|name| !self.settings.vars.contains_key(*name) && !self.used.contains(*name)) {
+            anyhow::bail!("unknown variable {name}");
+        }
+        let mut vars = persona::vars().into_iter().map(|(name, value)| (name, Value::from(value))).collect::<BTreeMap<_, _>>();
+        let mut missing = vec![];
+        for (name, var) in &self.settings.vars {
+            let value = match (given.get(name), &var.default) {
+                (Some(text), _) => var.typ.parse(text).with_context(|| format!("variable {name}"))?,
+                (None, Some(default)) => default.clone(),
+                (None, None) => {
+                    missing.push(name.clone());
+                    continue;
+                }
+            };
+            vars.insert(name.clone(), value);
+        }
+        for name in self.used.iter().filter(|name| !self.settings.vars.contains_key(*name)) {
+            match given.get(name) {
+                Some(text) => {
+                    vars.insert(name.clone(), Value::from(text.as_str()));
+                }
+                None if vars.contains_key(name) => {}
+                None => missing.push(name.clone()),
+            }
+        }
+        if !missing.is_empty() {
+            missing.sort();
+            return Err(MissingVars(missing).into());
+        }
+        Ok(vars)
+    }
+
+    /// Renders the system prompt and the turns, with the variables `given`.
+    pub fn render_messages(&self, given: &BTreeMap<String, String>) -> Result<(Option<String>, Vec<Message>)> {
+        let vars = self.vars(given)?;
+        let mut renderer = Renderer { vars: &vars, scope: vec![], role: None, out: vec![] };
+        renderer.render(&self.body)?;
+        messages(renderer.out)
+    }
+
+    /// Renders a request with the variables `given`, taking whatever settings the template leaves out from `client`.
+    pub fn render(&self, client: &Client, given: &BTreeMap<String, String>) -> Result<MessagesRequest> {
+        let (system, messages) = self.render_messages(given)?;
+        Ok(MessagesRequest {
+            model: self.settings.model.clone().unwrap_or_else(|| client.model().to_string()),
+            max_tokens: self.settings.max_tokens.unwrap_or(client.max_tokens()),
+            system,
+            messages,
+            temperature: self.settings.temperature.or(client.temperature()),
+            ..Default::default()
+        })
+    }
+}
+
+/// A tag, without its braces, and the line it starts on.
+type Tag = (String, usize);
+
+/// Splits `text` into the text between tags, and the tags along with the line each starts on. There is always one
+/// more text than there are tags.
+fn lex(text: &str) -> Result<(Vec<String>, Vec<Tag>)> {
+    let (mut texts, mut tags) = (vec![], vec![]);
+    let mut rest = text;
+    let mut line = 1;
+    while let Some(start) = rest.find("{{") {
+        let (before, after) = rest.split_at(start);
+        texts.push(before.to_string());
+        line += before.matches('\n').count();
+        let end = after.find("}}").with_context(|| format!("line {line}: unclosed {{{{"))?;
+        let tag = &after[2..end];
+        tags.push((tag.trim().to_string(), line));
+        line += tag.matches('\n').count();
+        rest = &after[end + 2..];
+    }
+    texts.push(rest.to_string());
+    strip_standalone(&mut texts, &tags);
+    Ok((texts, tags))
+}
+
+/// Takes out the lines of block tags which are alone on their line, so that they don't leave blank lines behind.
+fn strip_standalone(texts: &mut [String], tags: &[Tag]) {
+    let blank = |s: &str| s.chars().all(|c| matches!(c, ' ' | '\t' | '\r'));
+    let last = texts.len() - 1;
+    let standalone = tags
+        .iter()
+        .enumerate()
+        .map(|(i, (tag, _))| {
+            let block = tag == "else" || tag.starts_with(['#', '/', '>', '!']);
+            let before = match texts[i].rfind('\n') {
+                Some(nl) => blank(&texts[i][nl + 1..]),
+                None => i == 0 && blank(&texts[i]),
+            };
+            let after = match texts[i + 1].find('\n') {
+                Some(nl) => blank(&texts[i + 1][..nl]),
+                None => i + 1 == last && blank(&texts[i + 1]),
+            };
+            block && before && after
+        })
+        .collect::<Vec<_>>();
+    for (i, text) in texts.iter_mut().enumerate() {
+        let start = match i.checked_sub(1).is_some_and(|tag| standalone[tag]) {
+            true => text.find('\n').map_or(text.len(), |nl| nl + 1),
+            false => 0,
+        };
+        let end = match standalone.get(i) {
+            Some(true) => text.rfind('\n').map_or(0, |nl| nl + 1),
+            _ => text.len(),
+        };
+        *text = text.get(start..end.max(start)).unwrap_or_default().to_string();
+    }
+}
+
+fn parse_body(text: &str, dir: &Path, depth: usize) -> Result<Vec<Node>> {
+    let (texts, tags) = lex(text)?;
+    let mut parser = Parser { texts, tags, pos: 0, dir, depth };
+    Ok(parser.until(None, &[])?.0)
+}
+
+struct Parser<'a> {
+    texts: Vec<String>,
+    tags: Vec<Tag>,
+    pos: usize,
+    dir: &'a Path,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    /// Parses nodes up to one of the tags in `stop`, which it returns, or to the end if `opened` is `None`.
+    /// `opened` is the tag, and its line, which the nodes are inside of.
+    fn until(&mut self, opened: Option<(&str, usize)>, stop: &[&str]) -> Result<(Vec<Node>, String)> {
+        let mut nodes = vec![];
+        loop {
+            let text = std::mem::take(&mut self.texts[self.pos]);
+            if !text.is_empty() {
+                nodes.push(Node::Text(text));
+            }
+            let Some((tag, line)) = self.tags.get(self.pos).cloned() else {
+                return match opened {
+                    Some((open, line)) => anyhow::bail!("line {line}: {{{{{open}}}}} is never closed"),
+                    None => Ok((nodes, String::new())),
+                };
+            };
+            self.pos += 1;
+            if stop.contains(&tag.as_str()) {
+                return Ok((nodes, tag));
+            }
+            let (head, arg) = match tag.split_once(char::is_whitespace) {
+                Some((head, arg)) => (head, arg.trim()),
+                None => (tag.as_str(), ""),
+            };
+            let node = match head {
+                _ if tag.starts_with('!') => continue,
+                _ if tag.starts_with('>') => {
+                    nodes.extend(self.include(tag[1..].trim(), line)?);
+                    continue;
+                }
+                "#if" | "#unless" => {
+                    let path = var_path(arg, line)?;
+                    let close = format!("/{}", &head[1..]);
+                    let (then, end) = self.until(Some((&tag, line)), &["else", &close])?;
+                    let otherwise = match end.as_str() {
+                        "else" => self.until(Some((&tag, line)), &[&close])?.0,
+                        _ => vec![],
+                    };
+                    Node::If { path, negate: head == "#unless", then, otherwise }
+                }
+                "#each" => {
+                    let path = var_path(arg, line)?;
+                    Node::Each { path, body: self.until(Some((&tag, line)), &["/each"])?.0 }
+                }
+                "#system" | "#user" | "#assistant" if arg.is_empty() => {
+                    let role = match head {
+                        "#system" => Role::System,
+                        "#user" => Role::User,
+                        _ => Role::Assistant,
+                    };
+                    let close = format!("/{}", &head[1..]);
+                    let body = self.until(Some((&tag, line)), &[&close])?.0;
+                    anyhow::ensure!(!has_turn(&body), "line {line}: {{{{{tag}}}}} has another turn inside it");
+                    Node::Turn { role, body }
+                }
+                _ if head.starts_with(['#', '/']) || head == "else" => {
+                    anyhow::bail!("line {line}: unexpected {{{{{tag}}}}}")
+                }
+                _ => Node::Var(var_path(&tag, line)?),
+            };
+            nodes.push(node);
+        }
+    }
+
+    /// Parses the file at `path`, relative to the directory of the file being parsed. Its blocks must be closed
+    /// within it.
+    fn include(&self, path: &str, line: usize) -> Result<Vec<Node>> {
+        anyhow::ensure!(!path.is_empty(), "line {line}: {{{{>}}}} needs a path");
+        anyhow::ensure!(self.depth < MAX_INCLUDE_DEPTH, "line {line}: includes nest too deeply");
+        let path = self.dir.join(path);
+        let text = std::fs::read_to_string(&path).with_context(|| format!("line {line}: read {}", path.display()))?;
+        let dir = path.parent().unwrap_or(self.dir);
+        parse_body(&text, dir, self.depth + 1).with_context(|| format!("include {}", path.display()))
+    }
+}
+
+fn var_path(path: &str, line: usize) -> Result<String> {
+    let valid = !path.is_empty()
+        && path.split('.').all(|part| !part.is_empty() && part.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-'));
+    anyhow::ensure!(valid, "line {line}: {{{{{path}}}}} is not a variable");
+    Ok(path.to_string())
+}
+
+fn has_turn(nodes: &[Node]) -> bool {
+    nodes.iter().any(|node| match node {
+        Node::Turn { .. } => true,
+        Node::If { then, otherwise, .. } => has_turn(then) || has_turn(otherwise),
+        Node::Each { body, .. } => has_turn(body),
+        Node::Text(_) | Node::Var(_) => false,
+    })
+}
+
+/// Adds the variables `nodes` refer to, by the first part of their path, to `used`.
+fn collect_vars(nodes: &[Node], used: &mut BTreeSet<String>) {
+    fn add(path: &str, used: &mut BTreeSet<String>) {
+        let name = path.split('.').next().unwrap_or_default();
+        if name != "this" {
+            used.insert(name.to_string());
+        }
+    }
+    for node in nodes {
+        match node {
+            Node::Text(_) => {}
+            Node::Var(path) => add(path, used),
+            Node::If { path, then, otherwise, .. } => {
+                add(path, used);
+                collect_vars(then, used);
+                collect_vars(otherwise, used);
+            }
+            Node::Each { path, body } => {
+                add(path, used);
+                collect_vars(body, used);
+            }
+            Node::Turn { body, .. } => collect_vars(body, used),
+        }
+    }
+}
+
+struct Renderer<'a> {
+    vars: &'a BTreeMap<String, Value>,
+    /// the items of the `{{#each}}` blocks being rendered, innermost last
+    scope: Vec<&'a Value>,
+    role: Option<Role>,
+    /// the text rendered, split wherever a turn starts or ends
+    out: Vec<(Option<Role>, String)>,
+}
+
+impl<'a> Renderer<'a> {
+    fn render(&mut self, nodes: &[Node]) -> Result<()> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => self.write(text),
+                Node::Var(path) => {
+                    let value = display(self.lookup(path)?);
+                    self.write(&value);
+                }
+                Node::If { path, negate, then, otherwise } => {
+                    let branch = if truthy(self.lookup(path)?) != *negate { then } else { otherwise };
+                    self.render(branch)?;
+                }
+                Node::Each { path, body } => {
+                    let items = self.lookup(path)?.as_array().with_context(|| format!("{{{{#each {path}}}}} needs a list"))?;
+                    for item in items {
+                        self.scope.push(item);
+                        self.render(body)?;
+                        self.scope.pop();
+                    }
+                }
+                Node::Turn { role, body } => {
+                    self.role = Some(*role);
+                    self.out.push((self.role, String::new()));
+                    self.render(body)?;
+                    self.role = None;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, text: &str) {
+        match self.out.last_mut() {
+            Some((role, out)) if *role == self.role => out.push_str(text),
+            _ => self.out.push((self.role, text.to_string())),
+        }
+    }
+
+    /// The value at `path`: a variable or `this`, then any fields of it. Fields which aren't there are null.
+    fn lookup(&self, path: &str) -> Result<&'a Value> {
+        let mut parts = path.split('.');
+        let mut value = match parts.next().unwrap_or_default() {
+            "this" => *self.scope.last().context("{{this}} outside of {{#each}}")?,
+            name => self.vars.get(name).with_context(|| format!("unknown variable {{{{{name}}}}}"))?,
+        };
+        for part in parts {
+            value = match value {
+                Value::Array(items) => part.parse::<usize>().ok().and_then(|i| items.get(i)),
+                value => value.get(part),
+            }
+            .unwrap_or(&NULL);
+        }
+        Ok(value)
+    }
+}
+
+fn display(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items.iter().map(display).collect::<Vec<_>>().join(", "),
+        value => value.to_string(),
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(fields) => !fields.is_empty(),
+    }
+}
+
+/// Puts rendered text together into the system prompt and messages. Consecutive turns of the same role become one
+/// message.
+fn messages(out: Vec<(Option<Role>, String)>) -> Result<(Option<String>, Vec<Message>)> {
+    if out.iter().all(|(role, _)| role.is_none()) {
+        let text = out.into_iter().map(|(_, text)| text).collect::<String>();
+        anyhow::ensure!(!text.trim().is_empty(), "the template rendered to nothing");
+        return Ok((None, vec![Message { role: String::from("user"), content: vec![Content::text(text.trim())] }]));
+    }
+    let (mut system, mut messages) = (vec![], Vec::<Message>::new());
+    for (role, text) in out {
+        let text = text.trim();
+        let role = match role {
+            None => {
+                anyhow::ensure!(text.is_empty(), "text outside of a turn: {:?}", text.lines().next().unwrap_or_default());
+                continue;
+            }
+            Some(_) if text.is_empty() => continue,
+            Some(Role::System) => {
+                system.push(text.to_string());
+                continue;
+            }
+            Some(Role::User) => "user",
+            Some(Role::Assistant) => "assistant",
+        };
+        match messages.last_mut() {
+            Some(last) if last.role == role => last.content.push(Content::text(text)),
+            _ => messages.push(Message { role: role.to_string(), content: vec![Content::text(text)] }),
+        }
+    }
+    anyhow::ensure!(messages.first().is_some_and(|m| m.role == "user"), "the first turn must be the user's");
+    Ok(((!system.is_empty()).then(|| system.join("\n\n")), messages))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const TEMPLATE: &str = r#"+++
+model = "claude-3-5-sonnet-latest"
+max_tokens = 512
+
+[vars.language]
+[vars.formal]
+type = "bool"
+default = false
+[vars.examples]
+type = "list"
+default = [{ question = "2 + 2?", answer = "4" }, { question = "3 + 3?", answer = "6" }]
++++
+{{! few-shot examples first }}
+{{#system}}
+Answer in {{language}}{{#if formal}}, formally{{/if}}.
+{{/system}}
+{{#each examples}}
+{{#user}}{{this.question}}{{/user}}
+{{#assistant}}{{this.answer}}{{/assistant}}
+{{/each}}
+{{#user}}
+  {{> question.md}}
+{{#unless topics}}
+Anything goes.
+{{else}}
+Stick to {{topics}}.
+{{/unless}}
+{{/user}}
+"#;
+
+    fn given(vars: &[(&str, &str)]) -> BTreeMap<String, String> {
+        vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn text(msg: &Message) -> Vec<String> {
+        msg.content.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn render() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("question.md"), "What is {{number}} + {{number}}?\n").unwrap();
+        let template = Template::parse(TEMPLATE, dir.path()).unwrap();
+        let client = Client::new("key").unwrap().with_temperature(0.5);
+
+        let vars = given(&[("language", "French"), ("number", "4"), ("formal", "yes"), ("topics", "")]);
+        let req = template.render(&client, &vars).unwrap();
+        assert_eq!((req.model.as_str(), req.max_tokens, req.temperature), ("claude-3-5-sonnet-latest", 512, Some(0.5)));
+        assert_eq!(req.system.as_deref(), Some("Answer in French, formally."));
+        let turns = req.messages.iter().map(|m| (m.role.as_str(), text(m))).collect::<Vec<_>>();
+        assert_eq!(
+            turns,
+            [
+                ("user", vec![String::from("2 + 2?")]),
+                ("assistant", vec![String::from("4")]),
+                ("user", vec![String::from("3 + 3?")]),
+                ("assistant", vec![String::from("6")]),
+                ("user", vec![String::from("What is 4 + 4?\nAnything goes.")]),
+            ]
+        );
+
+        // typed values, and lists from the command line
+        let vars = given(&[("language", "English"), ("number", "1"), ("topics", "maths, physics"), ("examples", "")]);
+        let (system, messages) = template.render_messages(&vars).unwrap();
+        assert_eq!(system.as_deref(), Some("Answer in English."));
+        assert_eq!(text(&messages[0]), ["What is 1 + 1?\nStick to maths, physics."]);
+        let vars =
+            template.vars(&given(&[("language", "en"), ("number", "1"), ("topics", "a, b"), ("examples", "[1, 2]")])).unwrap();
+        assert_eq!((&vars["examples"], &vars["formal"], &vars["topics"]), (&json!([1, 2]), &json!(false), &json!("a, b")));
+    }
+
+    #[test]
+    fn vars() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("question.md"), "{{number}}").unwrap();
+        let template = Template::parse(TEMPLATE, dir.path()).unwrap();
+        let err = template.render_messages(&given(&[("formal", "true")])).unwrap_err();
+        assert_eq!(err.downcast_ref::<MissingVars>().unwrap().0, ["language", "number", "topics"]);
+        let err = template.vars(&given(&[("langauge", "en")])).unwrap_err();
+        assert_eq!(err.to_string(), "unknown variable langauge");
+        let err = template.vars(&given(&[("language", "en"), ("number", "1"), ("formal", "maybe")])).unwrap_err();
+        assert_eq!(format!("{err:#}"), "variable formal: \"maybe\" is not true or false");
+
+        assert_eq!(VarType::Number.parse(" 3 ").unwrap(), json!(3));
+        assert_eq!(VarType::Number.parse("2.5").unwrap(), json!(2.5));
+        assert!(VarType::Number.parse("three").is_err());
+        assert_eq!(VarType::List.parse("a, b,").unwrap(), json!(["a", "b"]));
+        let err = Template::parse("+++\n[vars.n]\ntype = \"number\"\ndefault = \"3\"\n+++\n{{n}}", ".").unwrap_err();
+        assert_eq!(err.to_string(), "the default of n is not a number");
+
+        // the body alone is a user message, with the variables which are always there
+        let (system, messages) = Template::parse("Hi, I'm {{ user }}.\n", ".").unwrap().render_messages(&given(&[])).unwrap();
+        assert_eq!((system, messages[0].role.as_str()), (None, "user"));
+        assert!(text(&messages[0])[0].starts_with("Hi, I'm "));
+    }
+
+    #[test]
+    fn errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = |text: &str| Template::parse(text, dir.path()).unwrap_err().to_string();
+        assert_eq!(err("a\n{{#if x}}\nb"), "line 2: {{#if x}} is never closed");
+        assert_eq!(err("{{/each}}"), "line 1: unexpected {{/each}}");
+        assert_eq!(err("{{#if x}}a\n{{/unless}}"), "line 2: unexpected {{/unless}}");
+        assert_eq!(err("{{#unless x}}a{{else}}b{{/if}}"), "line 1: unexpected {{/if}}");
+        assert_eq!(err("{{#user}}{{#assistant}}{{/assistant}}{{/user}}"), "line 1: {{#user}} has another turn inside it");
+        assert_eq!(err("{{a b}}"), "line 1: {{a b}} is not a variable");
+        assert_eq!(err("{{a"), "line 1: unclosed {{");
+        std::fs::write(dir.path().join("loop.md"), "{{> loop.md}}").unwrap();
+        assert!(format!("{:#}", Template::parse("{{> loop.md}}", dir.path()).unwrap_err()).contains("nest too deeply"));
+
+        let render = |text: &str| Template::parse(text, dir.path()).unwrap().render_messages(&given(&[])).unwrap_err();
+        assert_eq!(render("hi {{#user}}there{{/user}}").to_string(), "text outside of a turn: \"hi\"");
+        assert_eq!(render("{{#assistant}}hi{{/assistant}}").to_string(), "the first turn must be the user's");
+        assert_eq!(render("{{#each date}}{{/each}}").to_string(), "{{#each date}} needs a list");
+    }
+}
@@ -0,0 +1,152 @@
+//! Tools backed by the model itself, for other agents to delegate to, e.g. through [`crate::mcp::server`].
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{Tool, ToolRegistry, fs::Sandbox};
+use crate::anthropic::{Client, Content, Conversation, Message};
+
+/// Adds `ask_claude`, `describe_image` and `summarize_file` to `registry`. The files the latter two read must be
+/// inside `sandbox`.
+pub fn register(client: &Client, sandbox: &Sandbox, registry: &mut ToolRegistry) {
+    registry
+        .register(AskClaude(client.clone()))
+        .register(DescribeImage(client.clone(), sandbox.clone()))
+        .register(SummarizeFile(client.clone(), sandbox.clone()));
+}
+
+/// Has the model reply to a conversation of a single user message.
+async fn ask(client: &Client, system: Option<String>, content: Vec<Content>) -> Result<String> {
+    let mut conv = Conversation::new(client.model(), system);
+    conv.messages.push(Message { role: String::from("user"), content });
+    let resp = client.reply(&mut conv, &[]).await?;
+    Ok(resp.text())
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct AskInput {
+    pub prompt: String,
+    /// a system prompt setting the role, tone or rules for the answer
+    pub system: Option<String>,
+}
+
+pub struct AskClaude(pub Client);
+
+impl Tool for AskClaude {
+    type Input = AskInput;
+
+    fn name(&self) -> &str {
+        "ask_claude"
+    }
+
+    fn description(&self) -> &str {
+        "Asks Claude a question, or gives it a task, returning its answer."
+    }
+
+    async fn call(&self, input: AskInput) -> Result<String> {
+        ask(&self.0, input.system, vec![Content::text(input.prompt)]).await
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct DescribeImageInput {
+    /// a png, jpeg, gif or webp file
+    pub path: String,
+    /// what to look for or ask about the image; a general description if left out
+    pub prompt: Option<String>,
+}
+
+pub struct DescribeImage(pub Client, pub Sandbox);
+
+impl Tool for DescribeImage {
+    type Input = DescribeImageInput;
+
+    fn name(&self) -> &str {
+        "describe_image"
+    }
+
+    fn description(&self) -> &str {
+        "Has Claude look at an image file and describe it, or answer a question about it."
+    }
+
+    async fn call(&self, input: DescribeImageInput) -> Result<String> {
+        let path = self.1.resolve(&input.path)?;
+        let image = Content::image_path(&path).await?;
+        let prompt = input.prompt.unwrap_or_else(|| String::from("Describe this image."));
+        ask(&self.0, None, vec![image, Content::text(prompt)]).await
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct SummarizeFileInput {
+    /// a text file
+    pub path: String,
+    /// what the summary should concentrate on
+    pub focus: Option<String>,
+}
+
+pub struct SummarizeFile(pub Client, pub Sandbox);
+
+impl Tool for SummarizeFile {
+    type Input = SummarizeFileInput;
+
+    fn name(&self) -> &str {
+        "summarize_file"
+    }
+
+    fn description(&self) -> &str {
+        "Has Claude read a text file and summarize it."
+    }
+
+    async fn call(&self, input: SummarizeFileInput) -> Result<String> {
+        let path = self.1.resolve(&input.path)?;
+        let (text, truncated) = {
+            let sandbox = self.1.clone();
+            tokio::task::spawn_blocking(move || sandbox.read_text(&path)).await.context("read file")??
+        };
+        let mut prompt = format!("<file path=\"{}\">\n{text}\n</file>\n\n", input.path);
+        if truncated {
+            prompt.push_str("The file was too long to include in full; summarize the part above.\n");
+        }
+        prompt.push_str("Summarize this file concisely.");
+        if let Some(focus) = input.focus {
+            prompt.push_str(&format!(" Concentrate on {focus}."));
+        }
+        ask(&self.0, None, vec![Content::text(prompt)]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::anthropic::mock::{MockResponse, MockServer};
+
+    #[tokio::test]
+    async fn tools() {
+        let server = MockServer::start().await.unwrap();
+        let client = Client::new(String::from("key")).unwrap().with_endpoint(server.url());
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("notes.md"), "# notes\nbuy milk\n").unwrap();
+        let mut registry = ToolRegistry::new();
+        register(&client, &Sandbox::new(dir.path()).unwrap(), &mut registry);
+        assert_eq!(registry.len(), 3);
+
+        server.push(MockResponse::message("42"));
+        let call = |name: &str, input| Content::ToolUse { id: String::from("t"), name: name.to_string(), input };
+        let result = registry.dispatch(&call("ask_claude", json!({"prompt": "meaning of life?"}))).await.unwrap();
+        assert!(matches!(result, Content::ToolResult { content, is_error: false, .. } if content == "42"));
+
+        server.push(MockResponse::message("a shopping list"));
+        let input = json!({"path": "notes.md", "focus": "groceries"});
+        registry.dispatch(&call("summarize_file", input)).await.unwrap();
+        let prompt = server.requests()[1].body["messages"][0]["content"][0]["text"].as_str().unwrap().to_string();
+        assert!(prompt.starts_with("<file path=\"notes.md\">\n# notes\nbuy milk\n"), "{prompt}");
+        assert!(prompt.ends_with("Concentrate on groceries."));
+
+        let result = registry.dispatch(&call("describe_image", json!({"path": "notes.md"}))).await.unwrap();
+        assert!(matches!(result, Content::ToolResult { content, is_error: true, .. } if content.contains("not an image")));
+    }
+}
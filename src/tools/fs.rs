@@ -0,0 +1,534 @@
+//! Filesystem tools, confined to a sandbox directory.
+//!
+//! Every path a tool is given is resolved, following symlinks, and refused unless it ends up inside the sandbox's
+//! root, so neither `..` nor a symlink pointing elsewhere can reach the rest of the filesystem.
+
+use std::{
+    fs,
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{Tool, ToolRegistry};
+
+/// A directory the filesystem tools are confined to, and limits on what they read and write.
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    root: PathBuf,
+    max_read_bytes: u64,
+    max_write_bytes: usize,
+    max_results: usize,
+}
+
+impl Sandbox {
+    pub fn new(root: impl AsRef<Path>) -> Result<Self> {
+        let root = root.as_ref();
+        let root = root.canonicalize().with_context(|| format!("sandbox root {}", root.display()))?;
+        anyhow::ensure!(root.is_dir(), "sandbox root {} is not a directory", root.display());
+        Ok(Self { root, max_read_bytes: 256 * 1024, max_write_bytes: 1024 * 1024, max_results: 200 })
+    }
+
+    /// The most of a file that is read, or searched. Defaults to 256KiB.
+    pub fn with_max_read_bytes(mut self, bytes: u64) -> Self {
+        self.max_read_bytes = bytes;
+        self
+    }
+
+    /// The largest file that may be written. Defaults to 1MiB.
+    pub fn with_max_write_bytes(mut self, bytes: usize) -> Self {
+        self.max_write_bytes = bytes;
+        self
+    }
+
+    /// The most entries a listing, glob or grep returns. Defaults to 200.
+    pub fn with_max_results(mut self, n: usize) -> Self {
+        self.max_results = n;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Adds every filesystem tool, working in this sandbox, to `registry`.
+    pub fn register(&self, registry: &mut ToolRegistry) {
+        registry
+            .register(ReadFile(self.clone()))
+            .register(ListDir(self.clone()))
+            .register(Glob(self.clone()))
+            .register(Grep(self.clone()))
+            .register(WriteFile(self.clone()))
+            .register(EditFile(self.clone()));
+    }
+
+    /// Like [`Sandbox::register`], but only the tools which read.
+    pub fn register_read_only(&self, registry: &mut ToolRegistry) {
+        registry
+            .register(ReadFile(self.clone()))
+            .register(ListDir(self.clone()))
+            .register(Glob(self.clone()))
+            .register(Grep(self.clone()));
+    }
+
+    /// The real path of the existing file or directory `path`, which is relative to the root unless absolute.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf> {
+        let joined = self.root.join(path);
+        let real = joined.canonicalize().with_context(|| format!("{path} not found"))?;
+        self.check(path, real)
+    }
+
+    /// Like [`Sandbox::resolve`], but for a path which may not exist yet, such as a file about to be written.
+    fn resolve_new(&self, path: &str) -> Result<PathBuf> {
+        let joined = self.root.join(path);
+        let mut existing = joined.as_path();
+        let mut rest = vec![];
+        // a dangling symlink is there, not yet to be created: writing to it would follow it wherever it points
+        while !lexists(existing).with_context(|| format!("resolve {path}"))? {
+            let (Some(parent), Some(name)) = (existing.parent(), existing.file_name()) else {
+                anyhow::bail!("{path} is outside the sandbox");
+            };
+            rest.push(name);
+            existing = parent;
+        }
+        let mut real = existing.canonicalize().with_context(|| format!("resolve {path}, which may be a broken symlink"))?;
+        // `..` in the part that doesn't exist yet can't be resolved against the filesystem
+        for name in rest.into_iter().rev() {
+            anyhow::ensure!(Path::new(name).components().all(|c| matches!(c, Component::Normal(_))), "invalid path {path}");
+            real.push(name);
+        }
+        self.check(path, real)
+    }
+
+    fn check(&self, path: &str, real: PathBuf) -> Result<PathBuf> {
+        anyhow::ensure!(real.starts_with(&self.root), "{path} is outside the sandbox");
+        Ok(real)
+    }
+
+    /// `path` relative to the root, as shown to the model.
+    fn relative(&self, path: &Path) -> String {
+        match path.strip_prefix(&self.root) {
+            Ok(rel) if rel.as_os_str().is_empty() => String::from("."),
+            Ok(rel) => rel.to_string_lossy().replace('\\', "/"),
+            Err(_) => path.to_string_lossy().into_owned(),
+        }
+    }
+
+    /// Reads up to `max_read_bytes` of a text file, and whether there was more.
+    pub(super) fn read_text(&self, path: &Path) -> Result<(String, bool)> {
+        let file = fs::File::open(path).with_context(|| format!("open {}", self.relative(path)))?;
+        let mut buf = vec![];
+        let read = file.take(self.max_read_bytes + 1).read_to_end(&mut buf).context("read")?;
+        let truncated = read as u64 > self.max_read_bytes;
+        buf.truncate(self.max_read_bytes as usize);
+        let text = match String::from_utf8(buf) {
+            Ok(text) => text,
+            // the limit may have cut a character in half
+            Err(err) if truncated && err.utf8_error().error_len().is_none() => {
+                let valid = err.utf8_error().valid_up_to();
+                let mut buf = err.into_bytes();
+                buf.truncate(valid);
+                String::from_utf8(buf)?
+            }
+            Err(_) => anyhow::bail!("{} is not a text file", self.relative(path)),
+        };
+        Ok((text, truncated))
+    }
+
+    /// Every file under `dir`, skipping `.git` and anything which resolves outside the root.
+    fn walk(&self, dir: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+        walkdir::WalkDir::new(dir)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|e| e.file_name() != ".git")
+            .filter_map(|e| e.ok())
+            .filter(|e| !e.file_type().is_dir())
+            .filter(|e| e.path().canonicalize().is_ok_and(|real| real.starts_with(&self.root) && real.is_file()))
+            .map(|e| e.into_path())
+    }
+
+    fn read_file(&self, input: ReadFileInput) -> Result<String> {
+        let path = self.resolve(&input.path)?;
+        let (text, truncated) = self.read_text(&path)?;
+        let offset = input.offset.unwrap_or(1).max(1);
+        let limit = input.limit.unwrap_or(usize::MAX);
+        if offset == 1 && limit == usize::MAX && !truncated {
+            return Ok(text);
+        }
+        let mut out = text.split_inclusive('\n').skip(offset - 1).take(limit).collect::<String>();
+        if truncated {
+            out.push_str(&format!("\n[truncated at {} bytes]", self.max_read_bytes));
+        }
+        Ok(out)
+    }
+
+    fn list_dir(&self, input: ListDirInput) -> Result<String> {
+        let dir = self.resolve(input.path.as_deref().unwrap_or("."))?;
+        let mut entries = fs::read_dir(&dir)
+            .with_context(|| format!("list {}", self.relative(&dir)))?
+            .filter_map(|e| e.ok())
+            .map(|e| {
+                let name = e.file_name().to_string_lossy().into_owned();
+                if e.path().is_dir() { name + "/" } else { name }
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+        Ok(self.limit_results(entries))
+    }
+
+    fn glob(&self, input: GlobInput) -> Result<String> {
+        let dir = self.resolve(input.path.as_deref().unwrap_or("."))?;
+        let glob = globset::Glob::new(&input.pattern).context("invalid pattern")?.compile_matcher();
+        let matches = self
+            .walk(&dir)
+            .filter(|path| path.strip_prefix(&dir).is_ok_and(|rel| glob.is_match(rel)))
+            .map(|path| self.relative(&path));
+        Ok(self.limit_results(matches.take(self.max_results + 1).collect()))
+    }
+
+    fn grep(&self, input: GrepInput) -> Result<String> {
+        let dir = self.resolve(input.path.as_deref().unwrap_or("."))?;
+        let regex = regex::Regex::new(&input.pattern).context("invalid pattern")?;
+        let glob = match &input.glob {
+            Some(glob) => Some(globset::Glob::new(glob).context("invalid glob")?.compile_matcher()),
+            None => None,
+        };
+        let files = match dir.is_file() {
+            true => vec![dir.clone()],
+            false => self
+                .walk(&dir)
+                .filter(|path| {
+                    let rel = path.strip_prefix(&dir).unwrap_or(path);
+                    glob.as_ref().is_none_or(|g| g.is_match(rel))
+                })
+                .collect(),
+        };
+        let mut matches = vec![];
+        for path in files {
+            // binary and unreadable files are skipped rather than failing the search
+            let Ok((text, _)) = self.read_text(&path) else { continue };
+            for (n, line) in text.lines().enumerate().filter(|(_, line)| regex.is_match(line)) {
+                matches.push(format!("{}:{}: {}", self.relative(&path), n + 1, line.trim_end()));
+            }
+            if matches.len() > self.max_results {
+                break;
+            }
+        }
+        Ok(self.limit_results(matches))
+    }
+
+    fn write_file(&self, input: WriteFileInput) -> Result<String> {
+        anyhow::ensure!(
+            input.content.len() <= self.max_write_bytes,
+            "content is {} bytes, more than the {} allowed",
+            input.content.len(),
+            self.max_write_bytes
+        );
+        let path = self.resolve_new(&input.path)?;
+        anyhow::ensure!(!path.is_dir(), "{} is a directory", input.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("create {}", self.relative(parent)))?;
+        }
+        fs::write(&path, &input.content).with_context(|| format!("write {}", input.path))?;
+        Ok(format!("wrote {} bytes to {}", input.content.len(), self.relative(&path)))
+    }
+
+    fn edit_file(&self, input: EditFileInput) -> Result<String> {
+        let path = self.resolve(&input.path)?;
+        let (text, truncated) = self.read_text(&path)?;
+        anyhow::ensure!(!truncated, "{} is too large to edit", input.path);
+        anyhow::ensure!(!input.old_str.is_empty(), "old_str is empty");
+        match text.matches(&input.old_str).count() {
+            0 => anyhow::bail!("old_str not found in {}", input.path),
+            1 => {}
+            n => anyhow::bail!("old_str appears {n} times in {}; include more context to pick one", input.path),
+        }
+        let text = text.replacen(&input.old_str, &input.new_str, 1);
+        anyhow::ensure!(text.len() <= self.max_write_bytes, "the edited file would be too large");
+        fs::write(&path, text).with_context(|| format!("write {}", input.path))?;
+        Ok(format!("edited {}", self.relative(&path)))
+    }
+
+    fn limit_results(&self, mut results: Vec<String>) -> String {
+        if results.is_empty() {
+            return String::from("(none)");
+        }
+        let more = results.len() > self.max_results;
+        results.truncate(self.max_results);
+        let mut out = results.join("\n");
+        if more {
+            out.push_str(&format!("\n[only the first {} shown]", self.max_results));
+        }
+        out
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ReadFileInput {
+    /// relative to the root directory
+    pub path: String,
+    /// the line to start at, from 1
+    pub offset: Option<usize>,
+    /// the most lines to read
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ListDirInput {
+    /// relative to the root directory; the root itself if left out
+    pub path: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct GlobInput {
+    /// e.g. `**/*.rs`
+    pub pattern: String,
+    /// the directory to search in, relative to the root directory; the root itself if left out
+    pub path: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct GrepInput {
+    /// a regular expression
+    pub pattern: String,
+    /// the file or directory to search in, relative to the root directory; the root itself if left out
+    pub path: Option<String>,
+    /// only search files matching this glob, e.g. `*.rs`
+    pub glob: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct WriteFileInput {
+    /// relative to the root directory
+    pub path: String,
+    pub content: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct EditFileInput {
+    /// relative to the root directory
+    pub path: String,
+    /// the text to replace, which must appear exactly once in the file
+    pub old_str: String,
+    pub new_str: String,
+}
+
+/// Whether there is anything at `path`, a symlink counting as itself rather than what it points to.
+fn lexists(path: &Path) -> std::io::Result<bool> {
+    match path.symlink_metadata() {
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Runs a sandbox operation off the async runtime.
+async fn blocking<I: Send + 'static>(sandbox: &Sandbox, input: I, f: fn(&Sandbox, I) -> Result<String>) -> Result<String> {
+    let sandbox = sandbox.clone();
+    tokio::task::spawn_blocking(move || f(&sandbox, input)).await.context("filesystem task")?
+}
+
+pub struct ReadFile(pub Sandbox);
+
+impl Tool for ReadFile {
+    type Input = ReadFileInput;
+
+    fn name(&self) -> &str {
+        "read_file"
+    }
+
+    fn description(&self) -> &str {
+        "Reads a text file. Large files are truncated; use offset and limit to read them in parts."
+    }
+
+    async fn call(&self, input: ReadFileInput) -> Result<String> {
+        blocking(&self.0, input, Sandbox::read_file).await
+    }
+}
+
+pub struct ListDir(pub Sandbox);
+
+impl Tool for ListDir {
+    type Input = ListDirInput;
+
+    fn name(&self) -> &str {
+        "list_dir"
+    }
+
+    fn description(&self) -> &str {
+        "Lists a directory. Subdirectories end with a /."
+    }
+
+    async fn call(&self, input: ListDirInput) -> Result<String> {
+        blocking(&self.0, input, Sandbox::list_dir).await
+    }
+}
+
+pub struct Glob(pub Sandbox);
+
+impl Tool for Glob {
+    type Input = GlobInput;
+
+    fn name(&self) -> &str {
+        "glob"
+    }
+
+    fn description(&self) -> &str {
+        "Finds files whose path matches a glob pattern."
+    }
+
+    async fn call(&self, input: GlobInput) -> Result<String> {
+        blocking(&self.0, input, Sandbox::glob).await
+    }
+}
+
+pub struct Grep(pub Sandbox);
+
+impl Tool for Grep {
+    type Input = GrepInput;
+
+    fn name(&self) -> &str {
+        "grep"
+    }
+
+    fn description(&self) -> &str {
+        "Searches files for lines matching a regular expression, returning each as path:line: text."
+    }
+
+    async fn call(&self, input: GrepInput) -> Result<String> {
+        blocking(&self.0, input, Sandbox::grep).await
+    }
+}
+
+pub struct WriteFile(pub Sandbox);
+
+impl Tool for WriteFile {
+    type Input = WriteFileInput;
+
+    fn name(&self) -> &str {
+        "write_file"
+    }
+
+    fn description(&self) -> &str {
+        "Creates a file, or replaces its contents. Missing parent directories are created."
+    }
+
+    async fn call(&self, input: WriteFileInput) -> Result<String> {
+        blocking(&self.0, input, Sandbox::write_file).await
+    }
+}
+
+pub struct EditFile(pub Sandbox);
+
+impl Tool for EditFile {
+    type Input = EditFileInput;
+
+    fn name(&self) -> &str {
+        "edit_file"
+    }
+
+    fn description(&self) -> &str {
+        "Edits a file by replacing old_str, which must appear exactly once, with new_str."
+    }
+
+    async fn call(&self, input: EditFileInput) -> Result<String> {
+        blocking(&self.0, input, Sandbox::edit_file).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::anthropic::Content;
+
+    async fn call(registry: &ToolRegistry, name: &str, input: Value) -> Result<String, String> {
+        let block = Content::ToolUse { id: String::from("toolu_1"), name: name.to_string(), input };
+        match registry.dispatch(&block).await {
+            Some(Content::ToolResult { content, is_error: false, .. }) => Ok(content),
+            Some(Content::ToolResult { content, .. }) => Err(content),
+            res => panic!("{res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn tools() {
+        let dir = tempfile::tempdir().unwrap();
+        let sandbox = Sandbox::new(dir.path()).unwrap().with_max_read_bytes(64).with_max_results(3);
+        let mut registry = ToolRegistry::new();
+        sandbox.register(&mut registry);
+        assert_eq!(registry.len(), 6);
+
+        let wrote = call(&registry, "write_file", json!({"path": "src/main.rs", "content": "fn main() {}\n"})).await;
+        assert_eq!(wrote.unwrap(), "wrote 13 bytes to src/main.rs");
+        call(&registry, "write_file", json!({"path": "src/lib.rs", "content": "pub fn a() {}\npub fn b() {}\n"})).await.unwrap();
+        call(&registry, "write_file", json!({"path": "README.md", "content": "# fn\n"})).await.unwrap();
+
+        assert_eq!(call(&registry, "read_file", json!({"path": "src/main.rs"})).await.unwrap(), "fn main() {}\n");
+        let read = call(&registry, "read_file", json!({"path": "src/lib.rs", "offset": 2})).await;
+        assert_eq!(read.unwrap(), "pub fn b() {}\n");
+        assert_eq!(call(&registry, "list_dir", json!({})).await.unwrap(), "README.md\nsrc/");
+        assert_eq!(call(&registry, "glob", json!({"pattern": "**/*.rs"})).await.unwrap(), "src/lib.rs\nsrc/main.rs");
+        let grep = call(&registry, "grep", json!({"pattern": r"fn \w+\(", "glob": "*.rs"})).await;
+        assert_eq!(grep.unwrap(), "src/lib.rs:1: pub fn a() {}\nsrc/lib.rs:2: pub fn b() {}\nsrc/main.rs:1: fn main() {}");
+        let grep = call(&registry, "grep", json!({"pattern": "fn"})).await.unwrap();
+        assert!(grep.ends_with("[only the first 3 shown]"), "{grep}");
+
+        let edit = json!({"path": "src/lib.rs", "old_str": "pub fn", "new_str": "fn"});
+        assert!(call(&registry, "edit_file", edit).await.unwrap_err().contains("appears 2 times"));
+        let edit = json!({"path": "src/lib.rs", "old_str": "pub fn b", "new_str": "fn b"});
+        call(&registry, "edit_file", edit).await.unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("src/lib.rs")).unwrap(), "pub fn a() {}\nfn b() {}\n");
+
+        fs::write(dir.path().join("big.txt"), "x".repeat(100)).unwrap();
+        let read = call(&registry, "read_file", json!({"path": "big.txt"})).await.unwrap();
+        assert_eq!(read, format!("{}\n[truncated at 64 bytes]", "x".repeat(64)));
+    }
+
+    #[tokio::test]
+    async fn escapes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir(&root).unwrap();
+        fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        let sandbox = Sandbox::new(&root).unwrap().with_max_write_bytes(10);
+        let mut registry = ToolRegistry::new();
+        sandbox.register(&mut registry);
+
+        let outside = dir.path().join("secret.txt").to_string_lossy().into_owned();
+        for path in ["../secret.txt", outside.as_str()] {
+            let err = call(&registry, "read_file", json!({"path": path})).await.unwrap_err();
+            assert!(err.contains("outside the sandbox"), "{err}");
+        }
+        for path in ["../new.txt", "sub/../../new.txt", "/tmp/new.txt"] {
+            let err = call(&registry, "write_file", json!({"path": path, "content": "x"})).await.unwrap_err();
+            assert!(err.contains("outside the sandbox") || err.contains("invalid path"), "{path}: {err}");
+        }
+        let err = call(&registry, "write_file", json!({"path": "a.txt", "content": "more than ten"})).await;
+        assert!(err.unwrap_err().contains("more than the 10 allowed"));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.path().join("secret.txt"), root.join("link.txt")).unwrap();
+            std::os::unix::fs::symlink(dir.path(), root.join("parent")).unwrap();
+            for path in ["link.txt", "parent/secret.txt"] {
+                let err = call(&registry, "read_file", json!({"path": path})).await.unwrap_err();
+                assert!(err.contains("outside the sandbox"), "{err}");
+            }
+            let err = call(&registry, "write_file", json!({"path": "parent/x.txt", "content": "x"})).await;
+            assert!(err.unwrap_err().contains("outside the sandbox"));
+            // a link to a file which doesn't exist yet mustn't be written through
+            std::os::unix::fs::symlink(dir.path().join("new.txt"), root.join("dangling.txt")).unwrap();
+            for path in ["dangling.txt", "dangling.txt/x.txt"] {
+                assert!(call(&registry, "write_file", json!({"path": path, "content": "x"})).await.is_err());
+            }
+            assert!(!dir.path().join("new.txt").exists());
+            assert_eq!(call(&registry, "grep", json!({"pattern": "secret"})).await.unwrap(), "(none)");
+            assert_eq!(call(&registry, "glob", json!({"pattern": "**"})).await.unwrap(), "(none)");
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Tools the model can call, defined as rust types rather than hand written json schema.
+
+use std::{collections::BTreeMap, future::Future, sync::Arc};
+
+use anyhow::{Context, Result};
+use futures::{FutureExt, future::BoxFuture};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::anthropic::{Content, ToolDefinition, input_schema};
+
+pub mod claude;
+pub mod fs;
+pub mod shell;
+
+/// A tool implemented in rust. The input type's json schema is what the model sees.
+///
+/// ```ignore
+/// #[derive(Deserialize, JsonSchema)]
+/// struct WeatherInput {
+///     /// a city name, e.g. "Paris"
+///     city: String,
+/// }
+///
+/// struct Weather;
+///
+/// impl Tool for Weather {
+///     type Input = WeatherInput;
+///     fn name(&self) -> &str { "weather" }
+///     fn description(&self) -> &str { "Gets the current weather for a city." }
+///     async fn call(&self, input: WeatherInput) -> Result<String> { ... }
+/// }
+/// ```
+pub trait Tool: Send + Sync + 'static {
+    type Input: DeserializeOwned + JsonSchema + Send;
+
+    fn name(&self) -> &str;
+
+    fn description(&self) -> &str;
+
+    /// Runs the tool. The returned text, or the error, is sent back to the model as the tool's result.
+    fn call(&self, input: Self::Input) -> impl Future<Output = Result<String>> + Send;
+}
+
+/// The object safe form of a tool, taking and describing its input as json. Every [`Tool`] is a `DynTool`, and
+/// tools whose schema is only known at runtime (e.g. those of an MCP server) can implement it directly.
+pub trait DynTool: Send + Sync {
+    fn definition(&self) -> ToolDefinition;
+
+    fn call_json(&self, input: Value) -> BoxFuture<'_, Result<String>>;
+}
+
+impl<T: Tool> DynTool for T {
+    fn definition(&self) -> ToolDefinition {
+        let (input_schema, _) = input_schema::<T::Input>();
+        ToolDefinition { name: self.name().to_string(), description: self.description().to_string(), input_schema }
+    }
+
+    fn call_json(&self, input: Value) -> BoxFuture<'_, Result<String>> {
+        async move {
+            let (_, wrapped) = input_schema::<T::Input>();
+            let input = if wrapped { input.get("value").cloned().unwrap_or(Value::Null) } else { input };
+            let input = serde_json::from_value(input).context("invalid input")?;
+            self.call(input).await
+        }
+        .boxed()
+    }
+}
+
+/// The tools available to the model, by name.
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    tools: BTreeMap<String, Arc<dyn DynTool>>,
+}
+
+impl std::fmt::Debug for ToolRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.tools.keys()).finish()
+    }
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a tool, replacing any other of the same name.
+    pub fn register(&mut self, tool: impl Tool) -> &mut Self {
+        self.register_dyn(Arc::new(tool))
+    }
+
+    pub fn register_dyn(&mut self, tool: Arc<dyn DynTool>) -> &mut Self {
+        self.tools.insert(tool.definition().name, tool);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn DynTool>> {
+        self.tools.get(name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tools.len()
+    }
+
+    /// The definitions to send with a request.
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        self.tools.values().map(|t| t.definition()).collect()
+    }
+
+    /// Runs the tool a `tool_use` block asks for, returning its `tool_result`. Unknown tools, invalid input and
+    /// failed calls all become results with `is_error` set, so that the model can see what went wrong. Returns
+    /// `None` for blocks other than `tool_use`.
+    pub async fn dispatch(&self, block: &Content) -> Option<Content> {
+        let Content::ToolUse { id, name, input } = block else {
+            return None;
+        };
+        let res = match self.tools.get(name) {
+            Some(tool) => tool.call_json(input.clone()).await,
+            None => Err(anyhow::anyhow!("no tool named {name}")),
+        };
+        let (content, is_error) = match res {
+            Ok(content) => (content, false),
+            Err(err) => {
+                tracing::debug!("tool {name} failed: {err:#}");
+                (format!("{err:#}"), true)
+            }
+        };
+        Some(Content::ToolResult { tool_use_id: id.clone(), content, is_error })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Deserialize, JsonSchema)]
+    struct AddInput {
+        /// the first addend
+        a: i64,
+        b: i64,
+    }
+
+    struct Add;
+
+    impl Tool for Add {
+        type Input = AddInput;
+
+        fn name(&self) -> &str {
+            "add"
+        }
+
+        fn description(&self) -> &str {
+            "Adds two numbers."
+        }
+
+        async fn call(&self, input: AddInput) -> Result<String> {
+            input.a.checked_add(input.b).map(|n| n.to_string()).context("overflow")
+        }
+    }
+
+    fn tool_use(name: &str, input: Value) -> Content {
+        Content::ToolUse { id: String::from("toolu_1"), name: name.to_string(), input }
+    }
+
+    #[tokio::test]
+    async fn dispatch() {
+        let mut registry = ToolRegistry::new();
+        registry.register(Add);
+        let defs = registry.definitions();
+        assert_eq!(defs.len(), 1);
+        assert_eq!(defs[0].name, "add");
+        assert_eq!(defs[0].input_schema["properties"]["a"]["description"], "the first addend");
+
+        let res = registry.dispatch(&tool_use("add", json!({"a": 2, "b": 3}))).await.unwrap();
+        assert_eq!(
+            res,
+            Content::ToolResult { tool_use_id: String::from("toolu_1"), content: String::from("5"), is_error: false }
+        );
+
+        for (block, err) in [
+            (tool_use("add", json!({"a": 2})), "invalid input: missing field `b`"),
+            (tool_use("add", json!({"a": i64::MAX, "b": 1})), "overflow"),
+            (tool_use("subtract", json!({})), "no tool named subtract"),
+        ] {
+            let Some(Content::ToolResult { content, is_error, .. }) = registry.dispatch(&block).await else { panic!() };
+            assert!(is_error);
+            assert_eq!(content, err);
+        }
+        assert!(registry.dispatch(&Content::text("hi")).await.is_none());
+    }
+}
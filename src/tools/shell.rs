@@ -0,0 +1,237 @@
+//! A tool running shell commands, each of which must first get past an [`ApprovalPolicy`].
+//!
+//! Every command is logged with `tracing`: at info when it is approved and when it finishes, with its exit code and
+//! how long it took, and at warn when it is refused.
+
+use std::{
+    fmt,
+    io::{BufRead, IsTerminal, Write},
+    path::PathBuf,
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::Tool;
+
+/// Who decides whether a command may run.
+#[derive(Clone)]
+pub enum ApprovalPolicy {
+    /// asks on the terminal before every command, refusing when there is no terminal to ask on
+    Ask,
+    /// allows commands starting with one of these prefixes, e.g. `cargo test` or `git status`, and refuses the rest
+    Allowlist(Vec<String>),
+    /// refuses everything
+    Deny,
+    /// decides with a function of the command
+    Custom(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+impl fmt::Debug for ApprovalPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ask => write!(f, "Ask"),
+            Self::Allowlist(prefixes) => f.debug_tuple("Allowlist").field(prefixes).finish(),
+            Self::Deny => write!(f, "Deny"),
+            Self::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+impl ApprovalPolicy {
+    pub async fn approve(&self, command: &str) -> Result<bool> {
+        match self {
+            Self::Ask => {
+                let command = command.to_string();
+                tokio::task::spawn_blocking(move || ask(&command)).await.context("ask for approval")?
+            }
+            Self::Allowlist(prefixes) => Ok(allowed(prefixes, command)),
+            Self::Deny => Ok(false),
+            Self::Custom(f) => Ok(f(command)),
+        }
+    }
+}
+
+fn ask(command: &str) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    eprint!("run `{command}`? [y/N] ");
+    std::io::stderr().flush().context("flush stderr")?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer).context("read answer")?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Whether `command` is one of `prefixes` followed by nothing or further arguments. Commands which could chain,
+/// substitute or redirect anything are refused, whatever their prefix, as `cargo test; rm -rf ~` would otherwise
+/// pass as `cargo test`.
+fn allowed(prefixes: &[String], command: &str) -> bool {
+    const METACHARACTERS: &[char] = &[';', '&', '|', '`', '$', '>', '<', '(', ')', '\n', '\r'];
+    let command = command.trim();
+    if command.contains(METACHARACTERS) {
+        return false;
+    }
+    prefixes
+        .iter()
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .any(|prefix| command.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace)))
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ShellInput {
+    /// run with `sh -c`
+    pub command: String,
+}
+
+/// Runs shell commands in a working directory, once its [`ApprovalPolicy`] allows them.
+#[derive(Debug, Clone)]
+pub struct Shell {
+    policy: ApprovalPolicy,
+    cwd: PathBuf,
+    timeout: Duration,
+    max_output: usize,
+}
+
+impl Shell {
+    pub fn new(policy: ApprovalPolicy, cwd: impl Into<PathBuf>) -> Self {
+        Self { policy, cwd: cwd.into(), timeout: Duration::from_secs(30), max_output: 16 * 1024 }
+    }
+
+    /// How long a command may run before it is killed. Defaults to 30s.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The most of stdout, and of stderr, sent back to the model. Defaults to 16KiB each.
+    pub fn with_max_output(mut self, bytes: usize) -> Self {
+        self.max_output = bytes;
+        self
+    }
+
+    async fn run(&self, command: &str) -> Result<String> {
+        if !self.policy.approve(command).await? {
+            tracing::warn!(command, policy = ?self.policy, "shell command refused");
+            anyhow::bail!("the command was not approved");
+        }
+        tracing::info!(command, cwd = %self.cwd.display(), "shell command approved");
+        let started = Instant::now();
+        let child = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(&self.cwd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("spawn sh")?;
+        let output = match tokio::time::timeout(self.timeout, child.wait_with_output()).await {
+            Ok(output) => output.context("wait for command")?,
+            Err(_) => {
+                tracing::info!(command, elapsed = ?started.elapsed(), "shell command timed out");
+                anyhow::bail!("the command timed out after {:?}", self.timeout);
+            }
+        };
+        let code = output.status.code();
+        tracing::info!(
+            command,
+            exit_code = code,
+            elapsed = ?started.elapsed(),
+            stdout_bytes = output.stdout.len(),
+            stderr_bytes = output.stderr.len(),
+            "shell command finished"
+        );
+        let code = code.map_or_else(|| String::from("none (killed by a signal)"), |c| c.to_string());
+        Ok(format!(
+            "exit code: {code}\n<stdout>\n{}</stdout>\n<stderr>\n{}</stderr>",
+            truncate(&String::from_utf8_lossy(&output.stdout), self.max_output),
+            truncate(&String::from_utf8_lossy(&output.stderr), self.max_output),
+        ))
+    }
+}
+
+/// Keeps the start and end of `s`, which usually matter most, dropping the middle to fit in `max` bytes.
+fn truncate(s: &str, max: usize) -> String {
+    if s.len() <= max {
+        return s.to_string();
+    }
+    let mut head = max / 2;
+    while !s.is_char_boundary(head) {
+        head -= 1;
+    }
+    let mut tail = s.len() - max / 2;
+    while !s.is_char_boundary(tail) {
+        tail += 1;
+    }
+    format!("{}\n[{} bytes omitted]\n{}", &s[..head], tail - head, &s[tail..])
+}
+
+impl Tool for Shell {
+    type Input = ShellInput;
+
+    fn name(&self) -> &str {
+        "shell"
+    }
+
+    fn description(&self) -> &str {
+        "Runs a shell command, returning its exit code, stdout and stderr. Long output is truncated in the middle."
+    }
+
+    async fn call(&self, input: ShellInput) -> Result<String> {
+        self.run(&input.command).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowlist() {
+        let prefixes = vec![String::from("cargo test"), String::from("ls")];
+        for (command, ok) in [
+            ("cargo test", true),
+            ("cargo test --lib", true),
+            ("  ls -la ", true),
+            ("cargo testing", false),
+            ("lsblk", false),
+            ("cargo build", false),
+            ("cargo test; rm -rf ~", false),
+            ("ls && curl evil", false),
+            ("ls $(rm x)", false),
+            ("ls > out", false),
+        ] {
+            assert_eq!(allowed(&prefixes, command), ok, "{command}");
+        }
+    }
+
+    #[tokio::test]
+    async fn runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let shell = Shell::new(ApprovalPolicy::Allowlist(vec![String::from("echo"), String::from("sh")]), dir.path())
+            .with_max_output(20)
+            .with_timeout(Duration::from_millis(200));
+        let out = shell.run("echo hello").await.unwrap();
+        assert_eq!(out, "exit code: 0\n<stdout>\nhello\n</stdout>\n<stderr>\n</stderr>");
+        let out = shell.run("sh -c 'echo oops >&2; exit 3'").await;
+        assert!(out.unwrap_err().to_string().contains("not approved"));
+
+        let shell = Shell { policy: ApprovalPolicy::Custom(Arc::new(|_| true)), ..shell };
+        let out = shell.run("echo oops >&2; exit 3").await.unwrap();
+        assert_eq!(out, "exit code: 3\n<stdout>\n</stdout>\n<stderr>\noops\n</stderr>");
+        let out = shell.run("seq 1 100").await.unwrap();
+        assert!(out.contains("1\n2\n3\n4\n5\n\n[") && out.contains(" bytes omitted]\n98\n99\n100\n"), "{out}");
+        let err = shell.run("sleep 5").await.unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+
+        let shell = Shell { policy: ApprovalPolicy::Deny, ..shell };
+        assert!(shell.run("echo hello").await.is_err());
+    }
+}
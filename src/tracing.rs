@@ -1,3 +1,13 @@
 pub fn init() {
     tracing_subscriber::fmt::SubscriberBuilder::default().with_file(true).with_line_number(true).without_time().init();
 }
+
+/// Like [`init`], but logging to stderr, for when stdout carries something else, like an MCP server's replies.
+pub fn init_stderr() {
+    tracing_subscriber::fmt::SubscriberBuilder::default()
+        .with_file(true)
+        .with_line_number(true)
+        .without_time()
+        .with_writer(std::io::stderr)
+        .init();
+}
@@ -0,0 +1,495 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde_json::Value;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::{
+    anthropic::{
+        Client, Content, Conversation, MessagesResponse, SessionStore, SessionSummary, TextStreamEvent, Usage, models::Cost,
+    },
+    tools::ToolRegistry,
+};
+
+/// Which pane the keyboard drives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Focus {
+    Input,
+    Sessions,
+}
+
+/// A dialog drawn over everything else, taking the keyboard until it is closed.
+#[derive(Debug)]
+pub enum Popup {
+    Models {
+        selected: usize,
+    },
+    /// a tool call waiting for the user to approve or deny it
+    Approval {
+        id: String,
+        answer: oneshot::Sender<bool>,
+    },
+}
+
+/// What happened to a tool call of the reply in progress.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolStatus {
+    Waiting,
+    Running,
+    Denied,
+    Done(String),
+    Failed(String),
+}
+
+/// Part of the reply in progress, shown below the conversation until the reply is done.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Live {
+    Text(String),
+    Tool { id: String, name: String, input: Value, status: ToolStatus },
+}
+
+/// Sent to the app by the task running a reply.
+#[derive(Debug)]
+pub enum AppEvent {
+    Fragment(String),
+    /// the model finished a message, which may call tools
+    Response(MessagesResponse),
+    Approve {
+        call: Content,
+        answer: oneshot::Sender<bool>,
+    },
+    ToolResult(Content),
+    /// the reply is over, leaving the conversation as it is here
+    Done {
+        conv: Box<Conversation>,
+        error: Option<String>,
+    },
+}
+
+/// How far a reply has got, for the app to pick up from if it is cancelled: the conversation as of the last step
+/// to complete, and the text streamed in since.
+#[derive(Debug)]
+struct Progress {
+    conv: Conversation,
+    partial: String,
+}
+
+/// A reply in progress. Each has its own channel, so that nothing a cancelled reply had yet to deliver reaches the
+/// next one.
+struct Run {
+    task: JoinHandle<()>,
+    events: mpsc::UnboundedReceiver<AppEvent>,
+    progress: Arc<Mutex<Progress>>,
+}
+
+/// Timings of the latest reply.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Latency {
+    pub first_token: Option<Duration>,
+    pub total: Option<Duration>,
+}
+
+/// Everything the TUI shows, and how it changes as keys are pressed and replies arrive. Replies run as tasks which
+/// report back through [`AppEvent`]s, so the screen stays live while they stream in.
+pub struct App {
+    client: Client,
+    tools: ToolRegistry,
+    store: SessionStore,
+    pub(super) conv: Conversation,
+    pub(super) sessions: Vec<SessionSummary>,
+    pub(super) selected_session: usize,
+    pub(super) models: Vec<String>,
+    pub(super) input: String,
+    pub(super) focus: Focus,
+    pub(super) popup: Option<Popup>,
+    /// how many lines the conversation is scrolled up from the bottom
+    pub(super) scroll: u16,
+    pub(super) live: Vec<Live>,
+    /// usage and cost of the reply in progress, not yet in `conv`
+    run_usage: Usage,
+    run_cost: Cost,
+    pub(super) started: Option<Instant>,
+    pub(super) latency: Latency,
+    /// the latest error, shown in the status bar
+    pub(super) status: Option<String>,
+    run: Option<Run>,
+    quit: bool,
+}
+
+impl App {
+    pub fn new(client: Client, conv: Conversation, store: SessionStore) -> Self {
+        Self {
+            models: vec![conv.model.clone()],
+            client,
+            tools: ToolRegistry::new(),
+            store,
+            conv,
+            sessions: vec![],
+            selected_session: 0,
+            input: String::new(),
+            focus: Focus::Input,
+            popup: None,
+            scroll: 0,
+            live: vec![],
+            run_usage: Usage::default(),
+            run_cost: Cost::default(),
+            started: None,
+            latency: Latency::default(),
+            status: None,
+            run: None,
+            quit: false,
+        }
+    }
+
+    /// Offers the model `tools`, each call of which the user approves or denies.
+    pub fn with_tools(mut self, tools: ToolRegistry) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    /// The models the picker offers, along with the conversation's own.
+    pub fn with_models(mut self, models: Vec<String>) -> Self {
+        self.models = models;
+        if !self.models.contains(&self.conv.model) {
+            self.models.insert(0, self.conv.model.clone());
+        }
+        self
+    }
+
+    pub fn conversation(&self) -> &Conversation {
+        &self.conv
+    }
+
+    pub fn popup(&self) -> Option<&Popup> {
+        self.popup.as_ref()
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.run.is_some()
+    }
+
+    pub fn should_quit(&self) -> bool {
+        self.quit
+    }
+
+    /// Usage across the session, the reply in progress included.
+    pub fn usage(&self) -> Usage {
+        let mut usage = self.conv.usage();
+        usage.extend(self.run_usage.clone());
+        usage
+    }
+
+    pub fn cost(&self) -> Cost {
+        self.conv.cost() + self.run_cost
+    }
+
+    /// Rereads the saved sessions for the sidebar.
+    pub async fn refresh_sessions(&mut self) {
+        match self.store.list().await {
+            Ok(sessions) => self.sessions = sessions,
+            Err(err) => self.status = Some(format!("list sessions: {err:#}")),
+        }
+        self.selected_session = self.selected_session.min(self.sessions.len().saturating_sub(1));
+    }
+
+    /// Waits for the reply in progress to send something, forever if there is none. Unlike handling what it sends,
+    /// this is cancel safe.
+    pub async fn recv(&mut self) -> Option<AppEvent> {
+        let Some(run) = &mut self.run else { return std::future::pending().await };
+        let ev = run.events.recv().await;
+        if ev.is_none() {
+            // the task ended without saying it was done, which only a panic does
+            self.run = None;
+            self.live.clear();
+            self.started = None;
+            self.status = Some(String::from("the reply failed"));
+        }
+        ev
+    }
+
+    /// Waits for the reply in progress to send something, and handles it.
+    pub async fn next_event(&mut self) {
+        if let Some(ev) = self.recv().await {
+            self.on_event(ev).await;
+        }
+    }
+
+    pub async fn on_key(&mut self, key: KeyEvent) {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        if self.popup.is_some() && !ctrl {
+            return self.popup_key(key);
+        }
+        match key.code {
+            KeyCode::Char('c') if ctrl && self.is_busy() => self.cancel().await,
+            KeyCode::Char('c' | 'q') if ctrl => self.quit = true,
+            KeyCode::Esc if self.is_busy() => self.cancel().await,
+            KeyCode::Char('p') if ctrl && self.popup.is_none() => {
+                let selected = self.models.iter().position(|m| *m == self.conv.model).unwrap_or_default();
+                self.popup = Some(Popup::Models { selected });
+            }
+            KeyCode::Char('n') if ctrl && !self.is_busy() => {
+                self.conv = Conversation::new(&self.conv.model, self.conv.system.clone());
+                self.scroll = 0;
+            }
+            KeyCode::Tab => {
+                self.focus = if self.focus == Focus::Input { Focus::Sessions } else { Focus::Input };
+            }
+            KeyCode::PageUp => self.scroll = self.scroll.saturating_add(10),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            _ if self.focus == Focus::Sessions => self.session_key(key).await,
+            _ => self.input_key(key).await,
+        }
+    }
+
+    fn popup_key(&mut self, key: KeyEvent) {
+        let selected = match &mut self.popup {
+            Some(Popup::Approval { .. }) => {
+                match key.code {
+                    KeyCode::Char('y') => self.answer(true),
+                    KeyCode::Char('n') | KeyCode::Esc => self.answer(false),
+                    _ => {}
+                }
+                return;
+            }
+            Some(Popup::Models { selected }) => selected,
+            None => return,
+        };
+        match key.code {
+            KeyCode::Up => *selected = selected.saturating_sub(1),
+            KeyCode::Down => *selected = (*selected + 1).min(self.models.len().saturating_sub(1)),
+            KeyCode::Enter => {
+                self.conv.model = self.models[*selected].clone();
+                self.popup = None;
+            }
+            KeyCode::Esc => self.popup = None,
+            _ => {}
+        }
+    }
+
+    async fn session_key(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Up => self.selected_session = self.selected_session.saturating_sub(1),
+            KeyCode::Down => self.selected_session = (self.selected_session + 1).min(self.sessions.len().saturating_sub(1)),
+            KeyCode::Enter if !self.is_busy() => {
+                let Some(summary) = self.sessions.get(self.selected_session) else { return };
+                match self.store.load(&summary.id).await {
+                    Ok(conv) => {
+                        self.conv = conv;
+                        self.scroll = 0;
+                        self.focus = Focus::Input;
+                        if !self.models.contains(&self.conv.model) {
+                            self.models.push(self.conv.model.clone());
+                        }
+                    }
+                    Err(err) => self.status = Some(format!("{err:#}")),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn input_key(&mut self, key: KeyEvent) {
+        let (ctrl, alt) = (key.modifiers.contains(KeyModifiers::CONTROL), key.modifiers.contains(KeyModifiers::ALT));
+        match key.code {
+            KeyCode::Enter if alt || key.modifiers.contains(KeyModifiers::SHIFT) => self.input.push('\n'),
+            KeyCode::Char('j') if ctrl => self.input.push('\n'),
+            KeyCode::Char('u') if ctrl => self.input.clear(),
+            KeyCode::Enter => self.send(),
+            KeyCode::Backspace => _ = self.input.pop(),
+            KeyCode::Char(c) if !ctrl => self.input.push(c),
+            _ => {}
+        }
+    }
+
+    /// Sends the input as the next message, unless a reply is still in progress.
+    fn send(&mut self) {
+        if self.is_busy() || self.input.trim().is_empty() {
+            return;
+        }
+        let text = std::mem::take(&mut self.input);
+        match self.conv.messages.last_mut() {
+            // a reply cancelled once its tools had run leaves their results to go with the next message
+            Some(last) if last.role == "user" => last.content.push(Content::text(text.trim())),
+            _ => self.conv.push_user(text.trim()),
+        }
+        self.scroll = 0;
+        self.status = None;
+        self.live.clear();
+        self.run_usage = Usage::default();
+        self.run_cost = Cost::default();
+        self.started = Some(Instant::now());
+        self.latency = Latency::default();
+        let (tx, events) = mpsc::unbounded_channel();
+        let progress = Arc::new(Mutex::new(Progress { conv: self.conv.clone(), partial: String::new() }));
+        let task = tokio::spawn(converse(self.client.clone(), self.tools.clone(), progress.clone(), tx));
+        self.run = Some(Run { task, events, progress });
+    }
+
+    /// Stops the reply in progress, keeping the steps it completed and what text it got to since, and saves them.
+    async fn cancel(&mut self) {
+        let Some(run) = self.run.take() else { return };
+        run.task.abort();
+        self.popup = None;
+        {
+            let progress = lock(&run.progress);
+            self.conv = progress.conv.clone();
+            if !progress.partial.trim().is_empty() {
+                self.conv.push_interrupted(progress.partial.trim_end());
+            } else if unanswered(&self.conv) {
+                if let Some(msg) = self.conv.pop_user() {
+                    self.input = msg.content.iter().map(|c| c.to_string()).collect();
+                }
+            }
+        }
+        self.live.clear();
+        self.run_usage = Usage::default();
+        self.run_cost = Cost::default();
+        self.started = None;
+        self.status = Some(String::from("interrupted"));
+        if !self.conv.messages.is_empty() {
+            self.save().await;
+        }
+    }
+
+    /// Saves the conversation as a session, and rereads the sidebar to show it.
+    async fn save(&mut self) {
+        if let Err(err) = self.store.save(&self.conv).await {
+            self.status = Some(format!("save session: {err:#}"));
+        }
+        self.refresh_sessions().await;
+    }
+
+    fn answer(&mut self, approved: bool) {
+        let Some(Popup::Approval { id, answer }) = self.popup.take() else { return };
+        let _ = answer.send(approved);
+        if let Some(Live::Tool { status, .. }) = self.live.iter_mut().find(|l| matches!(l, Live::Tool { id: i, .. } if *i == id))
+        {
+            *status = if approved { ToolStatus::Running } else { ToolStatus::Denied };
+        }
+    }
+
+    pub async fn on_event(&mut self, ev: AppEvent) {
+        match ev {
+            AppEvent::Fragment(text) => {
+                if self.latency.first_token.is_none() {
+                    self.latency.first_token = self.started.map(|s| s.elapsed());
+                }
+                match self.live.last_mut() {
+                    Some(Live::Text(live)) => live.push_str(&text),
+                    _ => self.live.push(Live::Text(text)),
+                }
+            }
+            AppEvent::Response(resp) => {
+                self.latency.total = self.started.map(|s| s.elapsed());
+                if let Some(usage) = &resp.usage {
+                    self.run_usage.extend(usage.clone());
+                }
+                self.run_cost += resp.cost().unwrap_or_default();
+            }
+            AppEvent::Approve { call, answer } => {
+                let Content::ToolUse { id, name, input } = call else { return };
+                self.live.push(Live::Tool { id: id.clone(), name, input, status: ToolStatus::Waiting });
+                self.popup = Some(Popup::Approval { id, answer });
+            }
+            AppEvent::ToolResult(Content::ToolResult { tool_use_id, content, is_error }) => {
+                for live in &mut self.live {
+                    if let Live::Tool { id, status, .. } = live {
+                        if *id == tool_use_id && *status != ToolStatus::Denied {
+                            *status =
+                                if is_error { ToolStatus::Failed(content.clone()) } else { ToolStatus::Done(content.clone()) };
+                        }
+                    }
+                }
+            }
+            AppEvent::ToolResult(_) => {}
+            AppEvent::Done { conv, error } => {
+                self.run = None;
+                self.conv = *conv;
+                self.live.clear();
+                self.run_usage = Usage::default();
+                self.run_cost = Cost::default();
+                self.started = None;
+                self.status = error;
+                self.save().await;
+            }
+        }
+    }
+}
+
+/// Whether the last message is the user's own, with no reply to it yet.
+fn unanswered(conv: &Conversation) -> bool {
+    conv.messages.last().is_some_and(|m| m.role == "user" && m.content.iter().all(|c| matches!(c, Content::Text { .. })))
+}
+
+/// Has the model reply to the conversation of `progress`, running whichever of the tools it calls the user
+/// approves, until it stops calling them. Everything that happens is sent to the app.
+async fn converse(client: Client, tools: ToolRegistry, progress: Arc<Mutex<Progress>>, tx: mpsc::UnboundedSender<AppEvent>) {
+    let mut conv = lock(&progress).conv.clone();
+    let error = run_tools(&client, &tools, &mut conv, &progress, &tx).await.err().map(|err| format!("{err:#}"));
+    if error.is_some() && unanswered(&conv) {
+        // nothing was said in reply, so the message can be sent again
+        conv.pop_user();
+    }
+    let _ = tx.send(AppEvent::Done { conv: Box::new(conv), error });
+}
+
+fn lock(progress: &Mutex<Progress>) -> std::sync::MutexGuard<'_, Progress> {
+    progress.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+async fn run_tools(
+    client: &Client,
+    tools: &ToolRegistry,
+    conv: &mut Conversation,
+    progress: &Mutex<Progress>,
+    tx: &mpsc::UnboundedSender<AppEvent>,
+) -> Result<()> {
+    let definitions = tools.definitions();
+    // keeps the app's copy of the conversation up to date with each completed step
+    let done = |conv: &Conversation| {
+        let mut progress = lock(progress);
+        progress.conv = conv.clone();
+        progress.partial.clear();
+    };
+    loop {
+        let resp = client
+            .stream_reply_with(conv, &definitions, |ev| {
+                if let TextStreamEvent::Fragment(text) = ev {
+                    lock(progress).partial.push_str(text);
+                    let _ = tx.send(AppEvent::Fragment(text.clone()));
+                }
+            })
+            .await?;
+        done(conv);
+        let _ = tx.send(AppEvent::Response(resp.clone()));
+        if resp.stop_reason.as_deref() != Some("tool_use") {
+            return Ok(());
+        }
+        let mut results = vec![];
+        for call in resp.content.iter().filter(|c| matches!(c, Content::ToolUse { .. })) {
+            let Content::ToolUse { id, .. } = call else { unreachable!() };
+            let (answer, approved) = oneshot::channel();
+            let _ = tx.send(AppEvent::Approve { call: call.clone(), answer });
+            let result = if approved.await.unwrap_or(false) {
+                tools.dispatch(call).await.expect("tool_use blocks always have a result")
+            } else {
+                Content::ToolResult {
+                    tool_use_id: id.clone(),
+                    content: String::from("the user denied this tool call"),
+                    is_error: true,
+                }
+            };
+            let _ = tx.send(AppEvent::ToolResult(result.clone()));
+            results.push(result);
+        }
+        conv.push_tool_results(results);
+        done(conv);
+    }
+}
@@ -0,0 +1,242 @@
+//! A full-screen chat client: the conversation, an input box, a sidebar of saved sessions, live token, cost and
+//! latency stats, a model picker, and tool calls which wait for the user to approve or deny them.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use crossterm::event::{Event, EventStream, KeyEventKind};
+use futures::StreamExt;
+use ratatui::{Terminal, backend::Backend};
+
+mod app;
+mod ui;
+
+pub use app::{App, AppEvent, Focus, Latency, Live, Popup, ToolStatus};
+pub use ui::draw;
+
+/// Takes over the terminal until the user quits, putting it back as it was afterwards.
+pub async fn run(app: &mut App) -> Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let res = run_in(app, &mut terminal).await;
+    ratatui::try_restore()?;
+    res
+}
+
+/// Draws `app` on `terminal` and handles keys and replies until the user quits.
+pub async fn run_in<B: Backend>(app: &mut App, terminal: &mut Terminal<B>) -> Result<()> {
+    let mut keys = EventStream::new();
+    // redraws the stats while a reply is in progress
+    let mut tick = tokio::time::interval(Duration::from_millis(250));
+    app.refresh_sessions().await;
+    while !app.should_quit() {
+        terminal.draw(|frame| draw(app, frame))?;
+        tokio::select! {
+            ev = keys.next() => match ev {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => app.on_key(key).await,
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(err.into()),
+                None => break,
+            },
+            // only the receiving is raced; handling the event, which saves the session, mustn't be cut short
+            Some(ev) = app.recv() => app.on_event(ev).await,
+            _ = tick.tick(), if app.is_busy() => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+    use ratatui::backend::TestBackend;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        anthropic::{
+            Client, Conversation, INTERRUPTED, SessionStore,
+            mock::{MockResponse, MockServer},
+        },
+        tools::fs::Sandbox,
+    };
+
+    struct Harness {
+        app: App,
+        server: MockServer,
+        dir: tempfile::TempDir,
+    }
+
+    async fn harness() -> Harness {
+        let server = MockServer::start().await.unwrap();
+        let client = Client::new("key").unwrap().with_endpoint(server.url());
+        let dir = tempfile::tempdir().unwrap();
+        let conv = Conversation::new("claude-3-5-haiku-latest", None);
+        let app = App::new(client, conv, SessionStore::new(dir.path().join("sessions")));
+        Harness { app, server, dir }
+    }
+
+    async fn key(app: &mut App, code: KeyCode) {
+        app.on_key(KeyEvent::new(code, KeyModifiers::NONE)).await;
+    }
+
+    async fn ctrl(app: &mut App, c: char) {
+        app.on_key(KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL)).await;
+    }
+
+    async fn send(app: &mut App, text: &str) {
+        for c in text.chars() {
+            key(app, KeyCode::Char(c)).await;
+        }
+        key(app, KeyCode::Enter).await;
+    }
+
+    /// Handles what the reply sends until it asks for approval or is done.
+    async fn settle(app: &mut App) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while app.is_busy() && app.popup().is_none() {
+                app.next_event().await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    fn screen(app: &App) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(100, 30)).unwrap();
+        terminal.draw(|frame| draw(app, frame)).unwrap();
+        let buffer = terminal.backend().buffer();
+        buffer
+            .content
+            .chunks(buffer.area.width as usize)
+            .map(|row| row.iter().map(|cell| cell.symbol()).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[tokio::test]
+    async fn chat() {
+        let Harness { mut app, server, dir: _dir } = harness().await;
+        server.push(MockResponse::stream("Hello there, how can I help?"));
+        send(&mut app, "hi").await;
+        assert!(app.is_busy());
+        assert!(screen(&app).contains("first token"));
+        settle(&mut app).await;
+
+        assert_eq!(app.conversation().messages.len(), 2);
+        let screen = screen(&app);
+        assert!(screen.contains("Hello there, how can I help?"), "{screen}");
+        // saved and listed in the sidebar, with the session's usage in the stats
+        assert!(screen.contains("│hi "), "{screen}");
+        assert!(screen.contains("claude-3-5-haiku-latest │ 10 in, 7 out │ $0.0000 │ first token"), "{screen}");
+
+        // Ctrl-N starts afresh, and the sidebar loads the old session back
+        ctrl(&mut app, 'n').await;
+        assert!(app.conversation().messages.is_empty());
+        key(&mut app, KeyCode::Tab).await;
+        key(&mut app, KeyCode::Enter).await;
+        assert_eq!(app.conversation().messages.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn tool_approval() {
+        let Harness { app, server, dir } = harness().await;
+        std::fs::write(dir.path().join("notes.txt"), "buy milk").unwrap();
+        let mut tools = crate::tools::ToolRegistry::new();
+        Sandbox::new(dir.path()).unwrap().register_read_only(&mut tools);
+        let mut app = app.with_tools(tools);
+
+        server.push(MockResponse::stream_tool_use("read_file", json!({"path": "notes.txt"})));
+        server.push(MockResponse::stream_tool_use("read_file", json!({"path": "../secrets"})));
+        server.push(MockResponse::stream("You need milk."));
+        send(&mut app, "what do I need?").await;
+        settle(&mut app).await;
+        assert!(matches!(app.popup(), Some(Popup::Approval { .. })));
+        let popup = screen(&app);
+        assert!(popup.contains("Run read_file?") && popup.contains("\"path\": \"notes.txt\""), "{popup}");
+        key(&mut app, KeyCode::Char('y')).await;
+        settle(&mut app).await;
+        key(&mut app, KeyCode::Char('n')).await;
+        settle(&mut app).await;
+        assert!(!app.is_busy());
+
+        let requests = server.requests();
+        assert_eq!(requests[1].body["messages"][2]["content"][0]["content"], "buy milk");
+        let denied = &requests[2].body["messages"][4]["content"][0];
+        assert_eq!((&denied["content"], &denied["is_error"]), (&json!("the user denied this tool call"), &json!(true)));
+        assert_eq!(app.conversation().messages.len(), 6);
+        assert!(screen(&app).contains("↳ buy milk"));
+    }
+
+    #[tokio::test]
+    async fn model_picker() {
+        let Harness { app, .. } = harness().await;
+        let mut app = app.with_models(vec![String::from("claude-a"), String::from("claude-b")]);
+        ctrl(&mut app, 'p').await;
+        assert!(screen(&app).contains("claude-a"));
+        key(&mut app, KeyCode::Down).await;
+        key(&mut app, KeyCode::Down).await;
+        key(&mut app, KeyCode::Enter).await;
+        assert_eq!(app.conversation().model, "claude-b");
+        assert!(app.popup().is_none());
+    }
+
+    #[tokio::test]
+    async fn cancel() {
+        let Harness { mut app, server, dir } = harness().await;
+        let text = "one two three four five six seven eight nine ten";
+        server.push(MockResponse::stream(text).with_event_delay(Duration::from_millis(50)));
+        send(&mut app, "count").await;
+        while !matches!(app.live.first(), Some(Live::Text(text)) if text.len() > 8) {
+            app.next_event().await;
+        }
+        key(&mut app, KeyCode::Esc).await;
+        assert!(!app.is_busy());
+        let conv = app.conversation();
+        assert!(text.starts_with(&conv.messages[1].content[0].to_string()));
+        assert_eq!(conv.turns[0].stop_reason.as_deref(), Some(INTERRUPTED));
+
+        // quitting straight after doesn't lose the interrupted turn
+        let saved = SessionStore::new(dir.path().join("sessions")).load(&conv.id).await.unwrap();
+        assert_eq!(saved.messages, conv.messages);
+        assert_eq!(app.sessions.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cancel_after_tools() {
+        let Harness { app, server, dir } = harness().await;
+        std::fs::write(dir.path().join("notes.txt"), "buy milk").unwrap();
+        let mut tools = crate::tools::ToolRegistry::new();
+        Sandbox::new(dir.path()).unwrap().register_read_only(&mut tools);
+        let mut app = app.with_tools(tools);
+
+        server.push(MockResponse::stream_tool_use("read_file", json!({"path": "notes.txt"})));
+        let text = "You need milk, and then some more things after that, and more";
+        server.push(MockResponse::stream(text).with_event_delay(Duration::from_millis(50)));
+        send(&mut app, "what do I need?").await;
+        settle(&mut app).await;
+        key(&mut app, KeyCode::Char('y')).await;
+        while !matches!(app.live.last(), Some(Live::Text(text)) if text.len() > 8) {
+            app.next_event().await;
+        }
+        key(&mut app, KeyCode::Esc).await;
+
+        // the tool call and its result stay, along with the usage of the reply which made it
+        let conv = app.conversation();
+        let roles = conv.messages.iter().map(|m| m.role.as_str()).collect::<Vec<_>>();
+        assert_eq!(roles, ["user", "assistant", "user", "assistant"]);
+        assert!(text.starts_with(&conv.messages[3].content[0].to_string()));
+        assert!(conv.usage().output_tokens > 0);
+        assert_eq!(conv.turns.last().unwrap().stop_reason.as_deref(), Some(INTERRUPTED));
+
+        // what the cancelled reply had yet to deliver doesn't reach the next one
+        server.push(MockResponse::stream("Anything else?"));
+        send(&mut app, "thanks").await;
+        settle(&mut app).await;
+        let conv = app.conversation();
+        assert_eq!(conv.messages.len(), 6);
+        assert_eq!(conv.messages[5].content[0].to_string(), "Anything else?");
+        assert!(app.live.is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+use ratatui::{
+    Frame,
+    layout::{Constraint, Flex, Layout, Position, Rect},
+    style::{Color, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Wrap},
+};
+
+use super::app::{App, Focus, Live, Popup, ToolStatus};
+use crate::anthropic::{Content, Message};
+
+const HELP: &str = " Enter send · Alt-Enter newline · Tab sessions · ^P model · ^N new · Esc stop · ^Q quit ";
+
+/// Draws the whole screen: sessions on the left, and the conversation, input and stats on the right.
+pub fn draw(app: &App, frame: &mut Frame) {
+    let [sidebar, main] = Layout::horizontal([Constraint::Length(28), Constraint::Min(20)]).areas(frame.area());
+    let input_lines = app.input.split('\n').count().clamp(1, 6) as u16;
+    let [conversation, input, stats] =
+        Layout::vertical([Constraint::Min(3), Constraint::Length(input_lines + 2), Constraint::Length(1)]).areas(main);
+
+    draw_sessions(app, frame, sidebar);
+    draw_conversation(app, frame, conversation);
+    draw_input(app, frame, input);
+    draw_stats(app, frame, stats);
+    match app.popup() {
+        Some(Popup::Models { selected }) => draw_models(app, *selected, frame),
+        Some(Popup::Approval { id, .. }) => draw_approval(app, id, frame),
+        None => {}
+    }
+}
+
+fn focused(yes: bool) -> Style {
+    if yes { Style::new().fg(Color::Cyan) } else { Style::new().fg(Color::DarkGray) }
+}
+
+fn draw_sessions(app: &App, frame: &mut Frame, area: Rect) {
+    let items = app.sessions.iter().map(|s| {
+        let title = if s.title.is_empty() { "(untitled)" } else { s.title.as_str() };
+        let style = if s.id == app.conv.id { Style::new().bold() } else { Style::new() };
+        ListItem::new(Line::styled(title.to_string(), style))
+    });
+    let block = Block::bordered().title(" Sessions ").border_style(focused(app.focus == Focus::Sessions));
+    let list = List::new(items).block(block).highlight_style(Style::new().reversed());
+    let mut state = ListState::default();
+    if app.focus == Focus::Sessions {
+        state.select(Some(app.selected_session));
+    }
+    frame.render_stateful_widget(list, area, &mut state);
+}
+
+/// The conversation as lines of text, the reply in progress included.
+fn transcript(app: &App) -> Vec<Line<'static>> {
+    let mut lines = vec![];
+    for msg in &app.conv.messages {
+        message_lines(msg, &mut lines);
+    }
+    if app.is_busy() || !app.live.is_empty() {
+        lines.push(speaker("assistant"));
+        for live in &app.live {
+            match live {
+                Live::Text(text) => lines.extend(text.lines().map(|l| Line::raw(l.to_string()))),
+                Live::Tool { name, input, status, .. } => {
+                    lines.push(tool_use_line(name, input));
+                    match status {
+                        ToolStatus::Waiting => lines.push(Line::from("  waiting for approval".yellow())),
+                        ToolStatus::Running => lines.push(Line::from("  running".yellow())),
+                        ToolStatus::Denied => lines.push(Line::from("  denied".red())),
+                        ToolStatus::Done(result) => lines.push(tool_result_line(result, false)),
+                        ToolStatus::Failed(result) => lines.push(tool_result_line(result, true)),
+                    }
+                }
+            }
+        }
+        if app.live.is_empty() {
+            lines.push(Line::from("…".dark_gray()));
+        }
+    }
+    lines
+}
+
+fn speaker(role: &str) -> Line<'static> {
+    match role {
+        "user" => Line::from("You".cyan().bold()),
+        _ => Line::from("Claude".magenta().bold()),
+    }
+}
+
+fn message_lines(msg: &Message, lines: &mut Vec<Line<'static>>) {
+    let tool_results = msg.content.iter().all(|c| matches!(c, Content::ToolResult { .. }));
+    if !tool_results {
+        if !lines.is_empty() {
+            lines.push(Line::default());
+        }
+        lines.push(speaker(&msg.role));
+    }
+    for block in &msg.content {
+        match block {
+            Content::Text { text } => lines.extend(text.lines().map(|l| Line::raw(l.to_string()))),
+            Content::ToolUse { name, input, .. } => lines.push(tool_use_line(name, input)),
+            Content::ToolResult { content, is_error, .. } => lines.push(tool_result_line(content, *is_error)),
+            block => lines.push(Line::from(block.to_string().dark_gray())),
+        }
+    }
+}
+
+fn tool_use_line(name: &str, input: &serde_json::Value) -> Line<'static> {
+    Line::from(vec![Span::from("⚙ ").yellow(), Span::from(name.to_string()).yellow().bold(), Span::from(format!(" {input}"))])
+}
+
+/// The first line of a tool's result, and how many more there are.
+fn tool_result_line(result: &str, is_error: bool) -> Line<'static> {
+    let mut lines = result.lines();
+    let first = lines.next().unwrap_or_default();
+    let more = match lines.count() {
+        0 => String::new(),
+        n => format!(" (+{n} lines)"),
+    };
+    let style = if is_error { Style::new().red() } else { Style::new().dark_gray() };
+    Line::styled(format!("  ↳ {first}{more}"), style)
+}
+
+fn draw_conversation(app: &App, frame: &mut Frame, area: Rect) {
+    let title = match app.conv.title() {
+        title if title.is_empty() => String::from(" New conversation "),
+        title => format!(" {title} "),
+    };
+    let block = Block::bordered().title(title).border_style(focused(false));
+    let paragraph = Paragraph::new(transcript(app)).wrap(Wrap { trim: false });
+    let inner = block.inner(area);
+    // keep to the bottom, less however far it has been scrolled up
+    let lines = paragraph.line_count(inner.width) as u16;
+    let bottom = lines.saturating_sub(inner.height);
+    let top = bottom.saturating_sub(app.scroll);
+    frame.render_widget(paragraph.block(block).scroll((top, 0)), area);
+}
+
+fn draw_input(app: &App, frame: &mut Frame, area: Rect) {
+    let block = Block::bordered()
+        .title(" Message ")
+        .title_bottom(Line::from(HELP).dark_gray())
+        .border_style(focused(app.focus == Focus::Input && app.popup().is_none()));
+    let inner = block.inner(area);
+    let lines = app.input.split('\n').collect::<Vec<_>>();
+    // keep the end of long input in view
+    let top = lines.len().saturating_sub(inner.height as usize);
+    frame.render_widget(Paragraph::new(lines[top..].join("\n")).block(block), area);
+    if app.focus == Focus::Input && app.popup().is_none() {
+        let last = lines.last().map(|l| l.chars().count()).unwrap_or_default() as u16;
+        let row = (lines.len() - top).saturating_sub(1) as u16;
+        frame.set_cursor_position(Position::new(inner.x + last.min(inner.width.saturating_sub(1)), inner.y + row));
+    }
+}
+
+fn draw_stats(app: &App, frame: &mut Frame, area: Rect) {
+    let usage = app.usage();
+    let mut spans = vec![
+        Span::from(format!(" {} ", app.conv.model)).bold(),
+        Span::from(format!("│ {} in, {} out ", usage.input_tokens, usage.output_tokens)),
+        Span::from(format!("│ {} ", app.cost())),
+    ];
+    let secs = |d: std::time::Duration| format!("{:.1}s", d.as_secs_f64());
+    match (app.started, app.latency) {
+        (Some(started), latency) => {
+            let first = latency.first_token.map(secs).unwrap_or_else(|| String::from("…"));
+            spans.push(Span::from(format!("│ first token {first}, {} so far ", secs(started.elapsed()))).yellow());
+        }
+        (None, latency) => {
+            if let (Some(first), Some(total)) = (latency.first_token, latency.total) {
+                spans.push(Span::from(format!("│ first token {}, total {} ", secs(first), secs(total))));
+            }
+        }
+    }
+    if let Some(status) = &app.status {
+        spans.push(Span::from(format!("│ {status}")).red());
+    }
+    frame.render_widget(Line::from(spans).style(Style::new().bg(Color::Black)), area);
+}
+
+/// A rectangle of at most `width` by `height` in the middle of `area`.
+fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let [area] = Layout::horizontal([Constraint::Length(width.min(area.width))]).flex(Flex::Center).areas(area);
+    let [area] = Layout::vertical([Constraint::Length(height.min(area.height))]).flex(Flex::Center).areas(area);
+    area
+}
+
+fn draw_models(app: &App, selected: usize, frame: &mut Frame) {
+    let area = centered(frame.area(), 48, app.models.len() as u16 + 2);
+    let items = app.models.iter().map(|m| ListItem::new(m.clone()));
+    let list = List::new(items)
+        .block(Block::bordered().title(" Model ").border_style(focused(true)))
+        .highlight_style(Style::new().reversed());
+    frame.render_widget(Clear, area);
+    frame.render_stateful_widget(list, area, &mut ListState::default().with_selected(Some(selected)));
+}
+
+fn draw_approval(app: &App, id: &str, frame: &mut Frame) {
+    let Some(Live::Tool { name, input, .. }) = app.live.iter().find(|l| matches!(l, Live::Tool { id: i, .. } if i == id)) else {
+        return;
+    };
+    let input = serde_json::to_string_pretty(input).unwrap_or_default();
+    let mut lines = vec![Line::from(vec!["Run ".into(), name.clone().yellow().bold(), "?".into()]), Line::default()];
+    lines.extend(input.lines().map(|l| Line::raw(l.to_string())));
+    lines.extend([Line::default(), Line::from(vec!["y".green().bold(), " approve  ".into(), "n".red().bold(), " deny".into()])]);
+    let area = centered(frame.area(), 60, lines.len() as u16 + 2);
+    let block = Block::default().borders(Borders::ALL).title(" Tool call ").border_style(Style::new().fg(Color::Yellow));
+    frame.render_widget(Clear, area);
+    frame.render_widget(Paragraph::new(lines).block(block).wrap(Wrap { trim: false }), area);
+}